@@ -512,4 +512,16 @@ impl<M: MemoryMap> Device for CardBus<M> {
             _ => false,
         }
     }
+    /// DRQ of the selected card
+    fn ready(&self, _: u16, _: bool) -> bool {
+        let status = if (self.registers.drive_head & 0x10) == 0 {
+            self.card0.status
+        } else {
+            match self.card1.as_ref() {
+                Some(card1) => card1.status,
+                _ => 0,
+            }
+        };
+        (status & Status::DRQ) != 0
+    }
 }
@@ -1,53 +1,131 @@
-//! ASCII Parallel Keyboard Emulation
+//! Translation of SDL keyboard events into keyboard input
 
-use std::{
-    collections::VecDeque,
-    io::{self, Write},
-    time::{Duration, Instant},
-};
+use std::collections::VecDeque;
 
-use possum_emu::{Device, DeviceBus};
-use sdl2::{event::Event, EventPump};
+use possum_emu::{Key, KeyboardInput, Modifier, Terminal};
+use sdl2::keyboard::{Keycode, Mod, Scancode};
 
-pub struct AsciiKeyboard {
-    event_pump: EventPump,
-    next_poll_time: Instant,
-    buffer: VecDeque<u8>,
+fn modifiers(keymod: Mod) -> u8 {
+    let mut bits = 0;
+    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+        bits |= Modifier::SHIFT;
+    }
+    if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+        bits |= Modifier::CTRL;
+    }
+    if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) {
+        bits |= Modifier::ALT;
+    }
+    if keymod.intersects(Mod::LGUIMOD | Mod::RGUIMOD) {
+        bits |= Modifier::GUI;
+    }
+    if keymod.contains(Mod::CAPSMOD) {
+        bits |= Modifier::CAPS_LOCK;
+    }
+    if keymod.contains(Mod::NUMMOD) {
+        bits |= Modifier::NUM_LOCK;
+    }
+    bits
 }
 
-impl AsciiKeyboard {
-    pub fn new(event_pump: EventPump) -> Self {
-        Self {
-            event_pump,
-            next_poll_time: Instant::now(),
-            buffer: VecDeque::new(),
-        }
+fn key(keycode: Keycode) -> Key {
+    match keycode {
+        Keycode::Return | Keycode::KpEnter => Key::Enter,
+        Keycode::Backspace => Key::Backspace,
+        Keycode::Tab => Key::Tab,
+        Keycode::Escape => Key::Escape,
+        Keycode::Delete => Key::Delete,
+
+        Keycode::Up => Key::Up,
+        Keycode::Down => Key::Down,
+        Keycode::Left => Key::Left,
+        Keycode::Right => Key::Right,
+        Keycode::Home => Key::Home,
+        Keycode::End => Key::End,
+        Keycode::PageUp => Key::PageUp,
+        Keycode::PageDown => Key::PageDown,
+        Keycode::Insert => Key::Insert,
+
+        Keycode::F1 => Key::F(1),
+        Keycode::F2 => Key::F(2),
+        Keycode::F3 => Key::F(3),
+        Keycode::F4 => Key::F(4),
+        Keycode::F5 => Key::F(5),
+        Keycode::F6 => Key::F(6),
+        Keycode::F7 => Key::F(7),
+        Keycode::F8 => Key::F(8),
+        Keycode::F9 => Key::F(9),
+        Keycode::F10 => Key::F(10),
+        Keycode::F11 => Key::F(11),
+        Keycode::F12 => Key::F(12),
+
+        // SDL keycodes for printable keys are their (unshifted) ASCII value
+        _ => match keycode as i32 {
+            code @ 0x20..=0x7E => Key::Char(code as u8 as char),
+            _ => Key::Other,
+        },
     }
 }
 
-impl Device for AsciiKeyboard {
-    fn tick(&mut self, _: &mut dyn DeviceBus) {}
+/// Scancodes are USB HID usage IDs already. Only the ones that fit in a byte are usable.
+fn scancode(scancode: Option<Scancode>) -> Option<u8> {
+    scancode.and_then(|scancode| u8::try_from(scancode as i32).ok())
+}
+
+pub fn key_down(
+    input: &KeyboardInput,
+    keycode: Option<Keycode>,
+    sdl_scancode: Option<Scancode>,
+    keymod: Mod,
+) {
+    input.key_down(
+        keycode.map(key).unwrap_or(Key::Other),
+        scancode(sdl_scancode),
+        modifiers(keymod),
+    );
+}
 
-    fn read(&mut self, _: u16) -> u8 {
-        let now = Instant::now();
-        // Limit event polling to only ~once per frame
-        if now >= self.next_poll_time {
-            self.next_poll_time = now + Duration::from_millis(16);
-            match self.event_pump.poll_event() {
-                Some(Event::TextInput { text, .. }) => self.buffer.extend(text.bytes()),
-                _ => {}
-            }
+pub fn terminal_key_down(terminal: &Terminal, keycode: Option<Keycode>, keymod: Mod) {
+    terminal.key_down(keycode.map(key).unwrap_or(Key::Other), modifiers(keymod));
+}
+
+pub fn key_up(input: &KeyboardInput, sdl_scancode: Option<Scancode>, keymod: Mod) {
+    input.key_up(scancode(sdl_scancode), modifiers(keymod));
+}
+
+/// Types host text into the keyboard a few characters per frame
+pub struct Paste {
+    text: VecDeque<u8>,
+    rate: usize,
+}
+
+impl Paste {
+    pub fn new(rate: usize) -> Self {
+        Self {
+            text: VecDeque::new(),
+            rate,
         }
-        self.buffer.pop_front().unwrap_or_default()
     }
 
-    fn write(&mut self, _: u16, data: u8) {
-        // TODO: This is a basic output for debugging. Obviously in reality
-        //   you can't write to your keyboard :-P
-        io::stdout().write(&[data]).unwrap();
+    pub fn start(&mut self, text: &str) {
+        // Any style of line ending is a single press of enter
+        let text = text.replace("\r\n", "\r").replace('\n', "\r");
+        // The keyboard can't type anything else
+        self.text.extend(
+            text.bytes()
+                .filter(|&c| c == b'\r' || c == b'\t' || c == b' ' || c.is_ascii_graphic()),
+        );
     }
 
-    fn interrupting(&self) -> bool {
-        false
+    /// Types up to the configured rate, but only as much as the keyboard has room for
+    pub fn feed(&mut self, input: &KeyboardInput) {
+        for _ in 0..self.rate {
+            match self.text.front() {
+                Some(&c) if input.paste(c) => {
+                    self.text.pop_front();
+                }
+                _ => break,
+            }
+        }
     }
 }
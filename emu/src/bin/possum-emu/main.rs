@@ -2,6 +2,9 @@
 
 mod kb;
 mod mmap;
+mod ser;
+mod slip;
+mod xmodem;
 
 use std::{
     fs::{File, OpenOptions},
@@ -12,10 +15,20 @@ use std::{
 };
 
 use clap::Parser;
-use possum_emu::{CardBus, Device, System};
-use sdl2::{pixels::PixelFormatEnum, rect::Rect};
+use possum_emu::{
+    CardBus, Device, Framebuffer, Keyboard, KeyboardInput, KeyboardMode, MemoryLayout, System,
+    Terminal,
+};
+use sdl2::{
+    event::{Event, WindowEvent},
+    keyboard::{Keycode, Mod},
+    pixels::PixelFormatEnum,
+    rect::Rect,
+    render::{Canvas, Texture, TextureCreator},
+    video::{Window, WindowContext},
+};
 
-use crate::{kb::AsciiKeyboard, mmap::MemoryMapWrapper};
+use crate::{kb::Paste, mmap::MemoryMapWrapper};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -27,10 +40,162 @@ struct Args {
     /// Path to disk image for the primary drive
     #[clap(parse(from_os_str), long)]
     hd0: Option<PathBuf>,
+
+    /// Send raw make/break scancodes to the guest instead of ASCII
+    #[clap(long)]
+    kb_scancodes: bool,
+
+    /// Backend for the first serial port: `null`, `stdio`, `pty`, `tcp:PORT`, `unix:PATH`,
+    /// `file:INPUT,OUTPUT`, `slip`, `term`, `xmodem-send:FILE`, `xmodem-recv:FILE`,
+    /// `ymodem-send:FILE[,FILE...]` or `ymodem-recv:DIR`
+    #[clap(long, value_name = "BACKEND", default_value = "null")]
+    ser1: ser::Backend,
+
+    /// Backend for the second serial port: `null`, `stdio`, `pty`, `tcp:PORT`, `unix:PATH`,
+    /// `file:INPUT,OUTPUT`, `slip`, `term`, `xmodem-send:FILE`, `xmodem-recv:FILE`,
+    /// `ymodem-send:FILE[,FILE...]` or `ymodem-recv:DIR`
+    #[clap(long, value_name = "BACKEND", default_value = "null")]
+    ser2: ser::Backend,
+
+    /// Serial controller: `uart` puts a 16550A on each port, `sio` puts a Z80 SIO/2 on
+    /// the first port with one channel for each of the backends
+    #[clap(arg_enum, long, value_name = "CHIP", default_value = "uart")]
+    ser_chip: ser::Chip,
+
+    /// Hold off the first serial port's backend while the guest deasserts RTS.
+    /// Only for guests that raise RTS when they are ready to receive.
+    #[clap(long)]
+    ser1_flow: bool,
+
+    /// Hold off the second serial port's backend while the guest deasserts RTS.
+    /// Only for guests that raise RTS when they are ready to receive.
+    #[clap(long)]
+    ser2_flow: bool,
+
+    /// Characters per frame typed when pasting the clipboard (with ctrl+shift+v)
+    #[clap(long, value_name = "CHARS", default_value = "8")]
+    paste_rate: usize,
+
+    /// RAM in multiples of 64K
+    #[clap(long, value_name = "COUNT", default_value = "32")]
+    ram_banks: usize,
+
+    /// How many windows the address space is banked in, each with its own bank register
+    #[clap(long, value_name = "COUNT", default_value = "1", possible_values = ["1", "2", "4"])]
+    bank_windows: usize,
+
+    /// Start of the common area that is never banked (like `0xF000`)
+    #[clap(long, value_name = "ADDR", default_value = "0x0000", parse(try_from_str = parse_u16))]
+    common_base: u16,
+
+    /// Bytes in the common area
+    #[clap(long, value_name = "BYTES", default_value = "0x0400", parse(try_from_str = parse_u16))]
+    common_size: u16,
+
+    /// Add the paging MMU, which software can enable in place of the bank windows
+    #[clap(long)]
+    mmu: bool,
+}
+
+impl Args {
+    fn memory_layout(&self) -> io::Result<MemoryLayout> {
+        if !(1..=(256 / self.bank_windows)).contains(&self.ram_banks) {
+            return Err(io::Error::other(
+                "Too many banks of RAM for the bank registers",
+            ));
+        }
+        if (self.common_base as usize) + (self.common_size as usize) > 0x10000 {
+            return Err(io::Error::other(
+                "The common area must fit in the address space",
+            ));
+        }
+        Ok(MemoryLayout {
+            ram_banks: self.ram_banks,
+            windows: self.bank_windows,
+            common_base: self.common_base,
+            common_size: self.common_size as usize,
+            mmu: self.mmu,
+        })
+    }
+}
+
+/// Numbers in decimal, or hex with a `0x` prefix
+fn parse_u16(value: &str) -> Result<u16, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
+
+/// A window for a serial terminal. Its texture is made by a [`TerminalWindow`].
+fn terminal_canvas(video: &sdl2::VideoSubsystem, title: &str) -> io::Result<Canvas<Window>> {
+    let window = video
+        .window(title, 640 * 2, 192 * 2)
+        .allow_highdpi()
+        .resizable()
+        .build()
+        .map_err(io::Error::other)?;
+    window
+        .into_canvas()
+        .accelerated()
+        .build()
+        .map_err(io::Error::other)
+}
+
+/// A serial terminal shown in a window of its own
+struct TerminalWindow<'a> {
+    terminal: Terminal,
+    canvas: Canvas<Window>,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    /// Made on the first frame, once the size of the screen is known
+    texture: Option<Texture<'a>>,
+    framebuffer: Framebuffer,
+}
+
+impl<'a> TerminalWindow<'a> {
+    fn new(
+        canvas: Canvas<Window>,
+        texture_creator: &'a TextureCreator<WindowContext>,
+        terminal: Terminal,
+    ) -> Self {
+        Self {
+            terminal,
+            canvas,
+            texture_creator,
+            texture: None,
+            framebuffer: Framebuffer::default(),
+        }
+    }
+
+    fn present(&mut self) -> io::Result<()> {
+        self.terminal.render(&mut self.framebuffer);
+        let (width, height) = (self.framebuffer.width(), self.framebuffer.height());
+        let texture = match &mut self.texture {
+            Some(texture) => texture,
+            None => self.texture.insert(
+                self.texture_creator
+                    .create_texture_streaming(PixelFormatEnum::RGBA32, width as u32, height as u32)
+                    .map_err(io::Error::other)?,
+            ),
+        };
+        texture
+            .update(
+                None,
+                bytemuck::cast_slice(self.framebuffer.data()),
+                width * mem::size_of::<u32>(),
+            )
+            .map_err(io::Error::other)?;
+        self.canvas
+            .copy(texture, None, None)
+            .map_err(io::Error::other)?;
+        self.canvas.present();
+        Ok(())
+    }
 }
 
 fn main() -> io::Result<()> {
     let args = Args::parse();
+    let memory = args.memory_layout()?;
 
     let mut rom = Vec::new();
     File::open(args.file)?.read_to_end(&mut rom)?;
@@ -44,13 +209,43 @@ fn main() -> io::Result<()> {
     };
 
     let sdl = sdl2::init().map_err(io::Error::other)?;
-    let event_pump = sdl.event_pump().map_err(io::Error::other)?;
+    let mut event_pump = sdl.event_pump().map_err(io::Error::other)?;
     let video = sdl.video().map_err(io::Error::other)?;
     video.text_input().start();
 
-    let kb = AsciiKeyboard::new(event_pump);
-    let mut system = System::new(Box::new(kb), hd);
-    system.write_ram(&rom, 0);
+    let kb_input = KeyboardInput::default();
+    let kb_mode = if args.kb_scancodes {
+        KeyboardMode::Scancode
+    } else {
+        KeyboardMode::Ascii
+    };
+    let kb = Keyboard::new(kb_input.clone(), kb_mode);
+    let mut paste = Paste::new(args.paste_rate);
+    let (ser1, term1) = args.ser1.open()?;
+    let (ser2, term2) = args.ser2.open()?;
+    let (ser1, ser2) = args
+        .ser_chip
+        .build(ser1, ser2, [args.ser1_flow, args.ser2_flow]);
+    let mut canvases = Vec::new();
+    for (name, terminal) in [("ser1", term1), ("ser2", term2)] {
+        if let Some(terminal) = terminal {
+            let title = format!("possum-emu :: {name}");
+            canvases.push((terminal_canvas(&video, &title)?, terminal));
+        }
+    }
+    let texture_creators: Vec<_> = canvases
+        .iter()
+        .map(|(canvas, _)| canvas.texture_creator())
+        .collect();
+    let mut terminals: Vec<_> = canvases
+        .into_iter()
+        .zip(&texture_creators)
+        .map(|((canvas, terminal), texture_creator)| {
+            TerminalWindow::new(canvas, texture_creator, terminal)
+        })
+        .collect();
+    let mut system = System::new(memory, Box::new(kb), hd, ser1, ser2);
+    system.load_rom(&rom)?;
 
     let window = video
         .window("possum-emu", 752 * 2, 244 * 4)
@@ -71,16 +266,104 @@ fn main() -> io::Result<()> {
 
     let mut start = Instant::now();
     let mut last_frame = Instant::now();
+    let mut last_poll = Instant::now();
     let mut frames = 0;
     let mut cycles = 0;
-    while !system.halted() {
+    let mut rect = Rect::new(0, 0, 1, 1);
+    'running: while !system.halted() {
         cycles += system.step();
         let now = Instant::now();
 
+        // Limit event polling to only ~once per frame
+        if now.duration_since(last_poll) > Duration::from_millis(16) {
+            let mut redraw = false;
+            for event in event_pump.poll_iter() {
+                // Input to a terminal window goes to its serial port instead of the keyboard
+                let window_id = event.get_window_id();
+                if let Some(window) = terminals
+                    .iter_mut()
+                    .find(|window| Some(window.canvas.window().id()) == window_id)
+                {
+                    match event {
+                        Event::Window { win_event, .. } => match win_event {
+                            WindowEvent::Close => window.canvas.window_mut().hide(),
+                            WindowEvent::FocusGained => video.text_input().start(),
+                            WindowEvent::FocusLost => video.text_input().stop(),
+                            _ => {}
+                        },
+                        Event::KeyDown {
+                            keycode, keymod, ..
+                        } => kb::terminal_key_down(&window.terminal, keycode, keymod),
+                        Event::TextInput { text, .. } => window.terminal.text(&text),
+                        _ => {}
+                    }
+                    continue;
+                }
+
+                match event {
+                    Event::Quit { .. } => break 'running,
+
+                    Event::Window { win_event, .. } => match win_event {
+                        WindowEvent::Close => break 'running,
+
+                        // The last frame needs to be re-presented to fit the new window size
+                        WindowEvent::SizeChanged(..) | WindowEvent::Exposed => redraw = true,
+
+                        // Don't let the guest see text typed into other windows
+                        WindowEvent::FocusGained => video.text_input().start(),
+                        WindowEvent::FocusLost => video.text_input().stop(),
+
+                        _ => {}
+                    },
+
+                    // The paste hotkey is for the host only
+                    Event::KeyDown {
+                        keycode: Some(Keycode::V),
+                        keymod,
+                        ..
+                    } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD)
+                        && keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) =>
+                    {
+                        match video.clipboard().clipboard_text() {
+                            Ok(text) => paste.start(&text),
+                            Err(e) => eprintln!("failed to read the clipboard: {e}"),
+                        }
+                    }
+
+                    Event::KeyDown {
+                        keycode,
+                        scancode,
+                        keymod,
+                        ..
+                    } => kb::key_down(&kb_input, keycode, scancode, keymod),
+
+                    Event::KeyUp {
+                        scancode, keymod, ..
+                    } => kb::key_up(&kb_input, scancode, keymod),
+
+                    Event::TextInput { text, .. } => kb_input.text(&text),
+
+                    _ => {}
+                }
+            }
+            paste.feed(&kb_input);
+            // Terminals are redrawn whether or not the guest is using the VDC
+            for window in &mut terminals {
+                window.present()?;
+            }
+            if redraw {
+                canvas
+                    .copy(&texture, rect, None)
+                    .map_err(io::Error::other)?;
+                canvas.present();
+            }
+            last_poll = now;
+        }
+
         if system.framebuffer_ready() && now.duration_since(last_frame) > Duration::from_millis(16)
         {
             let framebuffer = system.framebuffer();
-            let rect = Rect::new(
+            rect = Rect::new(
                 0,
                 0,
                 framebuffer.width() as u32,
@@ -0,0 +1,501 @@
+//! Host backends for the serial ports
+//!
+//! None of the backends may block the emulator. Sources that can block are read on
+//! their own thread and the bytes are handed over through a channel. Sinks that can
+//! block are written on their own thread too, and bytes are dropped while that thread
+//! is backed up. Bytes sent while nothing is attached are discarded, just like on a
+//! real serial line.
+
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    net::TcpListener,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd, net::UnixListener},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc,
+    },
+    thread,
+};
+
+use clap::ArgEnum;
+use possum_emu::{Device, SerialHost, Sio, System, Terminal, Uart};
+
+use crate::{
+    slip::{self, Gateway},
+    xmodem,
+};
+
+#[derive(Debug)]
+pub enum Backend {
+    /// Nothing is connected. Transmitted data is discarded.
+    Null,
+
+    /// The host's stdin and stdout
+    Stdio,
+
+    /// A new pseudo-terminal. Its path is printed so that `screen` or `minicom`
+    /// can attach to it.
+    Pty,
+
+    /// A TCP listener on localhost, serving one connection at a time
+    Tcp(u16),
+
+    /// A Unix domain socket listener, serving one connection at a time
+    Unix(PathBuf),
+
+    /// Received data is read from `input` and transmitted data is written to `output`
+    File { input: PathBuf, output: PathBuf },
+
+    /// A SLIP gateway to the host's localhost
+    Slip,
+
+    /// Sends a file to the guest with XMODEM
+    XmodemSend(PathBuf),
+
+    /// Receives a file from the guest with XMODEM
+    XmodemReceive(PathBuf),
+
+    /// Sends a batch of files to the guest with YMODEM
+    YmodemSend(Vec<PathBuf>),
+
+    /// Receives a batch of files from the guest with YMODEM, into a directory
+    YmodemReceive(PathBuf),
+
+    /// A VT100 terminal in its own window
+    Terminal,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s, None),
+        };
+        match (kind, arg) {
+            ("null", None) => Ok(Self::Null),
+            ("stdio", None) => Ok(Self::Stdio),
+            ("pty", None) => Ok(Self::Pty),
+            ("slip", None) => Ok(Self::Slip),
+            ("term", None) => Ok(Self::Terminal),
+            ("xmodem-send", Some(path)) => Ok(Self::XmodemSend(path.into())),
+            ("xmodem-recv", Some(path)) => Ok(Self::XmodemReceive(path.into())),
+            ("ymodem-send", Some(paths)) => Ok(Self::YmodemSend(
+                paths.split(',').map(PathBuf::from).collect(),
+            )),
+            ("ymodem-recv", Some(path)) => Ok(Self::YmodemReceive(path.into())),
+            ("tcp", Some(port)) => port
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|_| format!("invalid tcp port `{port}`")),
+            ("unix", Some(path)) => Ok(Self::Unix(path.into())),
+            ("file", Some(paths)) => match paths.split_once(',') {
+                Some((input, output)) => Ok(Self::File {
+                    input: input.into(),
+                    output: output.into(),
+                }),
+                None => Err(format!("expected `file:INPUT,OUTPUT` but got `{s}`")),
+            },
+            _ => Err(format!(
+                "unknown serial backend `{s}` (expected `null`, `stdio`, `pty`, `tcp:PORT`, \
+                 `unix:PATH`, `file:INPUT,OUTPUT`, `slip`, `term`, \
+                 `xmodem-send:FILE`, `xmodem-recv:FILE`, `ymodem-send:FILE[,FILE...]` or \
+                 `ymodem-recv:DIR`)"
+            )),
+        }
+    }
+}
+
+impl Backend {
+    /// Opens the host end of the serial line. The terminal backend also returns the
+    /// terminal so that the frontend can display it.
+    pub fn open(&self) -> io::Result<(Box<dyn SerialHost>, Option<Terminal>)> {
+        let host: Box<dyn SerialHost> = match self {
+            Self::Null => Box::new(Null),
+            Self::Stdio => Box::new(Stdio::new()),
+            Self::Pty => {
+                let pty = Pty::open()?;
+                eprintln!("serial port attached to {}", pty.path.display());
+                Box::new(pty)
+            }
+            Self::Tcp(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", *port))?;
+                eprintln!("serial port listening on {}", listener.local_addr()?);
+                Box::new(Listener::spawn(move || {
+                    let (stream, _) = listener.accept()?;
+                    Ok((stream.try_clone()?, stream))
+                }))
+            }
+            Self::Unix(path) => {
+                let listener = UnixListener::bind(path)?;
+                eprintln!("serial port listening on {}", path.display());
+                Box::new(Listener::spawn(move || {
+                    let (stream, _) = listener.accept()?;
+                    Ok((stream.try_clone()?, stream))
+                }))
+            }
+            Self::File { input, output } => Box::new(Files {
+                input: File::open(input)?,
+                output: File::create(output)?,
+            }),
+            Self::Slip => {
+                eprintln!("serial port is a SLIP gateway at {}", slip::GATEWAY_ADDR);
+                Box::new(Gateway::new())
+            }
+            Self::XmodemSend(path) => {
+                Box::new(xmodem::Sender::new(std::slice::from_ref(path), false)?)
+            }
+            Self::XmodemReceive(path) => Box::new(xmodem::Receiver::new(path.clone(), false)),
+            Self::YmodemSend(paths) => Box::new(xmodem::Sender::new(paths, true)?),
+            Self::YmodemReceive(path) => Box::new(xmodem::Receiver::new(path.clone(), true)),
+            Self::Terminal => {
+                let terminal = Terminal::default();
+                return Ok((Box::new(terminal.clone()), Some(terminal)));
+            }
+        };
+        Ok((host, None))
+    }
+}
+
+/// The serial controller behind the serial port blocks
+#[derive(ArgEnum, Copy, Clone, Debug)]
+pub enum Chip {
+    /// A 16550A UART in each block
+    Uart,
+
+    /// A Z80 SIO/2 in the first block, with a channel for each serial port
+    Sio,
+}
+
+impl Chip {
+    /// Builds the devices for the serial port blocks from the hosts of both ports.
+    /// `flow` enables RTS flow control for each port.
+    pub fn build(
+        self,
+        ser1: Box<dyn SerialHost>,
+        ser2: Box<dyn SerialHost>,
+        flow: [bool; 2],
+    ) -> (Box<dyn Device>, Option<Box<dyn Device>>) {
+        match self {
+            Self::Uart => {
+                let mut uart1 = Uart::new(ser1, System::CLOCK_HZ);
+                uart1.set_flow_control(flow[0]);
+                let mut uart2 = Uart::new(ser2, System::CLOCK_HZ);
+                uart2.set_flow_control(flow[1]);
+                (Box::new(uart1), Some(Box::new(uart2)))
+            }
+            Self::Sio => {
+                let mut sio = Sio::new(ser1, ser2, System::CLOCK_HZ);
+                sio.set_flow_control(0, flow[0]);
+                sio.set_flow_control(1, flow[1]);
+                (Box::new(sio), None)
+            }
+        }
+    }
+}
+
+/// Sends everything from `reader` until it closes.
+/// Returns false if the receiving end went away first.
+fn forward(reader: &mut impl Read, tx: &Sender<u8>) -> bool {
+    let mut buf = [0; 256];
+    while let Ok(read) = reader.read(&mut buf) {
+        if read == 0 {
+            break;
+        }
+        if buf[..read].iter().any(|byte| tx.send(*byte).is_err()) {
+            return false;
+        }
+    }
+    true
+}
+
+/// How many writes can wait for a writer thread before more are dropped
+const WRITE_QUEUE: usize = 1024;
+
+type BoxedWrite = Box<dyn Write + Send>;
+
+/// Writes everything sent to the returned channel to the latest writer from `writers`.
+/// A writer that fails is dropped until the next one arrives.
+fn spawn_writer(writers: Receiver<BoxedWrite>) -> SyncSender<Vec<u8>> {
+    let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(WRITE_QUEUE);
+    thread::spawn(move || {
+        let mut current = None;
+        for data in rx {
+            while let Ok(writer) = writers.try_recv() {
+                current = Some(writer);
+            }
+            let failed = match current.as_mut() {
+                Some(writer) => writer
+                    .write_all(&data)
+                    .and_then(|_| writer.flush())
+                    .is_err(),
+                None => false,
+            };
+            if failed {
+                current = None;
+            }
+        }
+    });
+    tx
+}
+
+fn try_read(rx: &Receiver<u8>, buf: &mut [u8]) -> usize {
+    let mut read = 0;
+    while read < buf.len() {
+        match rx.try_recv() {
+            Ok(byte) => {
+                buf[read] = byte;
+                read += 1;
+            }
+            Err(_) => break,
+        }
+    }
+    read
+}
+
+struct Null;
+
+impl Read for Null {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Null {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// All of the modem lines are low
+impl SerialHost for Null {
+    fn clear_to_send(&mut self) -> bool {
+        false
+    }
+
+    fn connected(&mut self) -> bool {
+        false
+    }
+}
+
+struct Stdio {
+    rx: Receiver<u8>,
+    stdout: SyncSender<Vec<u8>>,
+}
+
+impl Stdio {
+    fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || forward(&mut io::stdin(), &tx));
+        let (writers, stdout) = mpsc::channel();
+        writers.send(Box::new(io::stdout()) as BoxedWrite).unwrap();
+        Self {
+            rx,
+            stdout: spawn_writer(stdout),
+        }
+    }
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(try_read(&self.rx, buf))
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _ = self.stdout.try_send(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialHost for Stdio {}
+
+/// The master side of a pseudo-terminal, opened non-blocking
+struct Pty {
+    master: File,
+    /// Held open so the terminal keeps its settings (and reads don't fail)
+    /// while nothing is attached
+    _slave: File,
+    path: PathBuf,
+}
+
+impl Pty {
+    fn open() -> io::Result<Self> {
+        let master = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open("/dev/ptmx")?;
+        let fd = master.as_raw_fd();
+
+        let mut name = [0; 64];
+        // SAFETY: `fd` is an open pty master and `name` outlives the calls
+        let path = unsafe {
+            if libc::grantpt(fd) != 0
+                || libc::unlockpt(fd) != 0
+                || libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            PathBuf::from(CStr::from_ptr(name.as_ptr()).to_string_lossy().as_ref())
+        };
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        // The guest expects a raw line. No echo and no translation.
+        // SAFETY: `termios` is filled in by `tcgetattr` before it is used
+        unsafe {
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Self {
+            master,
+            _slave: slave,
+            path,
+        })
+    }
+}
+
+impl Read for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.master.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Write for Pty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.master.write(buf) {
+            // Nobody is reading the terminal
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialHost for Pty {}
+
+/// Accepts connections on its own thread, one at a time
+struct Listener {
+    rx: Receiver<u8>,
+    tx: SyncSender<Vec<u8>>,
+    connected: Arc<AtomicBool>,
+}
+
+impl Listener {
+    /// `accept` waits for the next connection and returns its read and write halves
+    fn spawn<R, W>(mut accept: impl FnMut() -> io::Result<(R, W)> + Send + 'static) -> Self
+    where
+        R: Read,
+        W: Write + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let (writers, connections) = mpsc::channel();
+        let connected = Arc::new(AtomicBool::new(false));
+        let shared = connected.clone();
+        thread::spawn(move || {
+            while let Ok((mut reader, writer)) = accept() {
+                if writers.send(Box::new(writer) as BoxedWrite).is_err() {
+                    break;
+                }
+                shared.store(true, Ordering::Release);
+                let running = forward(&mut reader, &tx);
+                shared.store(false, Ordering::Release);
+                if !running {
+                    break;
+                }
+            }
+        });
+        Self {
+            rx,
+            tx: spawn_writer(connections),
+            connected,
+        }
+    }
+}
+
+impl Read for Listener {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(try_read(&self.rx, buf))
+    }
+}
+
+impl Write for Listener {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.connected() {
+            let _ = self.tx.try_send(buf.to_vec());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The lines are up while a client is connected
+impl SerialHost for Listener {
+    fn clear_to_send(&mut self) -> bool {
+        self.connected()
+    }
+
+    fn connected(&mut self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+}
+
+/// Reading a regular file never blocks, so these are used directly
+struct Files {
+    input: File,
+    output: File,
+}
+
+impl Read for Files {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Files {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl SerialHost for Files {}
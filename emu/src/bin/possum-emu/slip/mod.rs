@@ -0,0 +1,741 @@
+//! SLIP network gateway
+//!
+//! A serial backend that decodes the SLIP frames sent by the guest and hands them to a
+//! tiny userspace IPv4 stack. The gateway answers pings sent to its own address, and
+//! proxies TCP and UDP sent to its address to the same ports on the host's localhost.
+//! No root privileges or TUN devices are needed.
+//!
+//! Only connections opened by the guest are supported. The serial line never loses data
+//! so TCP is kept simple: unacknowledged data is only resent after the guest has been
+//! quiet for a while. Connecting to the host happens on a thread of its own, so a slow
+//! host doesn't hold up the emulator.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream, UdpSocket},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use possum_emu::SerialHost;
+
+/// The address of the gateway as seen by the guest
+pub const GATEWAY_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// The traditional SLIP MTU. Larger frames from the guest are dropped.
+const MTU: usize = 1006;
+
+const TTL: u8 = 64;
+
+/// The largest TCP payload sent to the guest. This is the TCP default, which the guest
+/// has to accept even if it sends no MSS option.
+const MSS: usize = 536;
+
+/// How much the gateway will hold for the host before closing its receive window
+const WINDOW: usize = 4096;
+
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// UDP has no end, so sockets are closed once nothing has gone either way for this long
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Protocol;
+impl Protocol {
+    const ICMP: u8 = 1;
+    const TCP: u8 = 6;
+    const UDP: u8 = 17;
+}
+
+struct IcmpType;
+impl IcmpType {
+    const ECHO_REPLY: u8 = 0;
+    const ECHO_REQUEST: u8 = 8;
+}
+
+struct TcpFlag;
+impl TcpFlag {
+    const FIN: u8 = 0x01;
+    const SYN: u8 = 0x02;
+    const RST: u8 = 0x04;
+    const PSH: u8 = 0x08;
+    const ACK: u8 = 0x10;
+}
+
+/// Adds `data` to a running internet checksum
+fn checksum(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [byte] = chunks.remainder() {
+        sum += (*byte as u32) << 8;
+    }
+    sum
+}
+
+/// The final ones' complement of a running checksum.
+/// Checking data that includes a correct checksum gives 0.
+fn fold(mut sum: u32) -> u16 {
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The TCP and UDP checksums also cover these fields of the IP header
+fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let sum = checksum(0, &src.octets());
+    let sum = checksum(sum, &dst.octets());
+    sum + (protocol as u32) + (len as u32)
+}
+
+#[inline]
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+#[inline]
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// The gateway's end of the serial line
+struct Link {
+    /// SLIP encoded bytes waiting for the guest to receive them
+    rx: VecDeque<u8>,
+    id: u16,
+}
+
+impl Link {
+    fn send(&mut self, dst: Ipv4Addr, protocol: u8, payload: &[u8]) {
+        let len = 20 + payload.len();
+        let mut packet = Vec::with_capacity(len);
+        packet.extend([0x45, 0x00]);
+        packet.extend((len as u16).to_be_bytes());
+        packet.extend(self.id.to_be_bytes());
+        // Don't fragment
+        packet.extend([0x40, 0x00, TTL, protocol, 0x00, 0x00]);
+        packet.extend(GATEWAY_ADDR.octets());
+        packet.extend(dst.octets());
+        let sum = fold(checksum(0, &packet));
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet.extend(payload);
+        self.id = self.id.wrapping_add(1);
+
+        // Starting with an END flushes out any line noise on the guest's end
+        self.rx.push_back(END);
+        for byte in packet {
+            match byte {
+                END => self.rx.extend([ESC, ESC_END]),
+                ESC => self.rx.extend([ESC, ESC_ESC]),
+                _ => self.rx.push_back(byte),
+            }
+        }
+        self.rx.push_back(END);
+    }
+}
+
+#[derive(Clone)]
+struct TcpHeader {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+}
+
+impl TcpHeader {
+    fn parse(segment: &[u8]) -> Option<(Self, &[u8])> {
+        if segment.len() < 20 {
+            return None;
+        }
+        let offset = ((segment[12] >> 4) as usize) * 4;
+        if offset < 20 || offset > segment.len() {
+            return None;
+        }
+        let header = Self {
+            src_port: u16_at(segment, 0),
+            dst_port: u16_at(segment, 2),
+            seq: u32_at(segment, 4),
+            ack: u32_at(segment, 8),
+            flags: segment[13],
+            window: u16_at(segment, 14),
+        };
+        Some((header, &segment[offset..]))
+    }
+
+    fn to_bytes(&self, dst: Ipv4Addr, options: &[u8], payload: &[u8]) -> Vec<u8> {
+        let offset = 20 + options.len();
+        let mut segment = Vec::with_capacity(offset + payload.len());
+        segment.extend(self.src_port.to_be_bytes());
+        segment.extend(self.dst_port.to_be_bytes());
+        segment.extend(self.seq.to_be_bytes());
+        segment.extend(self.ack.to_be_bytes());
+        segment.extend([((offset / 4) as u8) << 4, self.flags]);
+        segment.extend(self.window.to_be_bytes());
+        // Checksum and urgent pointer
+        segment.extend([0x00, 0x00, 0x00, 0x00]);
+        segment.extend(options);
+        segment.extend(payload);
+
+        let sum = pseudo_header(GATEWAY_ADDR, dst, Protocol::TCP, segment.len());
+        let sum = fold(checksum(sum, &segment));
+        segment[16..18].copy_from_slice(&sum.to_be_bytes());
+        segment
+    }
+}
+
+/// The guest's address and port, and the port on the host
+type Endpoints = (Ipv4Addr, u16, u16);
+
+/// The host end of a proxied TCP connection
+trait HostStream: Read + Write {
+    /// Closes the sending half once the guest has closed its end
+    fn shutdown_write(&mut self);
+}
+
+impl HostStream for TcpStream {
+    fn shutdown_write(&mut self) {
+        self.shutdown(Shutdown::Write).unwrap_or_default();
+    }
+}
+
+/// A SYN from the guest, waiting on the connection to the host
+struct Connecting {
+    syn: TcpHeader,
+    result: Receiver<io::Result<TcpStream>>,
+}
+
+struct UdpBinding {
+    socket: UdpSocket,
+    last_used: Instant,
+}
+
+struct TcpConnection<S = TcpStream> {
+    stream: S,
+    guest: Ipv4Addr,
+    guest_port: u16,
+    port: u16,
+    /// Our initial sequence number. Resent until the guest acknowledges it.
+    isn: u32,
+    established: bool,
+    /// The next sequence number expected from the guest
+    rcv_nxt: u32,
+    /// The sequence number of the first byte of `unacked`
+    snd_una: u32,
+    /// How much the guest is willing to receive
+    snd_wnd: usize,
+    /// Data from the host that the guest hasn't acknowledged yet
+    unacked: VecDeque<u8>,
+    /// How much of `unacked` has been sent
+    sent: usize,
+    /// Data from the guest that the host hasn't taken yet
+    to_host: Vec<u8>,
+    host_closed: bool,
+    guest_closed: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    last_sent: Instant,
+}
+
+impl<S: HostStream> TcpConnection<S> {
+    /// Accepts the guest's SYN, and sends the SYN-ACK
+    fn open(link: &mut Link, stream: S, guest: Ipv4Addr, syn: &TcpHeader, isn: u32) -> Self {
+        let mut connection = Self {
+            stream,
+            guest,
+            guest_port: syn.src_port,
+            port: syn.dst_port,
+            isn,
+            established: false,
+            rcv_nxt: syn.seq.wrapping_add(1),
+            snd_una: isn.wrapping_add(1),
+            snd_wnd: syn.window as usize,
+            unacked: VecDeque::new(),
+            sent: 0,
+            to_host: Vec::new(),
+            host_closed: false,
+            guest_closed: false,
+            fin_sent: false,
+            fin_acked: false,
+            last_sent: Instant::now(),
+        };
+        connection.send(link, TcpFlag::SYN, isn, &[]);
+        connection
+    }
+
+    fn send(&mut self, link: &mut Link, flags: u8, seq: u32, payload: &[u8]) {
+        let options: &[u8] = if (flags & TcpFlag::SYN) != 0 {
+            // MSS
+            &[0x02, 0x04, (MSS >> 8) as u8, MSS as u8]
+        } else {
+            &[]
+        };
+        let header = TcpHeader {
+            src_port: self.port,
+            dst_port: self.guest_port,
+            seq,
+            ack: self.rcv_nxt,
+            flags: flags | TcpFlag::ACK,
+            window: WINDOW.saturating_sub(self.to_host.len()) as u16,
+        };
+        link.send(
+            self.guest,
+            Protocol::TCP,
+            &header.to_bytes(self.guest, options, payload),
+        );
+        self.last_sent = Instant::now();
+    }
+
+    fn send_ack(&mut self, link: &mut Link) {
+        let seq = self.snd_una.wrapping_add(self.sent as u32);
+        self.send(link, 0, seq, &[]);
+    }
+
+    /// Handles a segment from the guest
+    fn receive(&mut self, link: &mut Link, header: &TcpHeader, payload: &[u8]) {
+        if (header.flags & TcpFlag::ACK) != 0 {
+            if !self.established {
+                if header.ack == self.snd_una {
+                    self.established = true;
+                }
+            } else {
+                let acked = header.ack.wrapping_sub(self.snd_una) as usize;
+                if acked <= self.unacked.len() {
+                    self.unacked.drain(..acked);
+                    self.snd_una = header.ack;
+                    self.sent = self.sent.saturating_sub(acked);
+                    if acked > 0 {
+                        self.last_sent = Instant::now();
+                    }
+                } else if self.fin_sent && acked == self.unacked.len() + 1 {
+                    // The FIN takes a sequence number of its own
+                    self.unacked.clear();
+                    self.snd_una = header.ack;
+                    self.sent = 0;
+                    self.fin_acked = true;
+                }
+            }
+            self.snd_wnd = header.window as usize;
+        }
+
+        let mut ack = false;
+        if !payload.is_empty() {
+            // Anything unexpected (or that doesn't fit) is dropped for the guest to resend
+            let room = WINDOW.saturating_sub(self.to_host.len());
+            if header.seq == self.rcv_nxt && payload.len() <= room {
+                self.to_host.extend(payload);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(payload.len() as u32);
+            }
+            ack = true;
+        }
+        if (header.flags & TcpFlag::FIN) != 0 {
+            let fin_seq = header.seq.wrapping_add(payload.len() as u32);
+            if fin_seq == self.rcv_nxt && !self.guest_closed {
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                self.guest_closed = true;
+            }
+            ack = true;
+        }
+        if ack {
+            self.send_ack(link);
+        }
+    }
+
+    /// Moves data between the host and guest.
+    /// Returns false once the connection is finished.
+    fn poll(&mut self, link: &mut Link) -> bool {
+        if !self.established {
+            if self.last_sent.elapsed() > RETRANSMIT_TIMEOUT {
+                self.send(link, TcpFlag::SYN, self.isn, &[]);
+            }
+            return true;
+        }
+
+        if !self.to_host.is_empty() {
+            let was_full = self.to_host.len() >= WINDOW;
+            match self.stream.write(&self.to_host) {
+                Ok(written) => {
+                    self.to_host.drain(..written);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => return self.reset(link),
+            }
+            // Let the guest know the window opened up again
+            if was_full && self.to_host.len() < WINDOW {
+                self.send_ack(link);
+            }
+        }
+        if self.guest_closed && self.to_host.is_empty() {
+            self.stream.shutdown_write();
+        }
+
+        while !self.host_closed && self.unacked.len() < WINDOW {
+            let mut buf = [0; MSS];
+            let len = buf.len().min(WINDOW - self.unacked.len());
+            match self.stream.read(&mut buf[..len]) {
+                Ok(0) => self.host_closed = true,
+                Ok(read) => self.unacked.extend(&buf[..read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return self.reset(link),
+            }
+        }
+
+        // Go back and resend everything the guest hasn't acknowledged
+        if !self.fin_acked
+            && (!self.unacked.is_empty() || self.fin_sent)
+            && self.last_sent.elapsed() > RETRANSMIT_TIMEOUT
+        {
+            self.sent = 0;
+            self.fin_sent = false;
+        }
+
+        while self.sent < self.unacked.len().min(self.snd_wnd) {
+            let len = (self.unacked.len().min(self.snd_wnd) - self.sent).min(MSS);
+            let payload: Vec<u8> = self
+                .unacked
+                .range(self.sent..(self.sent + len))
+                .copied()
+                .collect();
+            let seq = self.snd_una.wrapping_add(self.sent as u32);
+            self.send(link, TcpFlag::PSH, seq, &payload);
+            self.sent += len;
+        }
+
+        if self.host_closed && !self.fin_sent && !self.fin_acked && self.sent == self.unacked.len()
+        {
+            let seq = self.snd_una.wrapping_add(self.sent as u32);
+            self.send(link, TcpFlag::FIN, seq, &[]);
+            self.fin_sent = true;
+        }
+
+        !(self.guest_closed && self.fin_acked && self.to_host.is_empty())
+    }
+
+    fn reset(&mut self, link: &mut Link) -> bool {
+        let seq = self.snd_una.wrapping_add(self.sent as u32);
+        self.send(link, TcpFlag::RST, seq, &[]);
+        false
+    }
+}
+
+pub struct Gateway {
+    link: Link,
+    /// The frame being received from the guest
+    frame: Vec<u8>,
+    escaped: bool,
+    connecting: HashMap<Endpoints, Connecting>,
+    tcp: HashMap<Endpoints, TcpConnection>,
+    udp: HashMap<Endpoints, UdpBinding>,
+    isn: u32,
+}
+
+impl Gateway {
+    pub fn new() -> Self {
+        Self {
+            link: Link {
+                rx: VecDeque::new(),
+                id: 0,
+            },
+            frame: Vec::with_capacity(MTU),
+            escaped: false,
+            connecting: HashMap::new(),
+            tcp: HashMap::new(),
+            udp: HashMap::new(),
+            isn: 0x1000,
+        }
+    }
+
+    fn receive_byte(&mut self, byte: u8) {
+        match (self.escaped, byte) {
+            (false, END) => {
+                if !self.frame.is_empty() {
+                    let frame = std::mem::take(&mut self.frame);
+                    self.receive_packet(&frame);
+                    self.frame = frame;
+                    self.frame.clear();
+                }
+            }
+            (false, ESC) => self.escaped = true,
+            (true, _) => {
+                self.escaped = false;
+                self.push_frame(match byte {
+                    ESC_END => END,
+                    ESC_ESC => ESC,
+                    // Protocol violation. Pass the byte through like most implementations.
+                    _ => byte,
+                });
+            }
+            (false, _) => self.push_frame(byte),
+        }
+    }
+
+    #[inline]
+    fn push_frame(&mut self, byte: u8) {
+        // Overlong frames are truncated, and then dropped for being malformed
+        if self.frame.len() < MTU {
+            self.frame.push(byte);
+        }
+    }
+
+    fn receive_packet(&mut self, packet: &[u8]) {
+        if packet.len() < 20 || (packet[0] >> 4) != 4 {
+            return;
+        }
+        let header_len = ((packet[0] & 0x0F) as usize) * 4;
+        let len = u16_at(packet, 2) as usize;
+        if header_len < 20 || len < header_len || len > packet.len() {
+            return;
+        }
+        if fold(checksum(0, &packet[..header_len])) != 0 {
+            return;
+        }
+        // Fragments are not reassembled
+        if (u16_at(packet, 6) & 0x3FFF) != 0 {
+            return;
+        }
+        let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        if dst != GATEWAY_ADDR {
+            return;
+        }
+
+        let payload = &packet[header_len..len];
+        match packet[9] {
+            Protocol::ICMP => self.receive_icmp(src, payload),
+            Protocol::TCP => self.receive_tcp(src, payload),
+            Protocol::UDP => self.receive_udp(src, payload),
+            _ => {}
+        }
+    }
+
+    fn receive_icmp(&mut self, src: Ipv4Addr, message: &[u8]) {
+        if message.len() < 8 || fold(checksum(0, message)) != 0 {
+            return;
+        }
+        if message[0] == IcmpType::ECHO_REQUEST {
+            let mut reply = message.to_vec();
+            reply[0] = IcmpType::ECHO_REPLY;
+            reply[2..4].fill(0);
+            let sum = fold(checksum(0, &reply));
+            reply[2..4].copy_from_slice(&sum.to_be_bytes());
+            self.link.send(src, Protocol::ICMP, &reply);
+        }
+    }
+
+    fn receive_udp(&mut self, src: Ipv4Addr, datagram: &[u8]) {
+        if datagram.len() < 8 {
+            return;
+        }
+        let len = u16_at(datagram, 4) as usize;
+        if len < 8 || len > datagram.len() {
+            return;
+        }
+        let datagram = &datagram[..len];
+        // A zero checksum means the sender didn't compute one
+        if u16_at(datagram, 6) != 0 {
+            let sum = pseudo_header(src, GATEWAY_ADDR, Protocol::UDP, len);
+            if fold(checksum(sum, datagram)) != 0 {
+                return;
+            }
+        }
+
+        let endpoints = (src, u16_at(datagram, 0), u16_at(datagram, 2));
+        let binding = match self.udp.entry(endpoints) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match Self::open_udp(endpoints.2) {
+                Ok(socket) => entry.insert(UdpBinding {
+                    socket,
+                    last_used: Instant::now(),
+                }),
+                Err(_) => return,
+            },
+        };
+        binding.last_used = Instant::now();
+        binding.socket.send(&datagram[8..]).unwrap_or_default();
+    }
+
+    fn open_udp(port: u16) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.connect((Ipv4Addr::LOCALHOST, port))?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    fn receive_tcp(&mut self, src: Ipv4Addr, segment: &[u8]) {
+        let sum = pseudo_header(src, GATEWAY_ADDR, Protocol::TCP, segment.len());
+        if fold(checksum(sum, segment)) != 0 {
+            return;
+        }
+        let (header, payload) = match TcpHeader::parse(segment) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        let endpoints = (src, header.src_port, header.dst_port);
+
+        if (header.flags & TcpFlag::RST) != 0 {
+            self.connecting.remove(&endpoints);
+            self.tcp.remove(&endpoints);
+            return;
+        }
+        // The SYN-ACK waits for the host to answer
+        if self.connecting.contains_key(&endpoints) {
+            return;
+        }
+
+        if let Some(connection) = self.tcp.get_mut(&endpoints) {
+            if (header.flags & TcpFlag::SYN) != 0 {
+                // The guest didn't see our SYN-ACK
+                if !connection.established {
+                    connection.send(&mut self.link, TcpFlag::SYN, connection.isn, &[]);
+                }
+                return;
+            }
+            connection.receive(&mut self.link, &header, payload);
+            return;
+        }
+
+        if (header.flags & (TcpFlag::SYN | TcpFlag::ACK)) != TcpFlag::SYN {
+            // Nothing is known about this connection
+            if (header.flags & TcpFlag::ACK) != 0 {
+                self.send_reset(src, &header, header.ack, 0);
+            }
+            return;
+        }
+
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, header.dst_port));
+        let (send, result) = mpsc::channel();
+        thread::spawn(move || {
+            let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+                .and_then(|stream| stream.set_nonblocking(true).map(|_| stream));
+            send.send(stream).unwrap_or_default();
+        });
+        self.connecting.insert(
+            endpoints,
+            Connecting {
+                syn: header,
+                result,
+            },
+        );
+    }
+
+    /// Answers the SYNs whose connections to the host have finished
+    fn poll_connecting(&mut self) {
+        let Self {
+            link,
+            connecting,
+            tcp,
+            isn,
+            ..
+        } = self;
+        let mut failed = Vec::new();
+        connecting.retain(|&endpoints, connecting| {
+            let stream = match connecting.result.try_recv() {
+                Err(TryRecvError::Empty) => return true,
+                Ok(Ok(stream)) => stream,
+                Ok(Err(_)) | Err(TryRecvError::Disconnected) => {
+                    failed.push((endpoints.0, connecting.syn.clone()));
+                    return false;
+                }
+            };
+            let connection = TcpConnection::open(link, stream, endpoints.0, &connecting.syn, *isn);
+            *isn = isn.wrapping_add(0x10000);
+            tcp.insert(endpoints, connection);
+            false
+        });
+        for (guest, syn) in failed {
+            let ack = syn.seq.wrapping_add(1);
+            self.send_reset(guest, &syn, 0, ack);
+        }
+    }
+
+    fn send_reset(&mut self, dst: Ipv4Addr, to: &TcpHeader, seq: u32, ack: u32) {
+        let flags = if ack != 0 {
+            TcpFlag::RST | TcpFlag::ACK
+        } else {
+            TcpFlag::RST
+        };
+        let header = TcpHeader {
+            src_port: to.dst_port,
+            dst_port: to.src_port,
+            seq,
+            ack,
+            flags,
+            window: 0,
+        };
+        self.link
+            .send(dst, Protocol::TCP, &header.to_bytes(dst, &[], &[]));
+    }
+
+    /// Picks up anything the host has sent back
+    fn poll(&mut self) {
+        self.poll_connecting();
+        let Self { link, tcp, udp, .. } = self;
+        tcp.retain(|_, connection| connection.poll(link));
+
+        udp.retain(|_, binding| binding.last_used.elapsed() < UDP_TIMEOUT);
+        for (&(guest, guest_port, port), binding) in udp.iter_mut() {
+            let mut buf = [0; MTU - 28];
+            while let Ok(read) = binding.socket.recv(&mut buf) {
+                binding.last_used = Instant::now();
+                let len = 8 + read;
+                let mut datagram = Vec::with_capacity(len);
+                datagram.extend(port.to_be_bytes());
+                datagram.extend(guest_port.to_be_bytes());
+                datagram.extend((len as u16).to_be_bytes());
+                datagram.extend([0x00, 0x00]);
+                datagram.extend(&buf[..read]);
+                let sum = pseudo_header(GATEWAY_ADDR, guest, Protocol::UDP, len);
+                let sum = match fold(checksum(sum, &datagram)) {
+                    // Zero means no checksum, so it's sent as all ones instead
+                    0 => 0xFFFF,
+                    sum => sum,
+                };
+                datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+                link.send(guest, Protocol::UDP, &datagram);
+            }
+        }
+    }
+}
+
+impl Read for Gateway {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.link.rx.is_empty() {
+            self.poll();
+        }
+        self.link.rx.read(buf)
+    }
+}
+
+impl Write for Gateway {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.receive_byte(*byte);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialHost for Gateway {}
@@ -0,0 +1,446 @@
+use super::*;
+
+const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// Stands in for the host end of a connection
+#[derive(Default)]
+struct TestStream {
+    from_host: VecDeque<u8>,
+    to_host: Vec<u8>,
+    closed: bool,
+    shut_down: bool,
+}
+
+impl Read for TestStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.from_host.is_empty() && !self.closed {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.from_host.read(buf)
+    }
+}
+
+impl Write for TestStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.to_host.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl HostStream for TestStream {
+    fn shutdown_write(&mut self) {
+        self.shut_down = true;
+    }
+}
+
+fn link() -> Link {
+    Link {
+        rx: VecDeque::new(),
+        id: 0,
+    }
+}
+
+fn encode(packet: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![END];
+    for &byte in packet {
+        match byte {
+            END => encoded.extend([ESC, ESC_END]),
+            ESC => encoded.extend([ESC, ESC_ESC]),
+            _ => encoded.push(byte),
+        }
+    }
+    encoded.push(END);
+    encoded
+}
+
+/// Takes the packets the gateway sent to the guest
+fn decode(rx: &mut VecDeque<u8>) -> Vec<Vec<u8>> {
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut escaped = false;
+    for byte in rx.drain(..) {
+        if escaped {
+            escaped = false;
+            packet.push(match byte {
+                ESC_END => END,
+                ESC_ESC => ESC,
+                _ => panic!("Bad escape"),
+            });
+        } else if byte == ESC {
+            escaped = true;
+        } else if byte != END {
+            packet.push(byte);
+        } else if !packet.is_empty() {
+            packets.push(std::mem::take(&mut packet));
+        }
+    }
+    packets
+}
+
+/// A packet from the guest to the gateway
+fn packet(protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let len = 20 + payload.len();
+    let mut packet = vec![0x45, 0x00];
+    packet.extend((len as u16).to_be_bytes());
+    packet.extend([0x00, 0x00, 0x40, 0x00, TTL, protocol, 0x00, 0x00]);
+    packet.extend(GUEST.octets());
+    packet.extend(GATEWAY_ADDR.octets());
+    let sum = fold(checksum(0, &packet));
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend(payload);
+    packet
+}
+
+/// The payload of a packet to the guest, after checking its header
+fn payload(packet: &[u8], protocol: u8) -> &[u8] {
+    assert_eq!(fold(checksum(0, &packet[..20])), 0);
+    assert_eq!(packet[9], protocol);
+    assert_eq!(packet[12..16], GATEWAY_ADDR.octets());
+    assert_eq!(packet[16..20], GUEST.octets());
+    assert_eq!(u16_at(packet, 2) as usize, packet.len());
+    &packet[20..]
+}
+
+/// The TCP segments sent to the guest, after checking their checksums
+fn segments(link: &mut Link) -> Vec<(TcpHeader, Vec<u8>)> {
+    decode(&mut link.rx)
+        .iter()
+        .map(|packet| {
+            let segment = payload(packet, Protocol::TCP);
+            let sum = pseudo_header(GATEWAY_ADDR, GUEST, Protocol::TCP, segment.len());
+            assert_eq!(fold(checksum(sum, segment)), 0);
+            let (header, payload) = TcpHeader::parse(segment).unwrap();
+            (header, payload.to_vec())
+        })
+        .collect()
+}
+
+/// A segment from the guest
+fn segment(seq: u32, ack: u32, flags: u8) -> TcpHeader {
+    TcpHeader {
+        src_port: 1234,
+        dst_port: 80,
+        seq,
+        ack,
+        flags,
+        window: 1024,
+    }
+}
+
+fn echo_request(data: &[u8]) -> Vec<u8> {
+    let mut message = vec![
+        IcmpType::ECHO_REQUEST,
+        0x00,
+        0x00,
+        0x00,
+        0x12,
+        0x34,
+        0x00,
+        0x01,
+    ];
+    message.extend(data);
+    let sum = fold(checksum(0, &message));
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    message
+}
+
+#[test]
+fn checksums() {
+    // The example IPv4 header from Wikipedia
+    let mut header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xC0, 0xA8, 0x00,
+        0x01, 0xC0, 0xA8, 0x00, 0xC7,
+    ];
+    assert_eq!(fold(checksum(0, &header)), 0xB861);
+    header[10..12].copy_from_slice(&[0xB8, 0x61]);
+    assert_eq!(fold(checksum(0, &header)), 0);
+
+    // Odd lengths are padded with zero
+    assert_eq!(fold(checksum(0, &[0x12, 0x34, 0x56])), !0x6834);
+}
+
+#[test]
+fn slip_escaping() {
+    let mut link = link();
+    link.send(GUEST, Protocol::UDP, &[END, ESC, 0x01]);
+
+    let rx: Vec<u8> = link.rx.iter().copied().collect();
+    assert_eq!(rx[0], END);
+    assert_eq!(
+        rx[(rx.len() - 6)..],
+        [ESC, ESC_END, ESC, ESC_ESC, 0x01, END]
+    );
+    assert_eq!(rx.iter().filter(|&&byte| byte == END).count(), 2);
+
+    let packets = decode(&mut link.rx);
+    assert_eq!(packets.len(), 1);
+    assert_eq!(payload(&packets[0], Protocol::UDP), [END, ESC, 0x01]);
+}
+
+#[test]
+fn echo_reply() {
+    let mut gateway = Gateway::new();
+    // Line noise before the frame is flushed out by the first END
+    gateway.write_all(&[0x01, 0x02]).unwrap();
+    let data = [0xAA, END, ESC, 0x55];
+    gateway
+        .write_all(&encode(&packet(Protocol::ICMP, &echo_request(&data))))
+        .unwrap();
+
+    let packets = decode(&mut gateway.link.rx);
+    assert_eq!(packets.len(), 1);
+    let reply = payload(&packets[0], Protocol::ICMP);
+    assert_eq!(reply[0], IcmpType::ECHO_REPLY);
+    assert_eq!(fold(checksum(0, reply)), 0);
+    // The identifier, sequence number and data come back as they were
+    assert_eq!(reply[4..8], [0x12, 0x34, 0x00, 0x01]);
+    assert_eq!(reply[8..], data);
+}
+
+#[test]
+fn bad_packets_are_dropped() {
+    let mut gateway = Gateway::new();
+
+    let mut request = echo_request(&[1, 2, 3]);
+    request[8] ^= 0xFF;
+    gateway
+        .write_all(&encode(&packet(Protocol::ICMP, &request)))
+        .unwrap();
+
+    let mut bad_header = packet(Protocol::ICMP, &echo_request(&[1, 2, 3]));
+    bad_header[8] = 1;
+    gateway.write_all(&encode(&bad_header)).unwrap();
+
+    let mut elsewhere = packet(Protocol::ICMP, &echo_request(&[1, 2, 3]));
+    elsewhere[19] = 3;
+    gateway.write_all(&encode(&elsewhere)).unwrap();
+
+    assert!(gateway.link.rx.is_empty());
+}
+
+#[test]
+fn unknown_connection_is_reset() {
+    let mut gateway = Gateway::new();
+    let segment = segment(100, 5000, TcpFlag::ACK).to_bytes(GUEST, &[], &[]);
+    gateway
+        .write_all(&encode(&packet(Protocol::TCP, &segment)))
+        .unwrap();
+
+    let [(reset, _)]: [_; 1] = segments(&mut gateway.link).try_into().ok().unwrap();
+    assert_eq!(reset.flags, TcpFlag::RST);
+    assert_eq!(reset.seq, 5000);
+    assert_eq!((reset.src_port, reset.dst_port), (80, 1234));
+}
+
+#[test]
+fn tcp_connection() {
+    let mut link = link();
+    let syn = segment(100, 0, TcpFlag::SYN);
+    let mut connection = TcpConnection::open(&mut link, TestStream::default(), GUEST, &syn, 5000);
+
+    let [(syn_ack, _)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!(syn_ack.flags, TcpFlag::SYN | TcpFlag::ACK);
+    assert_eq!((syn_ack.seq, syn_ack.ack), (5000, 101));
+
+    // The guest finishes the handshake and sends some data
+    connection.receive(&mut link, &segment(101, 5001, TcpFlag::ACK), b"hello");
+    assert!(connection.established);
+    let [(ack, _)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!(ack.ack, 106);
+    assert!(connection.poll(&mut link));
+    assert_eq!(connection.stream.to_host, b"hello");
+
+    // Data out of order is dropped, and the guest is told what was expected
+    connection.receive(&mut link, &segment(200, 5001, TcpFlag::ACK), b"later");
+    let [(ack, _)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!(ack.ack, 106);
+
+    // The host answers
+    connection.stream.from_host.extend(b"world");
+    assert!(connection.poll(&mut link));
+    let [(data, payload)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!(data.flags, TcpFlag::PSH | TcpFlag::ACK);
+    assert_eq!(data.seq, 5001);
+    assert_eq!(payload, b"world");
+
+    // Unacknowledged data is resent once the guest has been quiet for a while
+    assert!(connection.poll(&mut link));
+    assert!(segments(&mut link).is_empty());
+    connection.last_sent -= RETRANSMIT_TIMEOUT * 2;
+    assert!(connection.poll(&mut link));
+    let [(data, payload)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!(data.seq, 5001);
+    assert_eq!(payload, b"world");
+
+    // The host closes, and the FIN follows right behind the data
+    connection.stream.closed = true;
+    assert!(connection.poll(&mut link));
+    let [(fin, _)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!(fin.flags, TcpFlag::FIN | TcpFlag::ACK);
+    assert_eq!(fin.seq, 5006);
+
+    // One ACK covers both, so nothing is resent
+    connection.receive(&mut link, &segment(106, 5007, TcpFlag::ACK), b"");
+    assert!(connection.unacked.is_empty());
+    assert!(connection.fin_acked);
+    connection.last_sent -= RETRANSMIT_TIMEOUT * 2;
+    assert!(connection.poll(&mut link));
+    assert!(segments(&mut link).is_empty());
+
+    // Then the guest closes. Its FIN is acknowledged from past our own.
+    connection.receive(
+        &mut link,
+        &segment(106, 5007, TcpFlag::FIN | TcpFlag::ACK),
+        b"",
+    );
+    let [(ack, _)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!((ack.seq, ack.ack), (5007, 107));
+    assert!(!connection.poll(&mut link));
+    assert!(connection.stream.shut_down);
+}
+
+#[test]
+fn tcp_fin_acked_separately() {
+    let mut link = link();
+    let syn = segment(100, 0, TcpFlag::SYN);
+    let mut connection = TcpConnection::open(&mut link, TestStream::default(), GUEST, &syn, 5000);
+    connection.receive(&mut link, &segment(101, 5001, TcpFlag::ACK), b"");
+    segments(&mut link);
+
+    // The data and the FIN are acknowledged one at a time
+    connection.stream.from_host.extend(b"bye");
+    connection.stream.closed = true;
+    assert!(connection.poll(&mut link));
+    let [(data, _), (fin, _)]: [_; 2] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!(data.seq, 5001);
+    assert_eq!(fin.seq, 5004);
+
+    connection.receive(&mut link, &segment(101, 5004, TcpFlag::ACK), b"");
+    assert!(connection.unacked.is_empty());
+    connection.receive(&mut link, &segment(101, 5005, TcpFlag::ACK), b"");
+    assert!(connection.fin_acked);
+    connection.last_sent -= RETRANSMIT_TIMEOUT * 2;
+    assert!(connection.poll(&mut link));
+    assert!(segments(&mut link).is_empty());
+
+    connection.receive(
+        &mut link,
+        &segment(101, 5005, TcpFlag::FIN | TcpFlag::ACK),
+        b"",
+    );
+    let [(ack, _)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!((ack.seq, ack.ack), (5005, 102));
+    assert!(!connection.poll(&mut link));
+}
+
+#[test]
+fn tcp_window() {
+    let mut link = link();
+    let small = |seq, ack, flags| TcpHeader {
+        window: 4,
+        ..segment(seq, ack, flags)
+    };
+    let mut connection = TcpConnection::open(
+        &mut link,
+        TestStream::default(),
+        GUEST,
+        &small(100, 0, TcpFlag::SYN),
+        5000,
+    );
+    connection.receive(&mut link, &small(101, 5001, TcpFlag::ACK), b"");
+    segments(&mut link);
+
+    // Only as much as the guest has room for is sent
+    connection.stream.from_host.extend(b"abcdefgh");
+    connection.poll(&mut link);
+    let [(_, payload)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!(payload, b"abcd");
+
+    connection.receive(&mut link, &small(101, 5005, TcpFlag::ACK), b"");
+    connection.poll(&mut link);
+    let [(data, payload)]: [_; 1] = segments(&mut link).try_into().ok().unwrap();
+    assert_eq!(data.seq, 5005);
+    assert_eq!(payload, b"efgh");
+}
+
+/// Polls the gateway until it sends something to the guest
+fn wait_for_segments(gateway: &mut Gateway) -> Vec<(TcpHeader, Vec<u8>)> {
+    for _ in 0..500 {
+        gateway.poll();
+        if !gateway.link.rx.is_empty() {
+            return segments(&mut gateway.link);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Nothing was sent");
+}
+
+fn send_syn(gateway: &mut Gateway, port: u16) {
+    let syn = TcpHeader {
+        dst_port: port,
+        ..segment(100, 0, TcpFlag::SYN)
+    };
+    gateway
+        .write_all(&encode(&packet(
+            Protocol::TCP,
+            &syn.to_bytes(GUEST, &[], &[]),
+        )))
+        .unwrap();
+}
+
+#[test]
+fn connect() {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut gateway = Gateway::new();
+    send_syn(&mut gateway, port);
+    // A repeated SYN is ignored while connecting
+    send_syn(&mut gateway, port);
+
+    let [(syn_ack, _)]: [_; 1] = wait_for_segments(&mut gateway).try_into().ok().unwrap();
+    assert_eq!(syn_ack.flags, TcpFlag::SYN | TcpFlag::ACK);
+    assert_eq!(syn_ack.ack, 101);
+    assert!(gateway.connecting.is_empty());
+    assert_eq!(gateway.tcp.len(), 1);
+}
+
+#[test]
+fn connection_refused() {
+    // Nothing is listening once the listener is gone
+    let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut gateway = Gateway::new();
+    send_syn(&mut gateway, port);
+
+    let [(reset, _)]: [_; 1] = wait_for_segments(&mut gateway).try_into().ok().unwrap();
+    assert_eq!(reset.flags, TcpFlag::RST | TcpFlag::ACK);
+    assert_eq!(reset.ack, 101);
+    assert!(gateway.connecting.is_empty());
+    assert!(gateway.tcp.is_empty());
+}
+
+#[test]
+fn udp_expiry() {
+    let mut gateway = Gateway::new();
+    let mut datagram = vec![0x04, 0xD2, 0x00, 0x09, 0x00, 0x0B, 0x00, 0x00];
+    datagram.extend(b"abc");
+    gateway
+        .write_all(&encode(&packet(Protocol::UDP, &datagram)))
+        .unwrap();
+    assert_eq!(gateway.udp.len(), 1);
+
+    gateway.poll();
+    assert_eq!(gateway.udp.len(), 1);
+    for binding in gateway.udp.values_mut() {
+        binding.last_used -= UDP_TIMEOUT * 2;
+    }
+    gateway.poll();
+    assert!(gateway.udp.is_empty());
+}
@@ -0,0 +1,472 @@
+//! XMODEM and YMODEM file transfers
+//!
+//! Serial backends that send or receive host files. XMODEM transfers a single file with
+//! 1K blocks and CRCs (falling back to 128 byte blocks with checksums if the guest asks).
+//! YMODEM sends a header block ahead of each file so that several files can be sent in
+//! a batch, with their names and exact sizes.
+//!
+//! The guest's side of the transfer runs at the emulated baud rate. The host has no
+//! clock of its own, so it counts the times it is polled for data while idle instead.
+//! The UART polls once per character time.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use possum_emu::SerialHost;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Pads out the last block of an XMODEM file
+const SUB: u8 = 0x1A;
+/// Asks the sender to use CRCs instead of checksums
+const CRC: u8 = b'C';
+
+/// Character times to wait before (re)starting a transfer, or giving up on a packet
+const TIMEOUT: usize = 4096;
+
+/// The receiver gives up after this many failed attempts in a row
+const MAX_RETRIES: usize = 10;
+
+/// CRC-16/XMODEM
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if (crc & 0x8000) != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// `data` is padded out to the block size with `pad`
+fn packet(block: u8, data: &[u8], pad: u8, crc: bool) -> Vec<u8> {
+    let (start, size) = if crc && data.len() > 128 {
+        (STX, 1024)
+    } else {
+        (SOH, 128)
+    };
+    let mut packet = Vec::with_capacity(size + 5);
+    packet.extend([start, block, !block]);
+    packet.extend(data);
+    packet.resize(3 + size, pad);
+    if crc {
+        packet.extend(crc16(&packet[3..]).to_be_bytes());
+    } else {
+        packet.push(checksum(&packet[3..]));
+    }
+    packet
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum SendState {
+    /// Waiting for the receiver to ask for the next header or file
+    Start,
+    Header,
+    Data,
+    Eot,
+    Done,
+}
+
+pub struct Sender {
+    /// The names and contents of the files left to send
+    files: VecDeque<(String, Vec<u8>)>,
+    batch: bool,
+    state: SendState,
+    /// The header for the current file has been sent
+    started: bool,
+    crc: bool,
+    block: u8,
+    offset: usize,
+    /// The last packet sent, in case it has to be sent again
+    packet: Vec<u8>,
+    tx: VecDeque<u8>,
+}
+
+impl Sender {
+    /// Without `batch`, only the first file is sent (with XMODEM)
+    pub fn new(paths: &[PathBuf], batch: bool) -> io::Result<Self> {
+        let mut files = VecDeque::new();
+        for path in paths {
+            files.push_back((file_name(path), fs::read(path)?));
+        }
+        Ok(Self {
+            files,
+            batch,
+            state: SendState::Start,
+            started: false,
+            crc: true,
+            block: 1,
+            offset: 0,
+            packet: Vec::new(),
+            tx: VecDeque::new(),
+        })
+    }
+
+    fn send_packet(&mut self, packet: Vec<u8>) {
+        self.tx.extend(&packet);
+        self.packet = packet;
+    }
+
+    fn send_header(&mut self) {
+        let mut header = Vec::new();
+        // An empty header ends the batch
+        if let Some((name, data)) = self.files.front() {
+            header.extend(name.bytes());
+            header.push(0);
+            header.extend(data.len().to_string().bytes());
+            header.push(0);
+        }
+        self.send_packet(packet(0, &header, 0, self.crc));
+        self.state = SendState::Header;
+    }
+
+    fn send_block(&mut self) {
+        let data = &self.files[0].1;
+        let size = if self.crc && (data.len() - self.offset) > 128 {
+            1024
+        } else {
+            128
+        };
+        let end = data.len().min(self.offset + size);
+        let packet = packet(self.block, &data[self.offset..end], SUB, self.crc);
+        self.send_packet(packet);
+        self.state = SendState::Data;
+    }
+
+    fn send_data(&mut self) {
+        if self.offset < self.files[0].1.len() {
+            self.send_block();
+        } else {
+            self.tx.push_back(EOT);
+            self.state = SendState::Eot;
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match (self.state, byte) {
+            (_, CAN) => {
+                eprintln!("transfer cancelled by the guest");
+                self.state = SendState::Done;
+            }
+
+            (SendState::Start, CRC | NAK) => {
+                self.crc = byte == CRC;
+                if self.batch && !self.started {
+                    self.send_header();
+                } else {
+                    self.send_data();
+                }
+            }
+
+            (SendState::Header, ACK) => {
+                if self.files.is_empty() {
+                    eprintln!("transfer complete");
+                    self.state = SendState::Done;
+                } else {
+                    // The receiver asks for the data separately
+                    self.started = true;
+                    self.state = SendState::Start;
+                }
+            }
+
+            (SendState::Data, ACK) => {
+                self.offset += self.packet.len() - if self.crc { 5 } else { 4 };
+                self.block = self.block.wrapping_add(1);
+                self.send_data();
+            }
+
+            (SendState::Header | SendState::Data, NAK | CRC) => self.tx.extend(&self.packet),
+
+            (SendState::Eot, NAK) => self.tx.push_back(EOT),
+
+            (SendState::Eot, ACK) => {
+                if let Some((name, _)) = self.files.pop_front() {
+                    eprintln!("sent {name}");
+                }
+                self.block = 1;
+                self.offset = 0;
+                self.started = false;
+                self.state = if self.batch {
+                    SendState::Start
+                } else {
+                    SendState::Done
+                };
+            }
+
+            _ => {}
+        }
+    }
+}
+
+impl Read for Sender {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tx.read(buf)
+    }
+}
+
+impl Write for Sender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.receive(*byte);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialHost for Sender {}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ReceiveState {
+    /// Asking for a header (or the file with XMODEM)
+    Start,
+    Header,
+    Data,
+    Done,
+}
+
+pub struct Receiver {
+    /// The file with XMODEM, or the directory to put files in with YMODEM
+    target: PathBuf,
+    batch: bool,
+    state: ReceiveState,
+    /// The packet being received
+    packet: Vec<u8>,
+    block: u8,
+    /// The name and size from the YMODEM header
+    name: String,
+    size: Option<usize>,
+    data: Vec<u8>,
+    /// YMODEM expects the EOT to be sent twice
+    eot_seen: bool,
+    idle: usize,
+    retries: usize,
+    tx: VecDeque<u8>,
+}
+
+impl Receiver {
+    pub fn new(target: PathBuf, batch: bool) -> Self {
+        Self {
+            target,
+            batch,
+            state: ReceiveState::Start,
+            packet: Vec::with_capacity(1029),
+            block: 1,
+            name: String::new(),
+            size: None,
+            data: Vec::new(),
+            eot_seen: false,
+            // Ask right away
+            idle: TIMEOUT,
+            retries: 0,
+            tx: VecDeque::new(),
+        }
+    }
+
+    fn cancel(&mut self, reason: &str) {
+        eprintln!("transfer cancelled: {reason}");
+        self.tx.extend([CAN, CAN]);
+        self.state = ReceiveState::Done;
+    }
+
+    fn retry(&mut self) {
+        let damaged = !self.packet.is_empty();
+        self.packet.clear();
+        match self.state {
+            // Keep asking until the sender is ready
+            ReceiveState::Start if !damaged => self.tx.push_back(CRC),
+
+            ReceiveState::Start | ReceiveState::Header | ReceiveState::Data => {
+                self.retries += 1;
+                if self.retries > MAX_RETRIES {
+                    self.cancel("too many errors");
+                } else if self.state == ReceiveState::Data {
+                    self.tx.push_back(NAK);
+                } else {
+                    self.tx.push_back(CRC);
+                }
+            }
+
+            ReceiveState::Done => {}
+        }
+    }
+
+    fn save(&mut self) {
+        let mut data = std::mem::take(&mut self.data);
+        match self.size {
+            Some(size) => data.truncate(size),
+            None => {
+                while data.last() == Some(&SUB) {
+                    data.pop();
+                }
+            }
+        }
+        let path = if self.batch {
+            self.target.join(&self.name)
+        } else {
+            self.target.clone()
+        };
+        match fs::write(&path, data) {
+            Ok(_) => eprintln!("received {}", path.display()),
+            Err(e) => eprintln!("failed to write {}: {e}", path.display()),
+        }
+    }
+
+    /// The size of the whole packet starting with `start`
+    fn packet_len(start: u8) -> usize {
+        match start {
+            SOH => 128 + 5,
+            _ => 1024 + 5,
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        let packet = std::mem::take(&mut self.packet);
+        let block = packet[1];
+        let data = &packet[3..(packet.len() - 2)];
+        let crc = u16::from_be_bytes([packet[packet.len() - 2], packet[packet.len() - 1]]);
+        if (block != !packet[2]) || (crc16(data) != crc) {
+            self.packet = packet;
+            return self.retry();
+        }
+        self.retries = 0;
+
+        let expecting_header = self.batch && self.state != ReceiveState::Data;
+        if expecting_header && block == 0 {
+            let mut fields = data.split(|byte| *byte == 0);
+            let name = fields.next().unwrap_or_default();
+            if name.is_empty() {
+                eprintln!("transfer complete");
+                self.tx.push_back(ACK);
+                self.state = ReceiveState::Done;
+            } else {
+                // Never write outside of the target directory
+                self.name = file_name(Path::new(&*String::from_utf8_lossy(name)));
+                self.size = fields
+                    .next()
+                    .and_then(|size| std::str::from_utf8(size).ok())
+                    .and_then(|size| size.split(' ').next())
+                    .and_then(|size| size.parse().ok());
+                self.tx.extend([ACK, CRC]);
+                self.state = ReceiveState::Header;
+            }
+        } else if block == self.block {
+            self.data.extend(data);
+            self.block = self.block.wrapping_add(1);
+            self.tx.push_back(ACK);
+            self.state = ReceiveState::Data;
+        } else if block == self.block.wrapping_sub(1) {
+            // Our ACK was lost. The block is a duplicate.
+            self.tx.push_back(ACK);
+        } else {
+            self.cancel("blocks out of sequence");
+        }
+        self.packet = packet;
+        self.packet.clear();
+    }
+
+    fn receive_eot(&mut self) {
+        if self.batch && !self.eot_seen {
+            self.eot_seen = true;
+            self.tx.push_back(NAK);
+            return;
+        }
+        self.tx.push_back(ACK);
+        self.save();
+        self.eot_seen = false;
+        self.block = 1;
+        self.size = None;
+        if self.batch {
+            // Ask for the next file
+            self.tx.push_back(CRC);
+            self.state = ReceiveState::Start;
+        } else {
+            self.state = ReceiveState::Done;
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.state == ReceiveState::Done {
+            return;
+        }
+        self.idle = 0;
+        if self.packet.is_empty() {
+            match byte {
+                SOH | STX => self.packet.push(byte),
+                // With YMODEM, the EOT can't come before the header
+                EOT if !self.batch || self.state != ReceiveState::Start => self.receive_eot(),
+                CAN => {
+                    eprintln!("transfer cancelled by the guest");
+                    self.state = ReceiveState::Done;
+                }
+                // Line noise
+                _ => {}
+            }
+            return;
+        }
+        self.packet.push(byte);
+        if self.packet.len() == Self::packet_len(self.packet[0]) {
+            self.receive_packet();
+        }
+    }
+}
+
+impl Read for Receiver {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.tx.is_empty() && self.state != ReceiveState::Done {
+            self.idle += 1;
+            if self.idle >= TIMEOUT {
+                self.idle = 0;
+                // Nothing has started yet, or the packet was cut short
+                if self.state == ReceiveState::Start || !self.packet.is_empty() {
+                    self.retry();
+                }
+            }
+        }
+        self.tx.read(buf)
+    }
+}
+
+impl Write for Receiver {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.receive(*byte);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialHost for Receiver {}
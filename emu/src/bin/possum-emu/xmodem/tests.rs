@@ -0,0 +1,204 @@
+use super::*;
+
+/// A scratch directory of its own for each test
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("possum-xmodem-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Bytes that don't line up with the block sizes
+fn contents(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7) as u8).collect()
+}
+
+fn take(host: &mut impl Read) -> Vec<u8> {
+    let mut buf = [0; 2048];
+    let read = host.read(&mut buf).unwrap();
+    buf[..read].to_vec()
+}
+
+/// Passes bytes back and forth until both ends are done. `corrupt` can change the
+/// bytes on their way from the sender.
+fn transfer(sender: &mut Sender, receiver: &mut Receiver, mut corrupt: impl FnMut(&mut [u8])) {
+    for _ in 0..100_000 {
+        if sender.state == SendState::Done && receiver.state == ReceiveState::Done {
+            return;
+        }
+        let mut data = take(sender);
+        corrupt(&mut data);
+        receiver.write_all(&data).unwrap();
+        let data = take(receiver);
+        sender.write_all(&data).unwrap();
+    }
+    panic!("the transfer never finished");
+}
+
+#[test]
+fn checksums() {
+    assert_eq!(crc16(b"123456789"), 0x31C3);
+    assert_eq!(checksum(&[0xFF, 0x02, 0x10]), 0x11);
+
+    let packet = packet(3, b"hi", SUB, false);
+    assert_eq!(packet.len(), 128 + 4);
+    assert_eq!(&packet[..5], &[SOH, 3, !3, b'h', b'i']);
+    assert_eq!(packet[5], SUB);
+    assert_eq!(packet[131], checksum(&packet[3..131]));
+}
+
+#[test]
+fn xmodem() {
+    let dir = scratch("xmodem");
+    let data = contents(3000);
+    fs::write(dir.join("in"), &data).unwrap();
+
+    let mut sender = Sender::new(&[dir.join("in")], false).unwrap();
+    let mut receiver = Receiver::new(dir.join("out"), false);
+    transfer(&mut sender, &mut receiver, |_| {});
+
+    // Without a size in a header, only the padding is trimmed
+    assert_eq!(fs::read(dir.join("out")).unwrap(), data);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn checksum_mode() {
+    let dir = scratch("checksum");
+    fs::write(dir.join("in"), contents(200)).unwrap();
+    let mut sender = Sender::new(&[dir.join("in")], false).unwrap();
+
+    // A NAK to start asks for 128 byte blocks with checksums
+    sender.write_all(&[NAK]).unwrap();
+    let first = take(&mut sender);
+    assert_eq!(first.len(), 128 + 4);
+    assert_eq!(&first[..3], &[SOH, 1, !1]);
+    assert_eq!(first[131], checksum(&first[3..131]));
+
+    sender.write_all(&[ACK]).unwrap();
+    let second = take(&mut sender);
+    assert_eq!(&second[..3], &[SOH, 2, !2]);
+    assert_eq!(second[3 + 72], SUB);
+
+    sender.write_all(&[ACK]).unwrap();
+    assert_eq!(take(&mut sender), [EOT]);
+    sender.write_all(&[ACK]).unwrap();
+    assert_eq!(sender.state, SendState::Done);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn nak_retry() {
+    let dir = scratch("retry");
+    let data = contents(2000);
+    fs::write(dir.join("in"), &data).unwrap();
+
+    let mut sender = Sender::new(&[dir.join("in")], false).unwrap();
+    let mut receiver = Receiver::new(dir.join("out"), false);
+
+    // The first copy of the first two packets is damaged
+    let mut damaged = 0;
+    transfer(&mut sender, &mut receiver, |data| {
+        if damaged < 2 && data.len() > 100 {
+            data[100] ^= 0xFF;
+            damaged += 1;
+        }
+    });
+    assert_eq!(damaged, 2);
+    assert_eq!(fs::read(dir.join("out")).unwrap(), data);
+
+    // The sender repeats the last packet for every NAK
+    let mut sender = Sender::new(&[dir.join("in")], false).unwrap();
+    sender.write_all(&[CRC]).unwrap();
+    let packet = take(&mut sender);
+    sender.write_all(&[NAK]).unwrap();
+    assert_eq!(take(&mut sender), packet);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn too_many_errors() {
+    let dir = scratch("errors");
+    fs::write(dir.join("in"), contents(2000)).unwrap();
+
+    let mut sender = Sender::new(&[dir.join("in")], false).unwrap();
+    let mut receiver = Receiver::new(dir.join("out"), false);
+    transfer(&mut sender, &mut receiver, |data| {
+        if data.len() > 100 {
+            data[100] ^= 0xFF;
+        }
+    });
+    // The receiver's CAN stops the sender too
+    assert_eq!(receiver.state, ReceiveState::Done);
+    assert_eq!(sender.state, SendState::Done);
+    assert!(!dir.join("out").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn cancel() {
+    let dir = scratch("cancel");
+    fs::write(dir.join("in"), contents(10)).unwrap();
+
+    let mut sender = Sender::new(&[dir.join("in")], false).unwrap();
+    sender.write_all(&[CRC]).unwrap();
+    take(&mut sender);
+    sender.write_all(&[CAN]).unwrap();
+    assert_eq!(sender.state, SendState::Done);
+
+    let mut receiver = Receiver::new(dir.join("out"), false);
+    assert_eq!(take(&mut receiver), [CRC]);
+    receiver.write_all(&[CAN]).unwrap();
+    assert_eq!(receiver.state, ReceiveState::Done);
+    // Nothing more is asked for once the transfer is over
+    for _ in 0..TIMEOUT {
+        assert!(take(&mut receiver).is_empty());
+    }
+    assert!(!dir.join("out").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn ymodem_header() {
+    let dir = scratch("header");
+    fs::write(dir.join("a.txt"), contents(300)).unwrap();
+    let mut sender = Sender::new(&[dir.join("a.txt")], true).unwrap();
+
+    sender.write_all(&[CRC]).unwrap();
+    let header = take(&mut sender);
+    assert_eq!(header.len(), 128 + 5);
+    assert_eq!(&header[..3], &[SOH, 0, 0xFF]);
+    assert_eq!(&header[3..14], b"a.txt\x00300\x00\x00");
+    let crc = crc16(&header[3..131]).to_be_bytes();
+    assert_eq!(&header[131..], &crc);
+
+    // The data is only sent once the receiver asks for it again
+    sender.write_all(&[ACK]).unwrap();
+    assert!(take(&mut sender).is_empty());
+    sender.write_all(&[CRC]).unwrap();
+    assert_eq!(&take(&mut sender)[..3], &[STX, 1, !1]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn ymodem_batch() {
+    let dir = scratch("batch");
+    let files = [("one", contents(130)), ("two.bin", contents(2049))];
+    let mut paths = Vec::new();
+    for (name, data) in &files {
+        paths.push(dir.join(name));
+        fs::write(dir.join(name), data).unwrap();
+    }
+    let out = dir.join("out");
+    fs::create_dir(&out).unwrap();
+
+    let mut sender = Sender::new(&paths, true).unwrap();
+    let mut receiver = Receiver::new(out.clone(), true);
+    transfer(&mut sender, &mut receiver, |_| {});
+
+    // The sizes from the headers trim the padding exactly
+    for (name, data) in &files {
+        assert_eq!(&fs::read(out.join(name)).unwrap(), data);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -12,6 +12,20 @@ pub trait Bus {
 
 pub trait InterruptBus: Bus {
     fn interrupted(&mut self) -> bool;
+
+    /// NMI is edge triggered, so this only returns true once for each falling edge
+    fn nmi(&mut self) -> bool {
+        false
+    }
+
+    /// The interrupt acknowledge cycle. Returns the byte the interrupting device
+    /// puts on the data bus, which is all 1s when nothing drives it.
+    fn acknowledge(&mut self) -> u8 {
+        0xFF
+    }
+
+    /// The CPU executed a RETI
+    fn reti(&mut self) {}
 }
 
 pub trait DeviceBus: Bus {}
@@ -24,6 +38,32 @@ pub trait Device {
     fn write(&mut self, port: u16, data: u8);
 
     fn interrupting(&self) -> bool;
+
+    /// The DMA request line (like DRQ) for a port. Devices with a line for each direction
+    /// pick one by whether the DMA is writing to them.
+    fn ready(&self, _port: u16, _output: bool) -> bool {
+        false
+    }
+
+    /// The CPU acknowledged an interrupt from this device. Devices in the mode 2 daisy
+    /// chain return their vector and hold the interrupt under service until a RETI.
+    fn acknowledge(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// An acknowledged interrupt is still being serviced. Lower priority devices in the
+    /// daisy chain can't interrupt until it ends.
+    fn in_service(&self) -> bool {
+        false
+    }
+
+    /// The CPU executed a RETI, ending the service of this device's interrupt
+    fn reti(&mut self) {}
+
+    /// The interrupt controller was polled for this device's request. Devices that hold
+    /// their request until the acknowledge cycle drop it here, but nothing goes under
+    /// service since polled interrupts end with an EOI rather than a RETI.
+    fn polled(&mut self) {}
 }
 
 pub struct NullBus;
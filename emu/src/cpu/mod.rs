@@ -1520,8 +1520,8 @@ impl Cpu {
     fn reti_wz(&mut self, bus: &mut impl InterruptBus) -> usize {
         let cycles = self.return_wz(bus);
         self.iff1 = self.iff2;
-        // Here is usually where you'd raise the reti pin to other devices
-        // for mode 0/2 interrupts. But mode1 is king.
+        // Devices in the daisy chain watch for this to end their interrupt service
+        bus.reti();
         cycles
     }
 
@@ -2101,12 +2101,28 @@ impl Cpu {
         let opcode = self.fetch(bus);
         let mut cycles = self.do_opcode(opcode, bus);
 
-        // TODO: NMI
-        cycles += self.do_irq(bus);
+        // NMI is taken ahead of any maskable interrupt
+        if bus.nmi() {
+            cycles += self.do_nmi(bus);
+        } else {
+            cycles += self.do_irq(bus);
+        }
 
         cycles
     }
 
+    fn do_nmi(&mut self, bus: &mut impl InterruptBus) -> usize {
+        if self.halted {
+            self.halted = false;
+        }
+        // IFF2 keeps the old state of IFF1 for RETN to restore
+        self.iff1 = false;
+        self.push_base(self.pc, bus);
+        self.pc = 0x0066;
+        self.wz = self.pc;
+        11
+    }
+
     fn do_irq(&mut self, bus: &mut impl InterruptBus) -> usize {
         // Do nothing if there aren't any pending interrupts
         if !bus.interrupted() {
@@ -2131,7 +2147,18 @@ impl Cpu {
                     return 13;
                 }
 
-                InterruptMode::Two => unimplemented!("Interrupt mode two is not implemented"),
+                // Only the daisy chain answers the acknowledge cycle, and only mode 2 uses
+                // it. In other modes, the interrupt controller is polled instead.
+                InterruptMode::Two => {
+                    let data = bus.acknowledge();
+                    self.push_base(self.pc, bus);
+                    let addr = (self.ir & 0xFF00) | (data as u16);
+                    let lo = bus.read(addr);
+                    let hi = bus.read(addr.wrapping_add(1));
+                    self.pc = u16::from_le_bytes([lo, hi]);
+                    self.wz = self.pc;
+                    return 19;
+                }
             }
         }
 
@@ -1307,3 +1307,185 @@ fn register_loads() {
     assert_eq!(8, cpu.step(&mut bus));
     assert_eq!(0x42, cpu.register(Register::E));
 }
+
+/// Interrupts once with a vector, and counts the RETIs it sees
+struct VectorBus {
+    bus: TestBus,
+    vector: Option<u8>,
+    retis: usize,
+}
+
+impl Bus for VectorBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.bus.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.bus.write(addr, data)
+    }
+
+    fn input(&mut self, port: u16) -> u8 {
+        self.bus.input(port)
+    }
+
+    fn output(&mut self, port: u16, data: u8) {
+        self.bus.output(port, data)
+    }
+}
+
+impl InterruptBus for VectorBus {
+    fn interrupted(&mut self) -> bool {
+        self.vector.is_some()
+    }
+
+    fn acknowledge(&mut self) -> u8 {
+        self.vector.take().unwrap_or(0xFF)
+    }
+
+    fn reti(&mut self) {
+        self.retis += 1;
+    }
+}
+
+#[test]
+fn interrupt_mode_one() {
+    #[rustfmt::skip]
+    let mut bus = VectorBus {
+        bus: TestBus::with_mem(vec![
+            0xED, 0x56,                                 // im 1
+            0xFB,                                       // ei
+            0x00,                                       // nop
+        ]),
+        vector: Some(0x10),
+        retis: 0,
+    };
+    let mut cpu = Cpu {
+        sp: 0xF000,
+        ..Default::default()
+    };
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(4 + 13, cpu.step(&mut bus));
+    assert_eq!(0x0038, cpu.pc);
+    // Devices aren't acknowledged outside of mode 2
+    assert_eq!(Some(0x10), bus.vector);
+}
+
+#[test]
+fn interrupt_mode_two() {
+    #[rustfmt::skip]
+    let mut bus = VectorBus {
+        bus: TestBus::with_mem(vec![
+            0xED, 0x5E,                                 // im 2
+            0x3E, 0x80,                                 // ld a, $80
+            0xED, 0x47,                                 // ld i, a
+            0xFB,                                       // ei
+            0x00,                                       // nop
+            0x00,                                       // nop
+        ]),
+        vector: Some(0x10),
+        retis: 0,
+    };
+    // The vector table entry points at a reti
+    bus.bus.mem_mut()[0x8010] = 0x00;
+    bus.bus.mem_mut()[0x8011] = 0x02;
+    bus.bus.mem_mut()[0x0200] = 0xED;
+    bus.bus.mem_mut()[0x0201] = 0x4D;
+
+    let mut cpu = Cpu {
+        sp: 0xF000,
+        ..Default::default()
+    };
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    // Interrupts are only enabled after the next instruction
+    cpu.step(&mut bus);
+    assert_eq!(0x0007, cpu.pc);
+    assert_eq!(Some(0x10), bus.vector);
+
+    assert_eq!(4 + 19, cpu.step(&mut bus));
+    assert_eq!(0x0200, cpu.pc);
+    assert_eq!(None, bus.vector);
+    assert_eq!(
+        0x0008,
+        u16::from_le_bytes([bus.bus.mem()[0xEFFE], bus.bus.mem()[0xEFFF]])
+    );
+
+    cpu.step(&mut bus);
+    assert_eq!(0x0008, cpu.pc);
+    assert_eq!(1, bus.retis);
+}
+
+/// Raises NMI once
+struct NmiBus {
+    bus: TestBus,
+    nmi: bool,
+}
+
+impl Bus for NmiBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.bus.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.bus.write(addr, data)
+    }
+
+    fn input(&mut self, port: u16) -> u8 {
+        self.bus.input(port)
+    }
+
+    fn output(&mut self, port: u16, data: u8) {
+        self.bus.output(port, data)
+    }
+}
+
+impl InterruptBus for NmiBus {
+    fn interrupted(&mut self) -> bool {
+        false
+    }
+
+    fn nmi(&mut self) -> bool {
+        mem::take(&mut self.nmi)
+    }
+}
+
+#[test]
+fn nmi() {
+    #[rustfmt::skip]
+    let mut bus = NmiBus {
+        bus: TestBus::with_mem(vec![
+            0xFB,                                       // ei
+            0x00,                                       // nop
+            0x00,                                       // nop
+        ]),
+        nmi: false,
+    };
+    // The handler just returns
+    bus.bus.mem_mut()[0x0066] = 0xED;
+    bus.bus.mem_mut()[0x0067] = 0x45;
+
+    let mut cpu = Cpu {
+        sp: 0xF000,
+        ..Default::default()
+    };
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert!(cpu.iff1);
+
+    bus.nmi = true;
+    assert_eq!(4 + 11, cpu.step(&mut bus));
+    assert_eq!(0x0066, cpu.pc);
+    assert!(!cpu.iff1);
+    assert!(cpu.iff2);
+    assert_eq!(
+        0x0003,
+        u16::from_le_bytes([bus.bus.mem()[0xEFFE], bus.bus.mem()[0xEFFF]])
+    );
+
+    // RETN restores the interrupts NMI masked
+    cpu.step(&mut bus);
+    assert_eq!(0x0003, cpu.pc);
+    assert!(cpu.iff1);
+}
@@ -0,0 +1,224 @@
+//! Z80 CTC Emulation
+//!
+//! The CTC is clocked by the CPU clock, so it is advanced by the cycles of each
+//! instruction rather than ticked. On the Possum the ZC/TO output of each channel is
+//! wired to the CLK/TRG input of the next, so channels can be cascaded into longer
+//! timers. CLK/TRG0 isn't connected.
+//!
+//! The four channels are at consecutive ports. Interrupts are vectored for mode 2, with
+//! channel 0 having the highest priority.
+
+#[cfg(test)]
+mod tests;
+
+use crate::{Device, DeviceBus};
+
+struct ChannelControl;
+impl ChannelControl {
+    const INTERRUPT: u8 = 0x80;
+
+    /// Count CLK/TRG edges instead of the system clock
+    const COUNTER: u8 = 0x40;
+
+    /// Timer mode prescaler of 256 instead of 16
+    const PRESCALER_256: u8 = 0x20;
+
+    /// A timer waits for CLK/TRG before starting. The active edge (bit 4) doesn't
+    /// matter on the Possum since ZC/TO pulses have both.
+    const TRIGGER: u8 = 0x08;
+
+    /// The next write is the time constant
+    const TIME_CONSTANT: u8 = 0x04;
+
+    const RESET: u8 = 0x02;
+
+    /// Set for control words. Clear for the interrupt vector.
+    const CONTROL: u8 = 0x01;
+}
+
+#[derive(Default)]
+struct Channel {
+    control: u8,
+    /// 0 counts 256
+    time_constant: u8,
+    counter: u8,
+    prescaler: usize,
+    awaiting_time_constant: bool,
+    running: bool,
+    /// A timer that will start on the next trigger
+    waiting_for_trigger: bool,
+}
+
+impl Channel {
+    #[inline]
+    fn counter_mode(&self) -> bool {
+        (self.control & ChannelControl::COUNTER) != 0
+    }
+
+    /// Returns true when the counter reaches zero and reloads
+    fn count(&mut self) -> bool {
+        self.counter = self.counter.wrapping_sub(1);
+        if self.counter == 0 {
+            self.counter = self.time_constant;
+            return true;
+        }
+        false
+    }
+
+    /// One cycle of the system clock
+    fn clock(&mut self) -> bool {
+        if !self.running || self.counter_mode() {
+            return false;
+        }
+        let prescaler = if (self.control & ChannelControl::PRESCALER_256) != 0 {
+            256
+        } else {
+            16
+        };
+        self.prescaler += 1;
+        if self.prescaler < prescaler {
+            return false;
+        }
+        self.prescaler = 0;
+        self.count()
+    }
+
+    /// An edge on CLK/TRG
+    fn trigger(&mut self) -> bool {
+        if self.waiting_for_trigger {
+            self.waiting_for_trigger = false;
+            self.running = true;
+            return false;
+        }
+        self.running && self.counter_mode() && self.count()
+    }
+
+    fn write_control(&mut self, data: u8) {
+        if (data & ChannelControl::RESET) != 0 {
+            self.running = false;
+            self.waiting_for_trigger = false;
+        }
+        self.control = data;
+        self.awaiting_time_constant = (data & ChannelControl::TIME_CONSTANT) != 0;
+    }
+
+    fn write_time_constant(&mut self, data: u8) {
+        self.time_constant = data;
+        self.awaiting_time_constant = false;
+        // A running channel picks up the new constant when it next reloads
+        if self.running || self.waiting_for_trigger {
+            return;
+        }
+        self.counter = data;
+        self.prescaler = 0;
+        if !self.counter_mode() && (self.control & ChannelControl::TRIGGER) != 0 {
+            self.waiting_for_trigger = true;
+        } else {
+            self.running = true;
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Ctc {
+    channels: [Channel; 4],
+    vector: u8,
+    /// One bit per channel
+    pending: u8,
+    /// One bit per channel
+    in_service: u8,
+}
+
+impl Ctc {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the channels for some cycles of the system clock
+    pub fn advance(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            for index in 0..self.channels.len() {
+                if self.channels[index].clock() {
+                    self.zero_count(index);
+                }
+            }
+        }
+    }
+
+    /// Pulses ZC/TO, which also triggers the next channel
+    fn zero_count(&mut self, index: usize) {
+        if (self.channels[index].control & ChannelControl::INTERRUPT) != 0 {
+            self.pending |= 1 << index;
+        }
+        // There is no ZC/TO3
+        if index < 3 && self.channels[index + 1].trigger() {
+            self.zero_count(index + 1);
+        }
+    }
+
+    /// The pending channel with the highest priority
+    #[inline]
+    fn highest_pending(&self) -> Option<usize> {
+        match self.pending {
+            0 => None,
+            pending => Some(pending.trailing_zeros() as usize),
+        }
+    }
+}
+
+impl Device for Ctc {
+    fn tick(&mut self, _: &mut dyn DeviceBus) {
+        self.advance(1);
+    }
+
+    fn read(&mut self, port: u16) -> u8 {
+        self.channels[(port & 0x03) as usize].counter
+    }
+
+    fn write(&mut self, port: u16, data: u8) {
+        let index = (port & 0x03) as usize;
+        let channel = &mut self.channels[index];
+        if channel.awaiting_time_constant {
+            channel.write_time_constant(data);
+        } else if (data & ChannelControl::CONTROL) != 0 {
+            channel.write_control(data);
+            if (data & ChannelControl::INTERRUPT) == 0 {
+                self.pending &= !(1 << index);
+            }
+        } else if index == 0 {
+            // The channel number fills in the low bits
+            self.vector = data & 0xF8;
+        }
+    }
+
+    /// Interrupts under service hold off their own channel and everything below
+    fn interrupting(&self) -> bool {
+        match self.highest_pending() {
+            Some(index) => (self.in_service & ((2 << index) - 1)) == 0,
+            None => false,
+        }
+    }
+
+    fn acknowledge(&mut self) -> Option<u8> {
+        let index = self.highest_pending()?;
+        self.pending &= !(1 << index);
+        self.in_service |= 1 << index;
+        Some(self.vector | ((index as u8) << 1))
+    }
+
+    fn in_service(&self) -> bool {
+        self.in_service != 0
+    }
+
+    fn polled(&mut self) {
+        if let Some(index) = self.highest_pending() {
+            self.pending &= !(1 << index);
+        }
+    }
+
+    /// Ends the service of the highest priority channel
+    fn reti(&mut self) {
+        self.in_service &= self.in_service.wrapping_sub(1);
+    }
+}
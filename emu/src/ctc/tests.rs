@@ -0,0 +1,165 @@
+use super::*;
+
+const TIMER: u8 = ChannelControl::TIME_CONSTANT | ChannelControl::CONTROL;
+
+const COUNTER: u8 =
+    ChannelControl::COUNTER | ChannelControl::TIME_CONSTANT | ChannelControl::CONTROL;
+
+#[test]
+fn timer() {
+    let mut ctc = Ctc::new();
+    ctc.write(0, TIMER);
+    ctc.write(0, 10);
+    assert_eq!(ctc.read(0), 10);
+
+    ctc.advance(15);
+    assert_eq!(ctc.read(0), 10);
+    ctc.advance(1);
+    assert_eq!(ctc.read(0), 9);
+
+    // Reloads after reaching zero
+    ctc.advance(16 * 9);
+    assert_eq!(ctc.read(0), 10);
+}
+
+#[test]
+fn prescaler() {
+    let mut ctc = Ctc::new();
+    ctc.write(1, ChannelControl::PRESCALER_256 | TIMER);
+    ctc.write(1, 2);
+    ctc.advance(255);
+    assert_eq!(ctc.read(1), 2);
+    ctc.advance(1);
+    assert_eq!(ctc.read(1), 1);
+}
+
+#[test]
+fn time_constant_256() {
+    let mut ctc = Ctc::new();
+    ctc.write(0, ChannelControl::INTERRUPT | TIMER);
+    ctc.write(0, 0);
+    ctc.advance((16 * 256) - 1);
+    assert!(!ctc.interrupting());
+    ctc.advance(1);
+    assert!(ctc.interrupting());
+    assert_eq!(ctc.read(0), 0);
+}
+
+#[test]
+fn new_time_constant() {
+    let mut ctc = Ctc::new();
+    ctc.write(0, TIMER);
+    ctc.write(0, 2);
+
+    // The running count isn't disturbed
+    ctc.write(0, TIMER);
+    ctc.write(0, 5);
+    assert_eq!(ctc.read(0), 2);
+    ctc.advance(16 * 2);
+    assert_eq!(ctc.read(0), 5);
+}
+
+#[test]
+fn reset() {
+    let mut ctc = Ctc::new();
+    ctc.write(0, TIMER);
+    ctc.write(0, 10);
+    ctc.write(0, ChannelControl::RESET | ChannelControl::CONTROL);
+    ctc.advance(16 * 4);
+    assert_eq!(ctc.read(0), 10);
+
+    // Loading a new constant starts it again
+    ctc.write(0, TIMER);
+    ctc.write(0, 3);
+    ctc.advance(16);
+    assert_eq!(ctc.read(0), 2);
+}
+
+#[test]
+fn cascade() {
+    let mut ctc = Ctc::new();
+    ctc.write(0, TIMER);
+    ctc.write(0, 4);
+    ctc.write(1, COUNTER);
+    ctc.write(1, 3);
+    // Counters don't count the system clock
+    ctc.advance(16 * 3);
+    assert_eq!(ctc.read(1), 3);
+
+    ctc.advance(16);
+    assert_eq!(ctc.read(1), 2);
+    ctc.advance(16 * 4 * 2);
+    assert_eq!(ctc.read(1), 3);
+}
+
+#[test]
+fn timer_trigger() {
+    let mut ctc = Ctc::new();
+    ctc.write(0, TIMER);
+    ctc.write(0, 2);
+    ctc.write(1, ChannelControl::TRIGGER | TIMER);
+    ctc.write(1, 100);
+
+    ctc.advance(16 * 2);
+    assert_eq!(ctc.read(1), 100);
+    ctc.advance(16);
+    assert_eq!(ctc.read(1), 99);
+}
+
+#[test]
+fn interrupts() {
+    let mut ctc = Ctc::new();
+    ctc.write(0, 0x40);
+    ctc.write(2, ChannelControl::INTERRUPT | TIMER);
+    ctc.write(2, 1);
+    ctc.write(3, ChannelControl::INTERRUPT | COUNTER);
+    ctc.write(3, 1);
+
+    // Channel 2 triggers channel 3 as well
+    ctc.advance(16);
+    assert!(ctc.interrupting());
+    assert_eq!(ctc.acknowledge(), Some(0x44));
+    assert!(ctc.in_service());
+    assert!(!ctc.interrupting());
+    ctc.reti();
+    assert!(ctc.interrupting());
+    assert_eq!(ctc.acknowledge(), Some(0x46));
+    ctc.reti();
+    assert!(!ctc.interrupting());
+    assert!(!ctc.in_service());
+
+    // Disabling the interrupt drops a pending one
+    ctc.advance(16);
+    ctc.write(3, COUNTER);
+    ctc.write(3, 1);
+    assert_eq!(ctc.acknowledge(), Some(0x44));
+    ctc.reti();
+    assert!(!ctc.interrupting());
+}
+
+#[test]
+fn interrupt_priority() {
+    let mut ctc = Ctc::new();
+    ctc.write(0, 0x80);
+    ctc.write(1, ChannelControl::INTERRUPT | TIMER);
+    ctc.write(1, 1);
+    ctc.advance(16);
+    assert_eq!(ctc.acknowledge(), Some(0x82));
+
+    // A higher priority channel can interrupt a lower one being serviced
+    ctc.write(0, ChannelControl::INTERRUPT | TIMER);
+    ctc.write(0, 1);
+    ctc.advance(16);
+    assert!(ctc.interrupting());
+    assert_eq!(ctc.acknowledge(), Some(0x80));
+
+    ctc.write(0, ChannelControl::RESET | ChannelControl::CONTROL);
+
+    // But not the other way around
+    ctc.advance(16);
+    assert!(!ctc.interrupting());
+    ctc.reti();
+    assert!(!ctc.interrupting());
+    ctc.reti();
+    assert!(ctc.interrupting());
+}
@@ -1,4 +1,18 @@
 //! z8410 DMA emulation
+//!
+//! The DMA is a bus master. While it is enabled and RDY is active it holds BUSREQ, and
+//! the system stops the CPU to hand it the bus. In byte mode the bus goes back to the
+//! CPU after every byte. In burst mode the DMA keeps it until RDY goes inactive, and in
+//! continuous mode until the transfer stops (waiting on RDY with the bus held).
+//!
+//! Each byte takes a read cycle and a write cycle, as long as the timing bytes of the
+//! ports say (or standard Z80 timing without them). Getting the bus costs another cycle.
+//!
+//! On the Possum, RDY is driven high by the request line of whichever I/O device the
+//! DMA is transferring to or from. With only memory involved it stays low.
+//!
+//! Besides copying, the DMA can search for a byte (ignoring the bits in a mask), either
+//! on its own or while copying.
 
 #[cfg(test)]
 mod tests;
@@ -19,8 +33,15 @@ impl WR0Mask {
     const SELECT_MASK: u8 = 0b1000_0000;
     const SELECT_BITS: u8 = 0b0000_0000;
 
-    const SEARCH_OR_TRANSFER: u8 = 0x03;
+    const TRANSFER: u8 = 0x01;
+    const SEARCH: u8 = 0x02;
     const DIRECTION: u8 = 0x04;
+
+    const PORT_A_ADDRESS_LOW: u8 = 0x08;
+    const PORT_A_ADDRESS_HIGH: u8 = 0x10;
+    const BLOCK_LENGTH_LOW: u8 = 0x20;
+    const BLOCK_LENGTH_HIGH: u8 = 0x40;
+    const FOLLOWS: u8 = 0x78;
 }
 
 struct WR1Mask;
@@ -31,6 +52,9 @@ impl WR1Mask {
     const MEMORY_OR_IO: u8 = 0x08;
     const INCREMENT_DECREMENT_MODE: u8 = 0x30;
 
+    const TIMING: u8 = 0x40;
+    const FOLLOWS: u8 = 0x40;
+
     const CYCLE_LENGTH: u8 = 0x03;
 }
 
@@ -42,6 +66,9 @@ impl WR2Mask {
     const MEMORY_OR_IO: u8 = 0x08;
     const INCREMENT_DECREMENT_MODE: u8 = 0x30;
 
+    const TIMING: u8 = 0x40;
+    const FOLLOWS: u8 = 0x40;
+
     const CYCLE_LENGTH: u8 = 0x03;
 }
 
@@ -51,6 +78,9 @@ impl WR3Mask {
     const SELECT_BITS: u8 = 0b1000_0000;
 
     const STOP_ON_MATCH: u8 = 0x04;
+    const MASK_BYTE: u8 = 0x08;
+    const MATCH_BYTE: u8 = 0x10;
+    const FOLLOWS: u8 = 0x18;
     const INTERRUPT_ENABLE: u8 = 0x20;
     const DMA_ENABLE: u8 = 0x40;
 }
@@ -60,14 +90,27 @@ impl WR4Mask {
     const SELECT_MASK: u8 = 0b1000_0011;
     const SELECT_BITS: u8 = 0b1000_0001;
 
-    const ACCESS_MODE: u8 = 0x06;
+    const PORT_B_ADDRESS_LOW: u8 = 0x04;
+    const PORT_B_ADDRESS_HIGH: u8 = 0x08;
+    const INTERRUPT_CONTROL: u8 = 0x10;
+    const FOLLOWS: u8 = 0x1C;
+
+    const ACCESS_MODE: u8 = 0x60;
+}
 
+/// Follows WR4
+struct InterruptControlMask;
+impl InterruptControlMask {
     const INTERRUPT_ON_MATCH: u8 = 0x01;
     const INTERRUPT_AT_END_OF_BLOCK: u8 = 0x02;
     const PULSE_GENERATED: u8 = 0x04;
 
-    const INTERRUPT_ON_READY: u8 = 0x40;
+    const PULSE_CONTROL: u8 = 0x08;
+    const INTERRUPT_VECTOR: u8 = 0x10;
+    const FOLLOWS: u8 = 0x18;
+
     const STATUS_AFFECTS_VECTOR: u8 = 0x20;
+    const INTERRUPT_ON_READY: u8 = 0x40;
 }
 
 struct WR5Mask;
@@ -75,7 +118,7 @@ impl WR5Mask {
     const SELECT_MASK: u8 = 0b1100_0111;
     const SELECT_BITS: u8 = 0b1000_0010;
 
-    const CHIP_ENABLE_ONLY_WAIT: u8 = 0x10;
+    const READY_ACTIVE_HIGH: u8 = 0x08;
     const STOP_RESTART_ON_END_OF_BLOCK: u8 = 0x20;
 }
 
@@ -84,6 +127,7 @@ impl WR6Mask {
     const SELECT_MASK: u8 = 0b1000_0011;
     const SELECT_BITS: u8 = 0b1000_0011;
 
+    /// Read mask bits, one per read register
     const STATUS: u8 = 0x01;
     const BYTE_COUNTER_LOW: u8 = 0x02;
     const BYTE_COUNTER_HIGH: u8 = 0x04;
@@ -91,17 +135,10 @@ impl WR6Mask {
     const PORT_A_ADDRESS_HIGH: u8 = 0x10;
     const PORT_B_ADDRESS_LOW: u8 = 0x20;
     const PORT_B_ADDRESS_HIGH: u8 = 0x40;
-}
+    const READ_MASK: u8 = 0x7F;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-enum ReadRegister {
-    Status,
-    ByteCounterLow,
-    ByteCounterHigh,
-    PortAAddressLow,
-    PortAAddressHigh,
-    PortBAddressLow,
-    PortBAddressHigh,
+    /// The read mask is the only byte that follows a command
+    const READ_MASK_FOLLOWS: u8 = 0x01;
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -127,6 +164,53 @@ enum WriteRegister {
     ReadMask,
 }
 
+/// The byte whose follow bits are being worked through
+#[derive(Copy, Clone, Debug, Default)]
+enum BaseRegister {
+    #[default]
+    WR0,
+    WR1,
+    WR2,
+    WR3,
+    WR4,
+    InterruptControl,
+    WR6,
+}
+
+impl BaseRegister {
+    /// The register written for one of the follow bits
+    fn follow(self, bit: u8) -> WriteRegister {
+        match (self, bit) {
+            (Self::WR0, WR0Mask::PORT_A_ADDRESS_LOW) => WriteRegister::PortAAddressLow,
+            (Self::WR0, WR0Mask::PORT_A_ADDRESS_HIGH) => WriteRegister::PortAAddressHigh,
+            (Self::WR0, WR0Mask::BLOCK_LENGTH_LOW) => WriteRegister::BlockLengthLow,
+            (Self::WR0, WR0Mask::BLOCK_LENGTH_HIGH) => WriteRegister::BlockLengthHigh,
+
+            (Self::WR1, WR1Mask::TIMING) => WriteRegister::PortATiming,
+
+            (Self::WR2, WR2Mask::TIMING) => WriteRegister::PortBTiming,
+
+            (Self::WR3, WR3Mask::MASK_BYTE) => WriteRegister::MaskByte,
+            (Self::WR3, WR3Mask::MATCH_BYTE) => WriteRegister::MatchByte,
+
+            (Self::WR4, WR4Mask::PORT_B_ADDRESS_LOW) => WriteRegister::PortBAddressLow,
+            (Self::WR4, WR4Mask::PORT_B_ADDRESS_HIGH) => WriteRegister::PortBAddressHigh,
+            (Self::WR4, WR4Mask::INTERRUPT_CONTROL) => WriteRegister::InterruptControl,
+
+            (Self::InterruptControl, InterruptControlMask::PULSE_CONTROL) => {
+                WriteRegister::PulseControl
+            }
+            (Self::InterruptControl, InterruptControlMask::INTERRUPT_VECTOR) => {
+                WriteRegister::InterruptVector
+            }
+
+            (Self::WR6, WR6Mask::READ_MASK_FOLLOWS) => WriteRegister::ReadMask,
+
+            _ => unreachable!(),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 enum Direction {
     PortBToA,
@@ -152,6 +236,17 @@ impl Default for IncrementMode {
     }
 }
 
+impl IncrementMode {
+    fn from_bits(bits: u8) -> Self {
+        match bits {
+            0 => Self::Decrement,
+            1 => Self::Increment,
+            2 | 3 => Self::Fixed,
+            _ => unreachable!(),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 enum AccessMode {
     Byte,
@@ -171,7 +266,6 @@ pub struct Dma {
     byte_counter: u16,
     port_a_counter: u16,
     port_b_counter: u16,
-    read_order: Vec<ReadRegister>,
 
     enabled: bool,
     interrupts_enabled: bool,
@@ -184,8 +278,18 @@ pub struct Dma {
     interrupt_at_end_of_block: bool,
     restart_at_end_of_block: bool,
     interrupt_on_ready: bool,
+    /// The level of the RDY input
+    rdy: bool,
+    ready_active_high: bool,
+    force_ready: bool,
+    /// The CPU has handed over the bus
+    busack: bool,
     status_affects_vector: bool,
     interrupt_vector: u8,
+    in_service: bool,
+    enable_after_reti: bool,
+    pulse_generated: bool,
+    pulse_control: u8,
 
     read_mask: u8,
 
@@ -202,24 +306,353 @@ pub struct Dma {
     port_b_is_memory: bool,
     port_b_increment_mode: IncrementMode,
 
-    // NOTE: Its fascinating but reads and writes to internal registers
-    // are controlled by a sort of list of registers to write
-    // that are popped off a stack. In reality, this is likely done
-    // using some sort of shifter in the real DMA.
-
-    // TODO: The implementation of the read and write register order tracking is *too* simple :-)
-    //   We do need to emulate the real behavior by using `u8::trailing_zeros` to get the
-    //   index of the lowest set bit in a `u8` and use that to look up the correct register.
-    //   The added benefit there is that the code below that sets up the read/write stack doesnt
-    //   need to be written in reverse order anymore.
-    write_order: Vec<WriteRegister>,
+    /// Empty for standard Z80 timing
+    port_a_timing: Option<u8>,
+    port_b_timing: Option<u8>,
+
+    // NOTE: Its fascinating but reads and writes to internal registers are controlled by
+    // a set of bits for the registers that follow, taken lowest bit first. In reality,
+    // this is likely done using some sort of shifter in the real DMA.
+    write_base: BaseRegister,
+    write_follows: u8,
+    read_follows: u8,
+}
+
+impl Dma {
+    /// Whether the DMA can go ahead with a byte
+    #[inline]
+    fn ready(&self) -> bool {
+        self.force_ready || self.rdy == self.ready_active_high
+    }
+
+    /// Drives the RDY input
+    pub fn set_ready(&mut self, level: bool) {
+        let was_ready = self.ready();
+        self.rdy = level;
+        if !was_ready
+            && self.ready()
+            && self.enabled
+            && self.interrupts_enabled
+            && self.interrupt_on_ready
+        {
+            self.status |= RR0Mask::INTERRUPT_PENDING;
+        }
+    }
+
+    /// The I/O port that RDY is coming from, and whether the DMA is writing to it
+    pub fn ready_port(&self) -> Option<(u16, bool)> {
+        let port_a = (self.port_a_is_memory, self.port_a_counter);
+        let port_b = (self.port_b_is_memory, self.port_b_counter);
+        let (source, destination) = match self.direction {
+            Direction::PortAToB => (port_a, port_b),
+            Direction::PortBToA => (port_b, port_a),
+        };
+        if !source.0 {
+            Some((source.1, false))
+        } else if self.transfer && !destination.0 {
+            Some((destination.1, true))
+        } else {
+            None
+        }
+    }
+
+    /// An interrupt on RDY holds off the transfer until it has been serviced
+    #[inline]
+    fn held_for_interrupt(&self) -> bool {
+        self.interrupt_on_ready
+            && (self.in_service || (self.status & RR0Mask::INTERRUPT_PENDING) != 0)
+    }
+
+    /// BUSREQ. Once it has the bus, the DMA lets go after every byte in byte mode, as soon
+    /// as it isn't ready in burst mode, and only when it stops in continuous mode.
+    pub fn bus_requested(&self) -> bool {
+        let active = self.enabled && self.ready() && !self.held_for_interrupt();
+        if !self.busack {
+            return active;
+        }
+        match self.access_mode {
+            AccessMode::Byte => false,
+            AccessMode::Burst => active,
+            AccessMode::Continuous => self.enabled,
+        }
+    }
+
+    /// The CPU has the bus back
+    #[inline]
+    pub fn release_bus(&mut self) {
+        self.busack = false;
+    }
+
+    /// Runs the DMA for one turn on the bus, returning the clock cycles it took. Getting
+    /// the bus from the CPU costs an extra cycle, and waiting for RDY takes one.
+    pub fn transfer(&mut self, bus: &mut dyn DeviceBus) -> usize {
+        let mut cycles = 0;
+        if !self.busack {
+            self.busack = true;
+            cycles += BUSACK_CYCLES;
+        }
+        if !self.ready() {
+            return cycles + 1;
+        }
+        cycles += self.byte_cycles();
+        self.tick(bus);
+        cycles
+    }
+
+    /// System clock cycles spent on the bus per byte: a read from the source and a write
+    /// to the destination. Ports without a timing byte use standard Z80 timing.
+    pub fn byte_cycles(&self) -> usize {
+        let port_a = port_cycles(
+            self.port_a_is_memory,
+            self.port_a_timing
+                .map(|timing| timing & WR1Mask::CYCLE_LENGTH),
+        );
+        let port_b = port_cycles(
+            self.port_b_is_memory,
+            self.port_b_timing
+                .map(|timing| timing & WR2Mask::CYCLE_LENGTH),
+        );
+        let (source, destination) = match self.direction {
+            Direction::PortAToB => (port_a, port_b),
+            Direction::PortBToA => (port_b, port_a),
+        };
+        if self.transfer {
+            source + destination
+        } else {
+            source
+        }
+    }
+
+    /// Starts taking the follow bytes of a base register
+    #[inline]
+    fn begin_follows(&mut self, base: BaseRegister, follows: u8) {
+        self.write_base = base;
+        self.write_follows = follows;
+    }
+
+    fn write_follow(&mut self, register: WriteRegister, data: u8) {
+        match register {
+            WriteRegister::PortAAddressLow => {
+                self.port_a_start_address = (self.port_a_start_address & 0xFF00) | data as u16;
+            }
+
+            WriteRegister::PortAAddressHigh => {
+                self.port_a_start_address =
+                    (self.port_a_start_address & 0x00FF) | ((data as u16) << 8);
+            }
+
+            WriteRegister::BlockLengthLow => {
+                self.block_length = (self.block_length & 0xFF00) | data as u16;
+            }
+
+            WriteRegister::BlockLengthHigh => {
+                self.block_length = (self.block_length & 0x00FF) | ((data as u16) << 8);
+            }
+
+            WriteRegister::PortATiming => {
+                self.port_a_timing = Some(timing_byte(data));
+            }
+
+            WriteRegister::PortBTiming => {
+                self.port_b_timing = Some(timing_byte(data));
+            }
+
+            WriteRegister::PortBAddressLow => {
+                self.port_b_start_address = (self.port_b_start_address & 0xFF00) | data as u16;
+            }
+
+            WriteRegister::PortBAddressHigh => {
+                self.port_b_start_address =
+                    (self.port_b_start_address & 0x00FF) | ((data as u16) << 8);
+            }
+
+            WriteRegister::InterruptControl => {
+                self.interrupt_on_match = (data & InterruptControlMask::INTERRUPT_ON_MATCH) != 0;
+                self.interrupt_at_end_of_block =
+                    (data & InterruptControlMask::INTERRUPT_AT_END_OF_BLOCK) != 0;
+                self.pulse_generated = (data & InterruptControlMask::PULSE_GENERATED) != 0;
+                self.status_affects_vector =
+                    (data & InterruptControlMask::STATUS_AFFECTS_VECTOR) != 0;
+                self.interrupt_on_ready = (data & InterruptControlMask::INTERRUPT_ON_READY) != 0;
+
+                // The interrupt control byte is the last that can follow WR4, so its own
+                // follow bytes come after it.
+                self.begin_follows(
+                    BaseRegister::InterruptControl,
+                    data & InterruptControlMask::FOLLOWS,
+                );
+            }
+
+            WriteRegister::PulseControl => {
+                self.pulse_control = data;
+            }
+
+            WriteRegister::InterruptVector => {
+                self.interrupt_vector = data;
+            }
+
+            WriteRegister::ReadMask => {
+                self.read_mask = data & WR6Mask::READ_MASK;
+                self.read_follows = self.read_mask;
+            }
+
+            WriteRegister::MaskByte => {
+                self.mask_byte = data;
+            }
+
+            WriteRegister::MatchByte => {
+                self.match_byte = data;
+            }
+        }
+    }
+
+    /// WR6
+    fn command(&mut self, data: u8) {
+        match data {
+            // Reset
+            0xC3 => {
+                // TODO: Do I need to reset other status bits?
+                //   like the found/end and transfer bits?
+                self.enabled = false;
+                self.interrupts_enabled = false;
+                self.in_service = false;
+                self.enable_after_reti = false;
+                self.restart_at_end_of_block = false;
+                self.ready_active_high = false;
+                self.force_ready = false;
+                self.status &= !RR0Mask::INTERRUPT_PENDING;
+                self.port_a_timing = None;
+                self.port_b_timing = None;
+                self.write_follows = 0;
+            }
+
+            // Reset Port A Timing
+            0xC7 => {
+                self.port_a_timing = None;
+            }
+
+            // Reset Port B Timing
+            0xCB => {
+                self.port_b_timing = None;
+            }
+
+            // Load
+            0xCF => {
+                // NOTE: This only loads the source register.
+                // The destination register will get updated during its first
+                // increment!
+                // TODO: Make sure I impl this correctly. Auto-restart is also
+                //   supposed to do this automatically.
+                match self.direction {
+                    Direction::PortAToB => {
+                        self.port_a_counter = self.port_a_start_address;
+                    }
+                    Direction::PortBToA => {
+                        self.port_b_counter = self.port_b_start_address;
+                    }
+                }
+                self.byte_counter = 0;
+                self.status &= !RR0Mask::TRANSFER_OCCURRED;
+            }
+
+            // Continue
+            // The addresses carry on from wherever the DMA stopped (like after a match)
+            0xD3 => {
+                self.byte_counter = 0;
+            }
+
+            // Disable interrupts
+            0xAF => {
+                self.interrupts_enabled = false;
+            }
+
+            // Enable interrupts
+            0xAB => {
+                self.interrupts_enabled = true;
+            }
+
+            // Reset and disable interrupts
+            0xA3 => {
+                self.interrupts_enabled = false;
+                self.in_service = false;
+                self.status &= !RR0Mask::INTERRUPT_PENDING;
+            }
+
+            // Enable after reti
+            0xB7 => {
+                self.enable_after_reti = true;
+            }
+
+            // Read status byte
+            0xBF => {
+                self.read_follows = WR6Mask::STATUS;
+            }
+
+            // Reinitialize status byte
+            0x8B => {
+                self.status |= RR0Mask::MATCH_NOT_FOUND;
+                self.status |= RR0Mask::NOT_END_OF_BLOCK;
+            }
+
+            // Read mask follows
+            0xBB => {
+                self.begin_follows(BaseRegister::WR6, WR6Mask::READ_MASK_FOLLOWS);
+            }
+
+            // Initiate read sequence
+            0xA7 => {
+                self.read_follows = self.read_mask;
+            }
+
+            // Force ready, for transfers without an I/O device to drive RDY
+            0xB3 => {
+                self.force_ready = true;
+            }
+
+            // Enable DMA
+            0x87 => {
+                self.enabled = true;
+            }
+
+            // Disable DMA
+            0x83 => {
+                self.enabled = false;
+            }
+
+            // Anything else isn't in the data sheet for the z8410, so it does nothing
+            _ => {}
+        }
+    }
+}
+
+/// BUSACK comes back on the clock after the CPU lets go of the bus
+const BUSACK_CYCLES: usize = 1;
+
+/// The length of one read or write cycle. Ending a strobe half a cycle early (the rest of
+/// the timing byte) doesn't change it.
+fn port_cycles(is_memory: bool, cycle_length: Option<u8>) -> usize {
+    match cycle_length {
+        None if is_memory => 3,
+        None => 4,
+        Some(1) => 3,
+        Some(2) => 2,
+        Some(_) => 4,
+    }
+}
+
+/// A cycle length of 11 isn't in the data sheet, so it's taken as 00 (4 cycles)
+fn timing_byte(data: u8) -> u8 {
+    if (data & WR1Mask::CYCLE_LENGTH) == WR1Mask::CYCLE_LENGTH {
+        data & !WR1Mask::CYCLE_LENGTH
+    } else {
+        data
+    }
 }
 
 impl Device for Dma {
-    // TODO: The timing for the DMA is probably jank. We are reading and writing on every cycle.
-    //   This might actually be how the DMS works, but I need to go through the docs again.
+    /// Transfers (or searches) a single byte. `transfer` works out how long it takes.
     fn tick(&mut self, bus: &mut dyn DeviceBus) {
-        if !self.enabled {
+        if !self.enabled || !self.ready() {
             return;
         }
 
@@ -327,382 +760,203 @@ impl Device for Dma {
             },
         }
 
-        // Step 6: Check for match
-        if self.search {
-            if (!self.mask_byte & self.match_byte) == byte {
-                self.status &= !RR0Mask::MATCH_NOT_FOUND;
+        // Step 6: Count the byte
+        self.byte_counter = self.byte_counter.wrapping_add(1);
+        // INT pulses whenever the low byte of the counter matches the pulse control byte
+        if self.interrupts_enabled
+            && self.pulse_generated
+            && (self.byte_counter as u8) == self.pulse_control
+        {
+            self.status |= RR0Mask::INTERRUPT_PENDING;
+        }
 
-                self.enabled = false;
-                if self.interrupts_enabled && self.interrupt_on_match {
-                    self.status |= RR0Mask::INTERRUPT_PENDING;
-                }
+        // Step 7: Check for match. Bits set in the mask byte don't take part.
+        if self.search && ((byte ^ self.match_byte) & !self.mask_byte) == 0 {
+            self.status &= !RR0Mask::MATCH_NOT_FOUND;
 
-                if self.stop_on_match {
-                    return;
-                }
+            if self.interrupts_enabled && self.interrupt_on_match {
+                self.status |= RR0Mask::INTERRUPT_PENDING;
+            }
+
+            if self.stop_on_match {
+                self.enabled = false;
             }
         }
 
-        // Step 7: Check for end of block
-        self.byte_counter = self.byte_counter.wrapping_add(1);
+        // Step 8: Check for end of block
         if self.byte_counter == self.block_length {
             self.status &= !RR0Mask::NOT_END_OF_BLOCK;
 
-            self.enabled = false;
-            if self.interrupt_at_end_of_block {
+            if self.interrupts_enabled && self.interrupt_at_end_of_block {
                 self.status |= RR0Mask::INTERRUPT_PENDING;
             }
 
+            // Auto restart reloads both ports and carries on (unless a match just
+            // stopped it).
             if self.restart_at_end_of_block {
-                // TODO: Do I need to stay enabled when I restart?
-                todo!()
+                self.port_a_counter = self.port_a_start_address;
+                self.port_b_counter = self.port_b_start_address;
+                self.byte_counter = 0;
+            } else {
+                self.enabled = false;
             }
         }
-
-        if self.interrupt_on_ready {
-            self.status |= RR0Mask::INTERRUPT_PENDING;
-        }
     }
 
     fn read(&mut self, _: u16) -> u8 {
-        debug_assert!(self.read_order.len() <= 8);
+        // The sequence starts over after the last register in the mask
+        if self.read_follows == 0 {
+            self.read_follows = self.read_mask;
+        }
+        let bit = match self.read_follows {
+            0 => return 0,
+            follows => 1 << follows.trailing_zeros(),
+        };
+        self.read_follows &= !bit;
 
-        match self.read_order.pop() {
-            Some(ReadRegister::Status) => self.status,
+        match bit {
+            WR6Mask::STATUS => {
+                if self.ready() {
+                    self.status | RR0Mask::READY
+                } else {
+                    self.status & !RR0Mask::READY
+                }
+            }
 
-            Some(ReadRegister::ByteCounterLow) => self.byte_counter as u8,
-            Some(ReadRegister::ByteCounterHigh) => (self.byte_counter >> 8) as u8,
+            WR6Mask::BYTE_COUNTER_LOW => self.byte_counter as u8,
+            WR6Mask::BYTE_COUNTER_HIGH => (self.byte_counter >> 8) as u8,
 
-            Some(ReadRegister::PortAAddressLow) => self.port_a_counter as u8,
-            Some(ReadRegister::PortAAddressHigh) => (self.port_a_counter >> 8) as u8,
+            WR6Mask::PORT_A_ADDRESS_LOW => self.port_a_counter as u8,
+            WR6Mask::PORT_A_ADDRESS_HIGH => (self.port_a_counter >> 8) as u8,
 
-            Some(ReadRegister::PortBAddressLow) => self.port_b_counter as u8,
-            Some(ReadRegister::PortBAddressHigh) => (self.port_b_counter >> 8) as u8,
+            WR6Mask::PORT_B_ADDRESS_LOW => self.port_b_counter as u8,
+            WR6Mask::PORT_B_ADDRESS_HIGH => (self.port_b_counter >> 8) as u8,
 
-            None => 0,
+            _ => unreachable!(),
         }
     }
 
     fn write(&mut self, _: u16, data: u8) {
-        debug_assert!(self.write_order.len() <= 8);
-
-        if self.enabled {
+        // Follow bytes are taken in order of the lowest set bit, no matter what they look
+        // like.
+        if self.write_follows != 0 {
+            let bit = 1 << self.write_follows.trailing_zeros();
+            self.write_follows &= !bit;
+            self.write_follow(self.write_base.follow(bit), data);
             return;
         }
 
-        match self.write_order.pop() {
-            // Initial state. Need to figure out which base register we are using.
-            // The logic for selecting the bits seems super janky but this is what it is.
-            None => {
-                // Register 1 => 0XXX X100
-                if (data & WR1Mask::SELECT_MASK) == WR1Mask::SELECT_BITS {
-                    self.port_a_is_memory = (data & WR1Mask::MEMORY_OR_IO) == 0;
-
-                    self.port_a_increment_mode =
-                        match (data & WR1Mask::INCREMENT_DECREMENT_MODE) >> 4 {
-                            0 => IncrementMode::Decrement,
-                            1 => IncrementMode::Increment,
-                            2 | 3 => IncrementMode::Fixed,
-                            _ => unreachable!(),
-                        };
-
-                    // Which ports will be written to?
-                    if (data & 0x40) != 0 {
-                        self.write_order.push(WriteRegister::PortATiming);
-                    }
-                }
-                // Register 2 => 0XXX X000
-                else if (data & WR2Mask::SELECT_MASK) == WR2Mask::SELECT_BITS {
-                    self.port_b_is_memory = (data & WR2Mask::MEMORY_OR_IO) == 0;
-
-                    self.port_b_increment_mode =
-                        match (data & WR2Mask::INCREMENT_DECREMENT_MODE) >> 4 {
-                            0 => IncrementMode::Decrement,
-                            1 => IncrementMode::Increment,
-                            2 | 3 => IncrementMode::Fixed,
-                            _ => unreachable!(),
-                        };
-
-                    // Which ports will be written to?
-                    if (data & 0x40) != 0 {
-                        self.write_order.push(WriteRegister::PortBTiming);
-                    }
-                }
-                // Register 0 => 0XXX XXXX
-                else if (data & WR0Mask::SELECT_MASK) == WR0Mask::SELECT_BITS {
-                    match data & WR0Mask::SEARCH_OR_TRANSFER {
-                        1 => self.transfer = true,
-                        2 => self.search = true,
-                        3 => {
-                            self.transfer = true;
-                            self.search = true;
-                        }
-                        _ => unreachable!(),
-                    }
-
-                    if (data & WR0Mask::DIRECTION) == 0 {
-                        self.direction = Direction::PortBToA;
-                    } else {
-                        self.direction = Direction::PortAToB;
-                    }
-
-                    // Which ports will be written to?
-                    if (data & 0x40) != 0 {
-                        self.write_order.push(WriteRegister::BlockLengthHigh);
-                    }
-                    if (data & 0x20) != 0 {
-                        self.write_order.push(WriteRegister::BlockLengthLow);
-                    }
-                    if (data & 0x10) != 0 {
-                        self.write_order.push(WriteRegister::PortAAddressHigh);
-                    }
-                    if (data & 0x08) != 0 {
-                        self.write_order.push(WriteRegister::PortAAddressLow);
-                    }
-                }
-                // Register 3 => 1XXX XX00
-                else if (data & WR3Mask::SELECT_MASK) == WR3Mask::SELECT_BITS {
-                    self.stop_on_match = (data & WR3Mask::STOP_ON_MATCH) != 0;
-
-                    // According to the docs, setting this to 0 does not disable interrupts
-                    if (data & WR3Mask::INTERRUPT_ENABLE) != 0 {
-                        self.interrupts_enabled = true;
-                    }
-
-                    // According to the docs, setting this to 0 does not disable DMA
-                    if (data & WR3Mask::DMA_ENABLE) != 0 {
-                        self.enabled = true;
-                    }
-
-                    // Which ports will be written to?
-                    if (data & 0x10) != 0 {
-                        self.write_order.push(WriteRegister::MatchByte);
-                    }
-                    if (data & 0x08) != 0 {
-                        self.write_order.push(WriteRegister::MaskByte);
-                    }
-                }
-                // Register 4 => 1XXX XX01
-                else if (data & WR4Mask::SELECT_MASK) == WR4Mask::SELECT_BITS {
-                    self.access_mode = match (data & WR4Mask::ACCESS_MODE) >> 5 {
-                        0 => AccessMode::Byte,
-                        1 => AccessMode::Continuous,
-                        3 => AccessMode::Burst,
-                        _ => unimplemented!("Attempted to set an impossible DMA access mode: 4"),
-                    };
-
-                    // Which ports will be written to?
-                    if (data & 0x10) != 0 {
-                        self.write_order.push(WriteRegister::InterruptControl);
-                    }
-                    if (data & 0x08) != 0 {
-                        self.write_order.push(WriteRegister::PortBAddressHigh);
-                    }
-                    if (data & 0x04) != 0 {
-                        self.write_order.push(WriteRegister::PortBAddressLow);
-                    }
-                }
-                // Register 5 => 10XX X010
-                else if (data & WR5Mask::SELECT_MASK) == WR5Mask::SELECT_BITS {
-                    if (data & WR5Mask::CHIP_ENABLE_ONLY_WAIT) != 0 {
-                        todo!()
-                    }
-
-                    self.restart_at_end_of_block =
-                        (data & WR5Mask::STOP_RESTART_ON_END_OF_BLOCK) != 0;
-                }
-                // Register 6 => 1XXX XX11
-                else if (data & WR6Mask::SELECT_MASK) == WR6Mask::SELECT_BITS {
-                    // All control bytes disable the DMA... except the enable DMA byte ;-)
-                    self.enabled = false;
-
-                    match data {
-                        // Reset
-                        0xC3 => {
-                            // TODO: Do I need to reset other status bits?
-                            //   like the found/end and transfer bits?
-                            self.interrupts_enabled = false;
-                            self.restart_at_end_of_block = false;
-                            self.status &= !RR0Mask::INTERRUPT_PENDING;
-                        }
-
-                        // Reset Port A Timing
-                        0xC7 => todo!(),
-
-                        // Reset Port B Timing
-                        0xCB => todo!(),
-
-                        // Load
-                        0xCF => {
-                            // NOTE: This only loads the source register.
-                            // The destination register will get updated during its first
-                            // increment!
-                            // TODO: Make sure I impl this correctly. Auto-restart is also
-                            //   supposed to do this automatically.
-                            match self.direction {
-                                Direction::PortAToB => {
-                                    self.port_a_counter = self.port_a_start_address;
-                                }
-                                Direction::PortBToA => {
-                                    self.port_b_counter = self.port_b_start_address;
-                                }
-                            }
-                            self.byte_counter = 0;
-                        }
-
-                        // Continue
-                        0xD3 => {
-                            self.byte_counter = 0;
-                        }
-
-                        // Disable interrupts
-                        0xAF => todo!(),
-
-                        // Enable interrupts
-                        0xAB => {
-                            self.interrupts_enabled = true;
-                        }
-
-                        // Reset and disable interrupts
-                        0xA3 => todo!(),
-
-                        // Enable after reti
-                        0xB7 => todo!(),
-
-                        // Read status byte
-                        0xBF => {
-                            self.read_order.push(ReadRegister::Status);
-                        }
-
-                        // Reinitialize status byte
-                        0x8B => {
-                            self.status |= RR0Mask::MATCH_NOT_FOUND;
-                            self.status |= RR0Mask::NOT_END_OF_BLOCK;
-                        }
-
-                        // Read mask follows
-                        0xBB => {
-                            self.write_order.push(WriteRegister::ReadMask);
-                        }
-
-                        // Initiate read sequence
-                        0xA7 => {
-                            if (self.read_mask & WR6Mask::PORT_B_ADDRESS_HIGH) != 0 {
-                                self.read_order.push(ReadRegister::PortBAddressHigh);
-                            }
-                            if (self.read_mask & WR6Mask::PORT_B_ADDRESS_LOW) != 0 {
-                                self.read_order.push(ReadRegister::PortBAddressLow);
-                            }
-                            if (self.read_mask & WR6Mask::PORT_A_ADDRESS_HIGH) != 0 {
-                                self.read_order.push(ReadRegister::PortAAddressHigh);
-                            }
-                            if (self.read_mask & WR6Mask::PORT_A_ADDRESS_LOW) != 0 {
-                                self.read_order.push(ReadRegister::PortAAddressLow);
-                            }
-                            if (self.read_mask & WR6Mask::BYTE_COUNTER_LOW) != 0 {
-                                self.read_order.push(ReadRegister::ByteCounterLow);
-                            }
-                            if (self.read_mask & WR6Mask::BYTE_COUNTER_HIGH) != 0 {
-                                self.read_order.push(ReadRegister::ByteCounterHigh);
-                            }
-                            if (self.read_mask & WR6Mask::STATUS) != 0 {
-                                self.read_order.push(ReadRegister::Status);
-                            }
-                        }
-
-                        // Force ready
-                        0xB3 => todo!(),
-
-                        // Enable DMA
-                        0x87 => {
-                            self.enabled = true;
-                        }
-
-                        // Disable DMA
-                        0x83 => {
-                            self.enabled = false;
-                        }
-
-                        _ => unimplemented!(
-                            "Unrecognized DMA command: {}. It wasn't in the data sheet for z8410.",
-                            data
-                        ),
-                    }
-                }
-            }
-
-            Some(WriteRegister::PortAAddressLow) => {
-                self.port_a_start_address = (self.port_a_start_address & 0xF0) | data as u16;
-            }
-
-            Some(WriteRegister::PortAAddressHigh) => {
-                self.port_a_start_address =
-                    (self.port_a_start_address & 0x0F) | ((data as u16) << 8);
-            }
-
-            Some(WriteRegister::BlockLengthLow) => {
-                self.block_length = (self.block_length & 0xF0) | data as u16;
-            }
-
-            Some(WriteRegister::BlockLengthHigh) => {
-                self.block_length = (self.block_length & 0x0F) | ((data as u16) << 8);
-            }
+        // Only commands get through while enabled. In byte mode the CPU gets the bus
+        // between bytes, so it can still stop the DMA.
+        if self.enabled && (data & WR6Mask::SELECT_MASK) != WR6Mask::SELECT_BITS {
+            return;
+        }
 
-            Some(WriteRegister::PortATiming) => todo!(),
-            Some(WriteRegister::PortBTiming) => todo!(),
+        // Need to figure out which base register we are using.
+        // The logic for selecting the bits seems super janky but this is what it is.
 
-            Some(WriteRegister::PortBAddressLow) => {
-                self.port_b_start_address = (self.port_b_start_address & 0xF0) | data as u16;
+        // Register 1 => 0XXX X100
+        if (data & WR1Mask::SELECT_MASK) == WR1Mask::SELECT_BITS {
+            self.port_a_is_memory = (data & WR1Mask::MEMORY_OR_IO) == 0;
+            self.port_a_increment_mode =
+                IncrementMode::from_bits((data & WR1Mask::INCREMENT_DECREMENT_MODE) >> 4);
+            self.begin_follows(BaseRegister::WR1, data & WR1Mask::FOLLOWS);
+        }
+        // Register 2 => 0XXX X000
+        else if (data & WR2Mask::SELECT_MASK) == WR2Mask::SELECT_BITS {
+            self.port_b_is_memory = (data & WR2Mask::MEMORY_OR_IO) == 0;
+            self.port_b_increment_mode =
+                IncrementMode::from_bits((data & WR2Mask::INCREMENT_DECREMENT_MODE) >> 4);
+            self.begin_follows(BaseRegister::WR2, data & WR2Mask::FOLLOWS);
+        }
+        // Register 0 => 0XXX XXXX
+        else if (data & WR0Mask::SELECT_MASK) == WR0Mask::SELECT_BITS {
+            self.transfer = (data & WR0Mask::TRANSFER) != 0;
+            self.search = (data & WR0Mask::SEARCH) != 0;
+
+            if (data & WR0Mask::DIRECTION) == 0 {
+                self.direction = Direction::PortBToA;
+            } else {
+                self.direction = Direction::PortAToB;
             }
+            self.begin_follows(BaseRegister::WR0, data & WR0Mask::FOLLOWS);
+        }
+        // Register 3 => 1XXX XX00
+        else if (data & WR3Mask::SELECT_MASK) == WR3Mask::SELECT_BITS {
+            self.stop_on_match = (data & WR3Mask::STOP_ON_MATCH) != 0;
 
-            Some(WriteRegister::PortBAddressHigh) => {
-                self.port_b_start_address =
-                    (self.port_b_start_address & 0x0F) | ((data as u16) << 8);
+            // According to the docs, setting this to 0 does not disable interrupts
+            if (data & WR3Mask::INTERRUPT_ENABLE) != 0 {
+                self.interrupts_enabled = true;
             }
 
-            Some(WriteRegister::InterruptControl) => {
-                self.interrupt_on_match = (data & WR4Mask::INTERRUPT_ON_MATCH) != 0;
-
-                self.interrupt_at_end_of_block = (data & WR4Mask::INTERRUPT_AT_END_OF_BLOCK) != 0;
-
-                if (data & WR4Mask::PULSE_GENERATED) != 0 {
-                    todo!();
-                }
-
-                self.status_affects_vector = (data & WR4Mask::STATUS_AFFECTS_VECTOR) != 0;
-
-                self.interrupt_on_ready = (data & WR4Mask::INTERRUPT_ON_READY) != 0;
-
-                // Which ports will be written to?
-                if (data & 0x10) != 0 {
-                    self.write_order.push(WriteRegister::InterruptVector);
-                }
-                if (data & 0x08) != 0 {
-                    self.write_order.push(WriteRegister::PulseControl);
-                }
+            // According to the docs, setting this to 0 does not disable DMA
+            if (data & WR3Mask::DMA_ENABLE) != 0 {
+                self.enabled = true;
             }
+            self.begin_follows(BaseRegister::WR3, data & WR3Mask::FOLLOWS);
+        }
+        // Register 4 => 1XXX XX01
+        else if (data & WR4Mask::SELECT_MASK) == WR4Mask::SELECT_BITS {
+            self.access_mode = match (data & WR4Mask::ACCESS_MODE) >> 5 {
+                0 => AccessMode::Byte,
+                1 => AccessMode::Continuous,
+                2 => AccessMode::Burst,
+                // 11 isn't a mode, so the old one stays
+                _ => self.access_mode,
+            };
+            self.begin_follows(BaseRegister::WR4, data & WR4Mask::FOLLOWS);
+        }
+        // Register 5 => 10XX X010
+        else if (data & WR5Mask::SELECT_MASK) == WR5Mask::SELECT_BITS {
+            // CE/WAIT multiplexing doesn't matter since nothing on the Possum drives WAIT
+            self.ready_active_high = (data & WR5Mask::READY_ACTIVE_HIGH) != 0;
+            self.restart_at_end_of_block = (data & WR5Mask::STOP_RESTART_ON_END_OF_BLOCK) != 0;
+        }
+        // Register 6 => 1XXX XX11
+        else if (data & WR6Mask::SELECT_MASK) == WR6Mask::SELECT_BITS {
+            self.command(data);
+        }
+    }
 
-            Some(WriteRegister::PulseControl) => todo!(),
-
-            Some(WriteRegister::InterruptVector) => {
-                self.interrupt_vector = data;
-            }
+    fn interrupting(&self) -> bool {
+        !self.in_service && (self.status & RR0Mask::INTERRUPT_PENDING) != 0
+    }
 
-            Some(WriteRegister::ReadMask) => {
-                self.read_mask = data;
-            }
+    fn acknowledge(&mut self) -> Option<u8> {
+        if !self.interrupting() {
+            return None;
+        }
+        self.status &= !RR0Mask::INTERRUPT_PENDING;
+        self.in_service = true;
+        if !self.status_affects_vector {
+            return Some(self.interrupt_vector);
+        }
+        // Bits 1 and 2 say what caused the interrupt: 00 ready, 01 match, 10 end of block
+        // and 11 both.
+        let mut cause = 0;
+        if (self.status & RR0Mask::MATCH_NOT_FOUND) == 0 {
+            cause |= 0x02;
+        }
+        if (self.status & RR0Mask::NOT_END_OF_BLOCK) == 0 {
+            cause |= 0x04;
+        }
+        Some((self.interrupt_vector & 0xF9) | cause)
+    }
 
-            Some(WriteRegister::MaskByte) => {
-                self.mask_byte = data;
-            }
+    fn in_service(&self) -> bool {
+        self.in_service
+    }
 
-            Some(WriteRegister::MatchByte) => {
-                self.match_byte = data;
-            }
-        }
+    fn polled(&mut self) {
+        self.status &= !RR0Mask::INTERRUPT_PENDING;
     }
 
-    fn interrupting(&self) -> bool {
-        (self.status & RR0Mask::INTERRUPT_PENDING) != 0
+    fn reti(&mut self) {
+        self.in_service = false;
+        if self.enable_after_reti {
+            self.enable_after_reti = false;
+            self.enabled = true;
+        }
     }
 }
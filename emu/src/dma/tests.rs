@@ -18,7 +18,7 @@ fn simple_transfer() {
 
     dma.write(0, 0b0001_0000); // wr2: b is memory, increment
 
-    dma.write(0, 0b1011_1101); // wr4: byte mode
+    dma.write(0, 0b1011_1101); // wr4: continuous mode
     dma.write(0, 0x05); // b address: 0x0005
     dma.write(0, 0x00); //
     dma.write(0, 0b010_0010); // status affects vector, interrupt at end
@@ -32,3 +32,479 @@ fn simple_transfer() {
     }
     assert_eq!(bus.mem()[0..5], bus.mem()[5..10]); // !
 }
+
+/// Programs a memory to memory copy of `length` bytes from 0x0000 to 0x0100
+fn program_copy(dma: &mut Dma, wr4: u8, length: u8) {
+    dma.write(0, 0b0111_1101); // wr0: transfer a -> b
+    dma.write(0, 0x00); // a address: 0x0000
+    dma.write(0, 0x00); //
+    dma.write(0, length); // length
+    dma.write(0, 0x00); //
+    dma.write(0, 0b0001_0100); // wr1: a is memory, increment
+    dma.write(0, 0b0001_0000); // wr2: b is memory, increment
+    dma.write(0, wr4); // wr4
+    dma.write(0, 0x00); // b address: 0x0100
+    dma.write(0, 0x01); //
+    dma.write(0, 0b0011_0010); // status affects vector, interrupt at end, vector follows
+    dma.write(0, 0x40); // vector
+    dma.write(0, 0xCF); // Load
+}
+
+#[test]
+fn bus_request() {
+    let mut bus = TestBus::new();
+    let mut dma = Dma::default();
+    program_copy(&mut dma, 0b1001_1101, 3); // wr4: byte mode
+    assert!(!dma.bus_requested());
+    dma.write(0, 0x87); // Enable DMA
+    assert!(dma.bus_requested());
+
+    // Lets go after every byte
+    assert_eq!(dma.transfer(&mut bus), 7);
+    assert!(!dma.bus_requested());
+    dma.release_bus();
+    assert!(dma.bus_requested());
+    dma.transfer(&mut bus);
+    dma.release_bus();
+    dma.transfer(&mut bus);
+    // Released at the end of the block
+    assert!(!dma.bus_requested());
+    dma.release_bus();
+
+    // RDY is active low to begin with
+    program_copy(&mut dma, 0b1011_1101, 3); // wr4: continuous mode
+    dma.write(0, 0x87); // Enable DMA
+    assert_eq!(dma.transfer(&mut bus), 7);
+    assert_eq!(dma.transfer(&mut bus), 6);
+    dma.set_ready(true);
+    // Holds the bus while waiting
+    assert!(dma.bus_requested());
+    assert_eq!(dma.transfer(&mut bus), 1);
+    dma.set_ready(false);
+    dma.transfer(&mut bus);
+    assert!(!dma.bus_requested());
+    dma.release_bus();
+
+    program_copy(&mut dma, 0b1101_1101, 3); // wr4: burst mode
+    dma.write(0, 0x87); // Enable DMA
+    dma.transfer(&mut bus);
+    assert!(dma.bus_requested());
+    // Lets go while not ready
+    dma.set_ready(true);
+    assert!(!dma.bus_requested());
+}
+
+#[test]
+fn disable_while_enabled() {
+    let mut bus = TestBus::new();
+    let mut dma = Dma::default();
+    program_copy(&mut dma, 0b1001_1101, 4); // wr4: byte mode
+    dma.write(0, 0x87); // Enable DMA
+    dma.tick(&mut bus);
+
+    // Other registers are locked out, but commands get through
+    dma.write(0, 0b0001_0100); // wr1
+    assert!(dma.bus_requested());
+    dma.write(0, 0x83); // Disable DMA
+    assert!(!dma.bus_requested());
+}
+
+#[test]
+fn invalid_bytes() {
+    let mut bus = TestBus::new();
+    let mut dma = Dma::default();
+    program_copy(&mut dma, 0b1001_1101, 4); // wr4: byte mode
+    dma.write(0, 0xFF); // Not a command
+    dma.write(0, 0b1110_0001); // wr4: access mode 11
+    dma.write(0, 0x87); // Enable DMA
+
+    // Still in byte mode
+    assert!(dma.bus_requested());
+    dma.transfer(&mut bus);
+    assert!(!dma.bus_requested());
+}
+
+#[test]
+fn interrupt_vector() {
+    let mut bus = TestBus::new();
+    let mut dma = Dma::default();
+    program_copy(&mut dma, 0b1001_1101, 1); // wr4: byte mode
+    dma.write(0, 0x8B); // Reinitialize status byte
+    dma.write(0, 0x87); // Enable DMA
+    dma.tick(&mut bus);
+
+    // Interrupts are still disabled
+    assert!(!dma.interrupting());
+
+    program_copy(&mut dma, 0b1001_1101, 1); // wr4: byte mode
+    dma.write(0, 0x8B); // Reinitialize status byte
+    dma.write(0, 0xAB); // Enable interrupts
+    dma.write(0, 0x87); // Enable DMA
+    dma.tick(&mut bus);
+    assert!(dma.interrupting());
+
+    // The end of block status is in the vector
+    assert_eq!(dma.acknowledge(), Some(0x44));
+    assert!(dma.in_service());
+    assert!(!dma.interrupting());
+    dma.reti();
+    assert!(!dma.in_service());
+}
+
+#[test]
+fn io_to_memory() {
+    let mut bus = TestBus::new();
+    bus.io_mut()[0x10] = 0x42;
+    let mut dma = Dma::default();
+
+    dma.write(0, 0b0111_1101); // wr0: transfer a -> b
+    dma.write(0, 0x10); // a address: 0x0010
+    dma.write(0, 0x00); //
+    dma.write(0, 0x03); // length: 3
+    dma.write(0, 0x00); //
+    dma.write(0, 0b0010_1100); // wr1: a is io, fixed
+    dma.write(0, 0b0001_0000); // wr2: b is memory, increment
+    dma.write(0, 0b1000_1101); // wr4: byte mode
+    dma.write(0, 0x00); // b address: 0x0200
+    dma.write(0, 0x02); //
+    dma.write(0, 0xCF); // Load
+    dma.write(0, 0x87); // Enable DMA
+    assert_eq!(dma.byte_cycles(), 7);
+
+    while dma.bus_requested() {
+        dma.tick(&mut bus);
+    }
+    assert_eq!(bus.mem()[0x200..0x204], [0x42, 0x42, 0x42, 0x00]);
+    assert_eq!(bus.io()[0x10], 0x42);
+}
+
+/// Reads the whole read sequence
+fn read_all(dma: &mut Dma) -> [u8; 7] {
+    dma.write(0, 0xBB); // Read mask follows
+    dma.write(0, 0x7F); // everything
+    dma.write(0, 0xA7); // Initiate read sequence
+    [(); 7].map(|_| dma.read(0))
+}
+
+#[test]
+fn datasheet_memory_to_io() {
+    let mut bus = TestBus::new();
+    bus.mem_mut()[0x1000..0x1004].copy_from_slice(&[1, 2, 3, 4]);
+    let mut dma = Dma::default();
+
+    // Reset, however far into a sequence the DMA was left
+    for _ in 0..6 {
+        dma.write(0, 0xC3);
+    }
+    dma.write(0, 0x79); // wr0: transfer b -> a, everything follows
+    dma.write(0, 0x00); // a address: 0x1000
+    dma.write(0, 0x10); //
+    dma.write(0, 0x04); // length: 4
+    dma.write(0, 0x00); //
+    dma.write(0, 0x14); // wr1: a is memory, increment
+    dma.write(0, 0x28); // wr2: b is io, fixed
+    dma.write(0, 0xAD); // wr4: continuous mode, b address follows
+    dma.write(0, 0x10); // b address: 0x0010
+    dma.write(0, 0x00); //
+    dma.write(0, 0x8A); // wr5: ready active high
+    dma.write(0, 0xCF); // Load b, since it is the source
+    dma.write(0, 0x05); // wr0: transfer a -> b
+    dma.write(0, 0xCF); // Load a
+    dma.write(0, 0x87); // Enable DMA
+
+    // The port is always ready
+    dma.set_ready(true);
+    let mut bytes = Vec::new();
+    while dma.bus_requested() {
+        dma.tick(&mut bus);
+        bytes.push(bus.io()[0x10]);
+    }
+    assert_eq!(bytes, [1, 2, 3, 4]);
+
+    let [status, counter_low, counter_high, a_low, a_high, b_low, b_high] = read_all(&mut dma);
+    assert_eq!(status & 0x21, 0x01);
+    assert_eq!([counter_low, counter_high], [0x04, 0x00]);
+    assert_eq!([a_low, a_high], [0x04, 0x10]);
+    assert_eq!([b_low, b_high], [0x10, 0x00]);
+}
+
+#[test]
+fn follow_bytes() {
+    let mut dma = Dma::default();
+
+    // Only the selected registers follow, lowest bit first
+    dma.write(0, 0b0011_0001); // wr0: a address high and length low follow
+    dma.write(0, 0x12); // a address high
+    dma.write(0, 0x05); // length low
+    dma.write(0, 0b1001_0001); // wr4: only interrupt control follows
+    dma.write(0, 0b0001_1000); // interrupt control: pulse control and vector follow
+    dma.write(0, 0x02); // pulse control
+    dma.write(0, 0x60); // vector
+    dma.write(0, 0b1001_1000); // wr3: mask and match follow
+    dma.write(0, 0xF0); // mask
+    dma.write(0, 0x0F); // match
+    dma.write(0, 0b0101_0100); // wr1: a is memory, increment, timing follows
+    dma.write(0, 0b0000_0001); // timing: 3 cycles
+    dma.write(0, 0b0001_0000); // wr2: b is memory, increment
+
+    assert_eq!(dma.port_a_start_address, 0x1200);
+    assert_eq!(dma.block_length, 0x0005);
+    assert_eq!(dma.pulse_control, 0x02);
+    assert_eq!(dma.interrupt_vector, 0x60);
+    assert_eq!((dma.mask_byte, dma.match_byte), (0xF0, 0x0F));
+    assert_eq!(dma.port_a_timing, Some(0x01));
+    assert!(dma.port_a_is_memory && dma.port_b_is_memory);
+
+    // Follow bytes that look like base registers are still follow bytes
+    dma.write(0, 0b0100_0100); // wr1: timing follows
+    dma.write(0, 0x87); // timing, not enable
+    assert!(!dma.bus_requested());
+}
+
+#[test]
+fn read_sequence() {
+    let mut dma = Dma::default();
+    dma.write(0, 0b0001_1001); // wr0: a address follows
+    dma.write(0, 0x34); //
+    dma.write(0, 0x12); //
+    dma.write(0, 0x05); // wr0: a -> b
+    dma.write(0, 0xCF); // Load
+
+    dma.write(0, 0xBB); // Read mask follows
+    dma.write(0, 0b0001_1000); // a address
+    dma.write(0, 0xA7); // Initiate read sequence
+    assert_eq!(dma.read(0), 0x34);
+    assert_eq!(dma.read(0), 0x12);
+    // And around again
+    assert_eq!(dma.read(0), 0x34);
+
+    // The status byte can be read without disturbing the mask
+    dma.write(0, 0x8B); // Reinitialize status byte
+    dma.write(0, 0xBF); // Read status byte
+    assert_eq!(dma.read(0) & 0x30, 0x30);
+    assert_eq!(dma.read(0), 0x34);
+}
+
+/// Programs a search of `length` bytes at 0x0000
+fn program_search(dma: &mut Dma, wr0: u8, wr3: u8, match_byte: u8, mask_byte: u8, length: u8) {
+    dma.write(0, wr0 | 0b0111_1000); // wr0: a -> b, everything follows
+    dma.write(0, 0x00); // a address: 0x0000
+    dma.write(0, 0x00); //
+    dma.write(0, length); // length
+    dma.write(0, 0x00); //
+    dma.write(0, 0b0001_0100); // wr1: a is memory, increment
+    dma.write(0, 0b0001_0000); // wr2: b is memory, increment
+    dma.write(0, 0b1001_1101); // wr4: byte mode, b address and interrupt control follow
+    dma.write(0, 0x00); // b address: 0x0100
+    dma.write(0, 0x01); //
+    dma.write(0, 0b0010_0001); // status affects vector, interrupt on match
+    dma.write(0, wr3 | 0b1001_1000); // wr3: mask and match follow
+    dma.write(0, mask_byte); // mask
+    dma.write(0, match_byte); // match
+    dma.write(0, 0x8B); // Reinitialize status byte
+    dma.write(0, 0xCF); // Load
+}
+
+fn status(dma: &mut Dma) -> u8 {
+    dma.write(0, 0xBF); // Read status byte
+    dma.read(0)
+}
+
+#[test]
+fn search() {
+    let mut bus = TestBus::with_mem(b"hello, world".to_vec());
+    let mut dma = Dma::default();
+
+    // Search only, like memchr
+    program_search(&mut dma, 0b0000_0110, 0b0000_0100, b',', 0x00, 12);
+    assert_eq!(dma.byte_cycles(), 3);
+    dma.write(0, 0x87); // Enable DMA
+    while dma.bus_requested() {
+        dma.tick(&mut bus);
+    }
+    // Match found, but not the end of the block
+    assert_eq!(status(&mut dma) & 0x30, 0x20);
+    // The address has moved past the match
+    assert_eq!(dma.port_a_counter, 6);
+    // Nothing was written
+    assert!(bus.mem()[0x100..0x10C].iter().all(|&b| b == 0));
+
+    // Carry on for another block from there
+    dma.write(0, 0x8B); // Reinitialize status byte
+    dma.write(0, 0xD3); // Continue
+    dma.write(0, 0x87); // Enable DMA
+    while dma.bus_requested() {
+        dma.tick(&mut bus);
+    }
+    assert_eq!(status(&mut dma) & 0x30, 0x10);
+    assert_eq!(dma.port_a_counter, 18);
+}
+
+#[test]
+fn masked_search_transfer() {
+    let mut bus = TestBus::with_mem(b"HELLO, world".to_vec());
+    let mut dma = Dma::default();
+
+    // Ignore the case bit and don't stop on a match
+    program_search(&mut dma, 0b0000_0111, 0b0000_0000, b'o', 0x20, 12);
+    dma.write(0, 0xAB); // Enable interrupts
+    dma.write(0, 0x87); // Enable DMA
+
+    // "O" matches first
+    while !dma.interrupting() {
+        dma.tick(&mut bus);
+    }
+    assert!(dma.bus_requested());
+    assert_eq!(dma.port_a_counter, 5);
+    // The match status is in the vector
+    assert_eq!(dma.acknowledge(), Some(0x02));
+    dma.reti();
+
+    // Then "o"
+    while !dma.interrupting() {
+        dma.tick(&mut bus);
+    }
+    assert_eq!(dma.port_a_counter, 9);
+    while dma.bus_requested() {
+        dma.tick(&mut bus);
+    }
+    // The whole block was copied
+    assert_eq!(&bus.mem()[0x100..0x10C], b"HELLO, world");
+}
+
+#[test]
+fn auto_restart() {
+    let mut bus = TestBus::with_mem(vec![1, 2, 3]);
+    let mut dma = Dma::default();
+    program_copy(&mut dma, 0b1001_1101, 3); // wr4: byte mode
+    dma.write(0, 0b1010_0010); // wr5: auto restart
+    dma.write(0, 0xAB); // Enable interrupts
+    dma.write(0, 0x87); // Enable DMA
+
+    for _ in 0..3 {
+        dma.tick(&mut bus);
+    }
+    assert!(dma.interrupting());
+    assert!(dma.bus_requested());
+    assert_eq!(dma.port_a_counter, 0x0000);
+    assert_eq!(dma.port_b_counter, 0x0100);
+
+    // Around again, over the same addresses
+    bus.mem_mut()[0..3].copy_from_slice(&[4, 5, 6]);
+    for _ in 0..3 {
+        dma.tick(&mut bus);
+    }
+    assert_eq!(bus.mem()[0x100..0x103], [4, 5, 6]);
+    assert!(dma.bus_requested());
+}
+
+#[test]
+fn ready() {
+    let mut bus = TestBus::new();
+    bus.io_mut()[0x10] = 0x42;
+    let mut dma = Dma::default();
+
+    dma.write(0, 0b0111_1101); // wr0: transfer a -> b
+    dma.write(0, 0x10); // a address: 0x0010
+    dma.write(0, 0x00); //
+    dma.write(0, 0x02); // length: 2
+    dma.write(0, 0x00); //
+    dma.write(0, 0b0010_1100); // wr1: a is io, fixed
+    dma.write(0, 0b0001_0000); // wr2: b is memory, increment
+    dma.write(0, 0b1000_1101); // wr4: byte mode
+    dma.write(0, 0x00); // b address: 0x0200
+    dma.write(0, 0x02); //
+    dma.write(0, 0x8A); // wr5: ready active high
+    dma.write(0, 0xCF); // Load
+    dma.write(0, 0x87); // Enable DMA
+
+    // Paced by the device being read
+    assert_eq!(dma.ready_port(), Some((0x10, false)));
+    assert!(!dma.bus_requested());
+    assert_eq!(dma.transfer(&mut bus), 2);
+    assert_eq!(bus.mem()[0x200], 0x00);
+    dma.release_bus();
+
+    dma.set_ready(true);
+    assert!(dma.bus_requested());
+    assert_eq!(status(&mut dma) & 0x02, 0x02);
+    assert_eq!(dma.transfer(&mut bus), 8);
+    assert_eq!(bus.mem()[0x200], 0x42);
+}
+
+#[test]
+fn force_ready() {
+    let mut bus = TestBus::with_mem(vec![1, 2]);
+    let mut dma = Dma::default();
+    program_copy(&mut dma, 0b1001_1101, 2); // wr4: byte mode
+    dma.write(0, 0x8A); // wr5: ready active high
+    dma.write(0, 0x87); // Enable DMA
+
+    // Nothing drives RDY in a memory to memory transfer
+    assert_eq!(dma.ready_port(), None);
+    assert!(!dma.bus_requested());
+    dma.write(0, 0xB3); // Force ready
+    while dma.bus_requested() {
+        dma.tick(&mut bus);
+    }
+    assert_eq!(bus.mem()[0x100..0x102], [1, 2]);
+}
+
+#[test]
+fn interrupt_on_ready() {
+    let mut bus = TestBus::new();
+    let mut dma = Dma::default();
+    program_copy(&mut dma, 0b1001_1101, 2); // wr4: byte mode
+    dma.write(0, 0b1001_0001); // wr4: interrupt control follows
+    dma.write(0, 0b0101_0000); // interrupt on ready, vector follows
+    dma.write(0, 0x40); // vector
+    dma.write(0, 0x8A); // wr5: ready active high
+    dma.write(0, 0xAB); // Enable interrupts
+    dma.write(0, 0x87); // Enable DMA
+    assert!(!dma.interrupting());
+
+    dma.set_ready(true);
+    assert!(dma.interrupting());
+    // The transfer waits for the interrupt to be serviced
+    assert!(!dma.bus_requested());
+    assert_eq!(dma.acknowledge(), Some(0x40));
+    assert!(!dma.bus_requested());
+    dma.reti();
+    assert!(dma.bus_requested());
+    dma.tick(&mut bus);
+
+    // Only a new edge interrupts again
+    dma.set_ready(true);
+    assert!(!dma.interrupting());
+    dma.set_ready(false);
+    dma.set_ready(true);
+    assert!(dma.interrupting());
+}
+
+#[test]
+fn timing() {
+    let mut bus = TestBus::new();
+    let mut dma = Dma::default();
+    program_copy(&mut dma, 0b1011_1101, 4); // wr4: continuous mode
+    dma.write(0, 0b0101_0100); // wr1: a is memory, increment, timing follows
+    dma.write(0, 0b0000_0010); // timing: 2 cycles
+    dma.write(0, 0b0101_0000); // wr2: b is memory, increment, timing follows
+    dma.write(0, 0b1000_1000); // timing: 4 cycles, MREQ and WR end early
+    dma.write(0, 0x87); // Enable DMA
+    assert_eq!(dma.transfer(&mut bus), 7);
+    assert_eq!(dma.transfer(&mut bus), 6);
+
+    // Back to standard timing
+    dma.write(0, 0xCB); // Reset port B timing
+    assert_eq!(dma.transfer(&mut bus), 5);
+
+    // Searching doesn't write
+    dma.write(0, 0x83); // Disable DMA
+    dma.write(0, 0b0000_0110); // wr0: search a
+    assert_eq!(dma.byte_cycles(), 2);
+
+    // A cycle length of 11 is taken as 4 cycles
+    dma.write(0, 0b0101_0100); // wr1: a is memory, increment, timing follows
+    dma.write(0, 0b0000_0011); // timing: 11
+    assert_eq!(dma.byte_cycles(), 4);
+}
@@ -0,0 +1,451 @@
+//! Parallel Keyboard Emulation
+//!
+//! The keyboard knows nothing about the host. Frontends (or tests) produce key events
+//! through a [`KeyboardInput`] handle and the keyboard consumes them as the system runs.
+//!
+//! In ASCII mode the guest reads characters. Control keys (and ctrl-combinations) are
+//! translated to their ASCII control codes and keys without an ASCII equivalent
+//! are translated to the codes in [`ExtendedCode`].
+//!
+//! In scancode mode the guest instead reads a make code when a key is pressed, and
+//! [`BREAK_PREFIX`] followed by the make code when the key is released.
+//! The make codes are USB HID usage IDs, so the modifier keys have the codes 0xE0-0xE7.
+//!
+//! The modifier state can be read at any time in either mode.
+//!
+//! Codes are held in a small FIFO until the guest reads them. The status register
+//! reports when data is ready, and when codes were lost because the FIFO was full.
+//! If interrupts are enabled, the keyboard interrupts as long as data is ready.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::bus::{Device, DeviceBus};
+
+/// Codes for keys with no ASCII equivalent
+pub struct ExtendedCode;
+impl ExtendedCode {
+    pub const UP: u8 = 0x80;
+    pub const DOWN: u8 = 0x81;
+    pub const LEFT: u8 = 0x82;
+    pub const RIGHT: u8 = 0x83;
+    pub const HOME: u8 = 0x84;
+    pub const END: u8 = 0x85;
+    pub const PAGE_UP: u8 = 0x86;
+    pub const PAGE_DOWN: u8 = 0x87;
+    pub const INSERT: u8 = 0x88;
+
+    /// F1 through F12 are sequential from here
+    pub const F1: u8 = 0x91;
+}
+
+/// Sent before the make code of a released key in scancode mode
+pub const BREAK_PREFIX: u8 = 0xF0;
+
+/// Number of codes the keyboard can hold before it starts dropping them
+const FIFO_SIZE: usize = 16;
+
+struct Status;
+impl Status {
+    /// There is at least one code in the FIFO
+    const DATA_READY: u8 = 0x01;
+
+    /// Codes were dropped since the last status read
+    const OVERFLOW: u8 = 0x02;
+}
+
+/// Bits of the control register. These are also mirrored in the status register.
+struct Control;
+impl Control {
+    /// Report make/break scancodes instead of ASCII
+    const SCANCODE: u8 = 0x40;
+
+    /// Interrupt while there is data ready
+    const INTERRUPT_ENABLE: u8 = 0x80;
+}
+
+/// Bits of the modifier state register
+pub struct Modifier;
+impl Modifier {
+    pub const SHIFT: u8 = 0x01;
+    pub const CTRL: u8 = 0x02;
+    pub const ALT: u8 = 0x04;
+    pub const GUI: u8 = 0x08;
+    pub const CAPS_LOCK: u8 = 0x10;
+    pub const NUM_LOCK: u8 = 0x20;
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum KeyboardMode {
+    #[default]
+    Ascii,
+    Scancode,
+}
+
+/// A key as the host's keyboard layout sees it
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Key {
+    /// A printable key, as it appears when no modifiers are held
+    Char(char),
+    Enter,
+    Backspace,
+    Tab,
+    Escape,
+    Delete,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    /// A function key, numbered from 1
+    F(u8),
+    /// Anything else. These are only visible in scancode mode.
+    Other,
+}
+
+#[derive(Debug)]
+enum KeyEvent {
+    Down {
+        key: Key,
+        scancode: Option<u8>,
+        modifiers: u8,
+    },
+    Up {
+        scancode: Option<u8>,
+        modifiers: u8,
+    },
+    Text(String),
+    /// An ASCII character of pasted text, typed whatever modifiers are held
+    Paste(u8),
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    events: VecDeque<KeyEvent>,
+    /// How many codes are sitting in the keyboard FIFO
+    buffered: usize,
+    /// Pasted text takes more codes in scancode mode
+    mode: KeyboardMode,
+}
+
+/// Shared queue of host key events waiting to be seen by the keyboard.
+///
+/// Frontends push into this while the keyboard device drains it. Printable characters
+/// should be reported with [`KeyboardInput::text`] so the host keyboard layout is
+/// respected, as well as with [`KeyboardInput::key_down`] so scancodes are available.
+#[derive(Clone, Default)]
+pub struct KeyboardInput(Rc<RefCell<Shared>>);
+
+impl KeyboardInput {
+    #[inline]
+    pub fn key_down(&self, key: Key, scancode: Option<u8>, modifiers: u8) {
+        self.0.borrow_mut().events.push_back(KeyEvent::Down {
+            key,
+            scancode,
+            modifiers,
+        });
+    }
+
+    #[inline]
+    pub fn key_up(&self, scancode: Option<u8>, modifiers: u8) {
+        self.0.borrow_mut().events.push_back(KeyEvent::Up {
+            scancode,
+            modifiers,
+        });
+    }
+
+    #[inline]
+    pub fn text(&self, text: &str) {
+        self.0
+            .borrow_mut()
+            .events
+            .push_back(KeyEvent::Text(text.to_string()));
+    }
+
+    /// Types an ASCII character of pasted text (with `\r` for enter), ignoring any
+    /// modifiers the user is holding. Returns false without typing anything when the
+    /// keyboard doesn't have room for it.
+    pub fn paste(&self, code: u8) -> bool {
+        let mode = self.0.borrow().mode;
+        if paste_codes(code, mode).len() > self.room() {
+            return false;
+        }
+        self.0.borrow_mut().events.push_back(KeyEvent::Paste(code));
+        true
+    }
+
+    /// A conservative count of how many more codes can be sent before the keyboard
+    /// would start dropping them.
+    pub fn room(&self) -> usize {
+        let shared = self.0.borrow();
+        let pending: usize = shared
+            .events
+            .iter()
+            .map(|event| match event {
+                KeyEvent::Down { .. } => 1,
+                KeyEvent::Up { .. } => 2,
+                KeyEvent::Text(text) => text.len(),
+                KeyEvent::Paste(code) => paste_codes(*code, shared.mode).len(),
+            })
+            .sum();
+        FIFO_SIZE.saturating_sub(shared.buffered + pending)
+    }
+
+    #[inline]
+    fn pop(&self) -> Option<KeyEvent> {
+        self.0.borrow_mut().events.pop_front()
+    }
+
+    #[inline]
+    fn set_buffered(&self, buffered: usize) {
+        self.0.borrow_mut().buffered = buffered;
+    }
+
+    #[inline]
+    fn set_mode(&self, mode: KeyboardMode) {
+        self.0.borrow_mut().mode = mode;
+    }
+}
+
+/// The make code of the key that types an ASCII character on a US layout, and whether
+/// it needs shift
+fn us_key(code: u8) -> Option<(u8, bool)> {
+    // The keys from 1 (0x1E) to slash (0x38), with 0x28-0x2C being the control keys and
+    // 0x32 the non-US hash key
+    const KEYS: &[u8; 27] = b"1234567890\r\x1B\x08\t -=[]\\\x00;'`,./";
+    const SHIFTED_KEYS: &[u8; 27] = b"!@#$%^&*()\x00\x00\x00\x00\x00_+{}|\x00:\"~<>?";
+    match code {
+        0x00 => None,
+        b'a'..=b'z' => Some((0x04 + (code - b'a'), false)),
+        b'A'..=b'Z' => Some((0x04 + (code - b'A'), true)),
+        _ => {
+            let find = |keys: &[u8]| keys.iter().position(|&key| key == code);
+            match (find(KEYS), find(SHIFTED_KEYS)) {
+                (Some(i), _) => Some((0x1E + i as u8, false)),
+                (_, Some(i)) => Some((0x1E + i as u8, true)),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// The codes a pasted character puts in the FIFO. In scancode mode, the key is pressed
+/// and released, inside a press of left shift if it needs one.
+fn paste_codes(code: u8, mode: KeyboardMode) -> Vec<u8> {
+    const LEFT_SHIFT: u8 = 0xE1;
+    match (mode, us_key(code)) {
+        (KeyboardMode::Ascii, _) => vec![code],
+        (KeyboardMode::Scancode, Some((key, false))) => vec![key, BREAK_PREFIX, key],
+        (KeyboardMode::Scancode, Some((key, true))) => {
+            vec![LEFT_SHIFT, key, BREAK_PREFIX, key, BREAK_PREFIX, LEFT_SHIFT]
+        }
+        (KeyboardMode::Scancode, None) => vec![],
+    }
+}
+
+/// The control code typed by holding ctrl with a printable key
+pub(crate) fn control_code(c: char) -> Option<u8> {
+    match c {
+        // ctrl-space is NUL just like ctrl-@
+        ' ' => Some(0x00),
+        '@'..='~' => Some((c as u8) & 0x1F),
+        _ => None,
+    }
+}
+
+fn ascii_code(key: Key, modifiers: u8) -> Option<u8> {
+    let code = match key {
+        Key::Enter => 0x0D,
+        Key::Backspace => 0x08,
+        Key::Tab => 0x09,
+        Key::Escape => 0x1B,
+        Key::Delete => 0x7F,
+
+        Key::Up => ExtendedCode::UP,
+        Key::Down => ExtendedCode::DOWN,
+        Key::Left => ExtendedCode::LEFT,
+        Key::Right => ExtendedCode::RIGHT,
+        Key::Home => ExtendedCode::HOME,
+        Key::End => ExtendedCode::END,
+        Key::PageUp => ExtendedCode::PAGE_UP,
+        Key::PageDown => ExtendedCode::PAGE_DOWN,
+        Key::Insert => ExtendedCode::INSERT,
+
+        Key::F(n @ 1..=12) => ExtendedCode::F1 + (n - 1),
+
+        // Printable keys arrive as text input, unless ctrl is held
+        Key::Char(c) if (modifiers & Modifier::CTRL) != 0 => return control_code(c),
+
+        _ => return None,
+    };
+    Some(code)
+}
+
+pub struct Keyboard {
+    input: KeyboardInput,
+    mode: KeyboardMode,
+    fifo: VecDeque<u8>,
+    overflow: bool,
+    interrupt_enabled: bool,
+    modifiers: u8,
+}
+
+impl Keyboard {
+    pub fn new(input: KeyboardInput, mode: KeyboardMode) -> Self {
+        input.set_mode(mode);
+        Self {
+            input,
+            mode,
+            fifo: VecDeque::with_capacity(FIFO_SIZE),
+            overflow: false,
+            interrupt_enabled: false,
+            modifiers: 0,
+        }
+    }
+
+    /// Codes that belong together (like a break sequence) are either all queued or all dropped
+    fn push(&mut self, codes: &[u8]) {
+        if (self.fifo.len() + codes.len()) > FIFO_SIZE {
+            self.overflow = true;
+        } else {
+            self.fifo.extend(codes);
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = 0;
+        if !self.fifo.is_empty() {
+            status |= Status::DATA_READY;
+        }
+        if self.overflow {
+            status |= Status::OVERFLOW;
+        }
+        if self.mode == KeyboardMode::Scancode {
+            status |= Control::SCANCODE;
+        }
+        if self.interrupt_enabled {
+            status |= Control::INTERRUPT_ENABLE;
+        }
+        status
+    }
+
+    fn write_control(&mut self, data: u8) {
+        let mode = if (data & Control::SCANCODE) != 0 {
+            KeyboardMode::Scancode
+        } else {
+            KeyboardMode::Ascii
+        };
+        // Codes from the old mode would be nonsense in the new one
+        if mode != self.mode {
+            self.mode = mode;
+            self.input.set_mode(mode);
+            self.fifo.clear();
+            self.input.set_buffered(0);
+        }
+        self.interrupt_enabled = (data & Control::INTERRUPT_ENABLE) != 0;
+    }
+
+    fn drain_input(&mut self) {
+        while let Some(event) = self.input.pop() {
+            match event {
+                KeyEvent::Down {
+                    key,
+                    scancode,
+                    modifiers,
+                } => {
+                    self.modifiers = modifiers;
+                    let code = match self.mode {
+                        KeyboardMode::Ascii => ascii_code(key, modifiers),
+                        KeyboardMode::Scancode => scancode,
+                    };
+                    if let Some(code) = code {
+                        self.push(&[code]);
+                    }
+                }
+
+                KeyEvent::Up {
+                    scancode,
+                    modifiers,
+                } => {
+                    self.modifiers = modifiers;
+                    if let (KeyboardMode::Scancode, Some(code)) = (self.mode, scancode) {
+                        self.push(&[BREAK_PREFIX, code]);
+                    }
+                }
+
+                KeyEvent::Paste(code) => {
+                    let codes = paste_codes(code, self.mode);
+                    self.push(&codes);
+                }
+
+                KeyEvent::Text(text) => {
+                    // Ctrl-combinations were already handled as key presses
+                    if self.mode == KeyboardMode::Ascii && (self.modifiers & Modifier::CTRL) == 0 {
+                        for code in text.bytes().filter(u8::is_ascii) {
+                            self.push(&[code]);
+                        }
+                    }
+                }
+            }
+        }
+        self.input.set_buffered(self.fifo.len());
+    }
+}
+
+impl Device for Keyboard {
+    fn tick(&mut self, _: &mut dyn DeviceBus) {
+        self.drain_input();
+    }
+
+    fn read(&mut self, port: u16) -> u8 {
+        self.drain_input();
+        match port & 0x03 {
+            // Data
+            0 => {
+                let data = self.fifo.pop_front().unwrap_or_default();
+                self.input.set_buffered(self.fifo.len());
+                data
+            }
+
+            // Status (reading clears the overflow)
+            1 => {
+                let status = self.status();
+                self.overflow = false;
+                status
+            }
+
+            // Modifier state
+            2 => self.modifiers,
+
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, data: u8) {
+        match port & 0x03 {
+            // TODO: This is a basic output for debugging. Obviously in reality
+            //   you can't write to your keyboard :-P
+            0 => io::stdout().write_all(&[data]).unwrap(),
+
+            // Control
+            1 => self.write_control(data),
+
+            _ => {}
+        }
+    }
+
+    fn interrupting(&self) -> bool {
+        self.interrupt_enabled && !self.fifo.is_empty()
+    }
+}
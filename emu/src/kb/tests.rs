@@ -0,0 +1,126 @@
+use super::*;
+use crate::bus::NullBus;
+
+#[test]
+fn ascii_translation() {
+    let input = KeyboardInput::default();
+    let mut kb = Keyboard::new(input.clone(), KeyboardMode::Ascii);
+
+    input.key_down(Key::Char('h'), Some(0x0B), 0);
+    input.text("h");
+    input.key_down(Key::Enter, Some(0x28), 0);
+    input.key_down(Key::Up, Some(0x52), 0);
+    input.key_down(Key::F(3), Some(0x3C), 0);
+    input.key_down(Key::Char('c'), Some(0x06), Modifier::CTRL);
+    kb.tick(&mut NullBus);
+
+    assert_eq!(kb.read(0), b'h');
+    assert_eq!(kb.read(0), 0x0D);
+    assert_eq!(kb.read(0), ExtendedCode::UP);
+    assert_eq!(kb.read(0), ExtendedCode::F1 + 2);
+    assert_eq!(kb.read(0), 0x03);
+    assert_eq!(kb.read(2), Modifier::CTRL);
+    assert_eq!(kb.read(1) & Status::DATA_READY, 0);
+}
+
+#[test]
+fn scancodes() {
+    let input = KeyboardInput::default();
+    let mut kb = Keyboard::new(input.clone(), KeyboardMode::Ascii);
+    kb.write(1, Control::SCANCODE);
+
+    input.key_down(Key::Other, Some(0xE1), Modifier::SHIFT);
+    input.key_down(Key::Char('a'), Some(0x04), Modifier::SHIFT);
+    input.text("A");
+    input.key_up(Some(0x04), Modifier::SHIFT);
+    input.key_up(Some(0xE1), 0);
+
+    assert_eq!(kb.read(0), 0xE1);
+    assert_eq!(kb.read(0), 0x04);
+    assert_eq!(kb.read(0), BREAK_PREFIX);
+    assert_eq!(kb.read(0), 0x04);
+    assert_eq!(kb.read(0), BREAK_PREFIX);
+    assert_eq!(kb.read(0), 0xE1);
+    assert_eq!(kb.read(2), 0);
+}
+
+#[test]
+fn overflow_and_interrupts() {
+    let input = KeyboardInput::default();
+    let mut kb = Keyboard::new(input.clone(), KeyboardMode::Ascii);
+    kb.write(1, Control::INTERRUPT_ENABLE);
+    assert!(!kb.interrupting());
+
+    input.text(&"x".repeat(FIFO_SIZE + 1));
+    kb.tick(&mut NullBus);
+    assert!(kb.interrupting());
+
+    let status = kb.read(1);
+    assert_ne!(status & Status::DATA_READY, 0);
+    assert_ne!(status & Status::OVERFLOW, 0);
+    assert_eq!(kb.read(1) & Status::OVERFLOW, 0);
+
+    for _ in 0..FIFO_SIZE {
+        assert_eq!(kb.read(0), b'x');
+    }
+    assert!(!kb.interrupting());
+}
+
+#[test]
+fn room() {
+    let input = KeyboardInput::default();
+    let mut kb = Keyboard::new(input.clone(), KeyboardMode::Ascii);
+    assert_eq!(input.room(), FIFO_SIZE);
+
+    input.text("abc");
+    input.key_down(Key::Enter, None, 0);
+    assert_eq!(input.room(), FIFO_SIZE - 4);
+
+    kb.tick(&mut NullBus);
+    assert_eq!(input.room(), FIFO_SIZE - 4);
+
+    kb.read(0);
+    assert_eq!(input.room(), FIFO_SIZE - 3);
+}
+
+#[test]
+fn paste_ignores_modifiers() {
+    let input = KeyboardInput::default();
+    let mut kb = Keyboard::new(input.clone(), KeyboardMode::Ascii);
+
+    // The paste hotkey is still held
+    input.key_down(Key::Other, Some(0xE0), Modifier::CTRL | Modifier::SHIFT);
+    assert!(input.paste(b'h'));
+    assert!(input.paste(b'\r'));
+    kb.tick(&mut NullBus);
+
+    assert_eq!(kb.read(0), b'h');
+    assert_eq!(kb.read(0), 0x0D);
+    assert_eq!(kb.read(1) & Status::DATA_READY, 0);
+}
+
+#[test]
+fn paste_scancodes() {
+    let input = KeyboardInput::default();
+    let mut kb = Keyboard::new(input.clone(), KeyboardMode::Scancode);
+
+    assert!(input.paste(b'1'));
+    assert!(input.paste(b'?'));
+    assert_eq!(input.room(), FIFO_SIZE - 9);
+    kb.tick(&mut NullBus);
+
+    for code in [0x1E, BREAK_PREFIX, 0x1E] {
+        assert_eq!(kb.read(0), code);
+    }
+    for code in [0xE1, 0x38, BREAK_PREFIX, 0x38, BREAK_PREFIX, 0xE1] {
+        assert_eq!(kb.read(0), code);
+    }
+    assert_eq!(kb.read(1) & Status::DATA_READY, 0);
+
+    // Nothing is typed without room for all of it
+    for _ in 0..(FIFO_SIZE / 6) {
+        assert!(input.paste(b'A'));
+    }
+    assert!(!input.paste(b'A'));
+    assert!(input.paste(b'a'));
+}
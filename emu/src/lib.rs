@@ -5,12 +5,22 @@
 mod ata;
 mod bus;
 mod cpu;
+mod ctc;
 mod dma;
+mod kb;
+mod mmu;
+mod pic;
 mod ser;
+mod sio;
 mod sys;
+mod term;
 mod vdc;
 
 pub use ata::{CardBus, MemoryMap};
 pub use bus::{Device, DeviceBus};
-pub use sys::System;
+pub use kb::{ExtendedCode, Key, Keyboard, KeyboardInput, KeyboardMode, Modifier, BREAK_PREFIX};
+pub use ser::{SerialHost, Uart};
+pub use sio::Sio;
+pub use sys::{MemoryLayout, System};
+pub use term::Terminal;
 pub use vdc::Framebuffer;
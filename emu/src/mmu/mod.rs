@@ -0,0 +1,171 @@
+//! Possum MMU
+//!
+//! An optional extension of the bank registers for the multitasking OS. While enabled,
+//! it replaces the banked windows (and the common area) with a page table that maps each
+//! 4K page of the address space to any 4K frame of RAM. Pages can be made read-only or
+//! shut off entirely.
+//!
+//! A CPU access that breaks the protection of its page is dropped (reads see `0x00`)
+//! and latches the faulting address and access type. The fault raises NMI, which the
+//! CPU takes at the end of the faulting instruction. Only the first fault is latched
+//! until software clears it. The DMA sees the mapping while it follows the CPU's banks,
+//! but its accesses are never checked.
+//!
+//! The page table is reached through a selected page, much like the registers of the
+//! VDC:
+//!
+//! | Port | |
+//! |------|-|
+//! | 0    | Control. Bit 0 enables the MMU |
+//! | 1    | Selects the page for the next two ports |
+//! | 2    | Low byte of the selected page's frame |
+//! | 3    | High 4 bits of the frame, and the protection bits |
+//! | 4    | Fault status. Writing clears the fault |
+//! | 5    | Low byte of the faulting address |
+//! | 6    | High byte of the faulting address |
+
+#[cfg(test)]
+mod tests;
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+const PAGE_COUNT: usize = 0x10;
+
+struct Port;
+impl Port {
+    const CONTROL: u16 = 0x00;
+    const PAGE: u16 = 0x01;
+    const FRAME: u16 = 0x02;
+    const FLAGS: u16 = 0x03;
+    const FAULT: u16 = 0x04;
+    const FAULT_ADDR_LO: u16 = 0x05;
+    const FAULT_ADDR_HI: u16 = 0x06;
+}
+
+struct Control;
+impl Control {
+    const ENABLE: u8 = 0x01;
+}
+
+/// The high byte of a page table entry
+pub struct PageFlags;
+impl PageFlags {
+    const FRAME_HI: u8 = 0x0F;
+
+    /// Writes fault
+    pub const READ_ONLY: u8 = 0x40;
+
+    /// Reads and writes fault
+    pub const NO_ACCESS: u8 = 0x80;
+}
+
+/// The fault status register
+pub struct Fault;
+impl Fault {
+    /// The faulting access was a write (otherwise a read)
+    pub const WRITE: u8 = 0x01;
+
+    /// A fault is latched
+    pub const FAULTED: u8 = 0x80;
+}
+
+pub struct Mmu {
+    control: u8,
+    page: usize,
+    frames: [u16; PAGE_COUNT],
+    flags: [u8; PAGE_COUNT],
+    frame_count: usize,
+    fault: u8,
+    fault_addr: u16,
+    nmi: bool,
+}
+
+impl Mmu {
+    /// Out of reset the MMU is disabled and the pages map the first 64K of RAM in order
+    pub fn new(ram_size: usize) -> Self {
+        let mut frames = [0; PAGE_COUNT];
+        for (page, frame) in frames.iter_mut().enumerate() {
+            *frame = page as u16;
+        }
+        Self {
+            control: 0,
+            page: 0,
+            frames,
+            flags: [0; PAGE_COUNT],
+            frame_count: ram_size / PAGE_SIZE,
+            fault: 0,
+            fault_addr: 0,
+            nmi: false,
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        (self.control & Control::ENABLE) != 0
+    }
+
+    /// Where in RAM an address is mapped. Frames past the end of RAM wrap around.
+    #[inline]
+    pub fn ram_addr(&self, addr: u16) -> usize {
+        let frame = (self.frames[(addr as usize) / PAGE_SIZE] as usize) % self.frame_count;
+        (frame * PAGE_SIZE) + ((addr as usize) % PAGE_SIZE)
+    }
+
+    /// Where in RAM a CPU access goes, or `None` if it faults
+    pub fn access(&mut self, addr: u16, write: bool) -> Option<usize> {
+        let flags = self.flags[(addr as usize) / PAGE_SIZE];
+        let faulted = if write {
+            (flags & (PageFlags::READ_ONLY | PageFlags::NO_ACCESS)) != 0
+        } else {
+            (flags & PageFlags::NO_ACCESS) != 0
+        };
+        if !faulted {
+            return Some(self.ram_addr(addr));
+        }
+        if (self.fault & Fault::FAULTED) == 0 {
+            self.fault = Fault::FAULTED | if write { Fault::WRITE } else { 0 };
+            self.fault_addr = addr;
+            self.nmi = true;
+        }
+        None
+    }
+
+    /// The NMI line is edge triggered, so each fault is only seen once
+    #[inline]
+    pub fn take_nmi(&mut self) -> bool {
+        let nmi = self.nmi;
+        self.nmi = false;
+        nmi
+    }
+
+    pub fn read(&self, port: u16) -> u8 {
+        match port & 0x0F {
+            Port::CONTROL => self.control,
+            Port::PAGE => self.page as u8,
+            Port::FRAME => self.frames[self.page] as u8,
+            Port::FLAGS => self.flags[self.page] | ((self.frames[self.page] >> 8) as u8),
+            Port::FAULT => self.fault,
+            Port::FAULT_ADDR_LO => self.fault_addr as u8,
+            Port::FAULT_ADDR_HI => (self.fault_addr >> 8) as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, port: u16, data: u8) {
+        match port & 0x0F {
+            Port::CONTROL => self.control = data & Control::ENABLE,
+            Port::PAGE => self.page = (data as usize) % PAGE_COUNT,
+            Port::FRAME => {
+                let frame = &mut self.frames[self.page];
+                *frame = (*frame & 0xFF00) | (data as u16);
+            }
+            Port::FLAGS => {
+                let frame = &mut self.frames[self.page];
+                *frame = (((data & PageFlags::FRAME_HI) as u16) << 8) | (*frame & 0x00FF);
+                self.flags[self.page] = data & (PageFlags::READ_ONLY | PageFlags::NO_ACCESS);
+            }
+            Port::FAULT => self.fault = 0,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use super::*;
+
+/// 256K of RAM
+fn mmu() -> Mmu {
+    let mut mmu = Mmu::new(0x40000);
+    mmu.write(Port::CONTROL, Control::ENABLE);
+    mmu
+}
+
+fn map(mmu: &mut Mmu, page: u8, frame: u16, flags: u8) {
+    mmu.write(Port::PAGE, page);
+    mmu.write(Port::FRAME, frame as u8);
+    mmu.write(Port::FLAGS, flags | ((frame >> 8) as u8));
+}
+
+#[test]
+fn identity_from_reset() {
+    let mmu = Mmu::new(0x40000);
+    assert!(!mmu.enabled());
+    assert_eq!(mmu.ram_addr(0x0000), 0x0000);
+    assert_eq!(mmu.ram_addr(0x5123), 0x5123);
+    assert_eq!(mmu.ram_addr(0xFFFF), 0xFFFF);
+}
+
+#[test]
+fn mapping() {
+    let mut mmu = mmu();
+    map(&mut mmu, 0x0, 0x3F, 0);
+    map(&mut mmu, 0xF, 0x20, 0);
+    assert_eq!(mmu.access(0x0123, false), Some(0x3F123));
+    assert_eq!(mmu.access(0xF456, true), Some(0x20456));
+    assert_eq!(mmu.access(0x1000, true), Some(0x01000));
+
+    // Frames past the end of RAM wrap around
+    map(&mut mmu, 0x1, 0x140, 0);
+    assert_eq!(mmu.ram_addr(0x1000), 0x00000);
+
+    mmu.write(Port::PAGE, 0x1);
+    assert_eq!(mmu.read(Port::FRAME), 0x40);
+    assert_eq!(mmu.read(Port::FLAGS), 0x01);
+}
+
+#[test]
+fn read_only() {
+    let mut mmu = mmu();
+    map(&mut mmu, 0x2, 0x02, PageFlags::READ_ONLY);
+    assert_eq!(mmu.access(0x2000, false), Some(0x2000));
+    assert!(!mmu.take_nmi());
+
+    assert_eq!(mmu.access(0x2345, true), None);
+    assert!(mmu.take_nmi());
+    assert!(!mmu.take_nmi());
+    assert_eq!(mmu.read(Port::FAULT), Fault::FAULTED | Fault::WRITE);
+    assert_eq!(mmu.read(Port::FAULT_ADDR_LO), 0x45);
+    assert_eq!(mmu.read(Port::FAULT_ADDR_HI), 0x23);
+}
+
+#[test]
+fn no_access() {
+    let mut mmu = mmu();
+    map(&mut mmu, 0x8, 0x08, PageFlags::NO_ACCESS);
+    assert_eq!(mmu.access(0x8001, false), None);
+    assert!(mmu.take_nmi());
+    assert_eq!(mmu.read(Port::FAULT), Fault::FAULTED);
+
+    // Only the first fault is latched until it is cleared
+    assert_eq!(mmu.access(0x8002, true), None);
+    assert!(!mmu.take_nmi());
+    assert_eq!(mmu.read(Port::FAULT_ADDR_LO), 0x01);
+
+    mmu.write(Port::FAULT, 0);
+    assert_eq!(mmu.read(Port::FAULT), 0);
+    assert_eq!(mmu.access(0x8002, true), None);
+    assert!(mmu.take_nmi());
+    assert_eq!(mmu.read(Port::FAULT), Fault::FAULTED | Fault::WRITE);
+}
+
+#[test]
+fn disabled() {
+    let mut mmu = mmu();
+    map(&mut mmu, 0x0, 0x10, PageFlags::NO_ACCESS);
+    mmu.write(Port::CONTROL, 0);
+    assert!(!mmu.enabled());
+    assert_eq!(mmu.read(Port::CONTROL), 0);
+}
@@ -0,0 +1,125 @@
+//! Programmable interrupt controller
+//!
+//! Modeled on the 8259 in its polled mode. Each device drives one of the eight request
+//! lines. The controller asserts INT while an unmasked request outranks everything
+//! under service. Software reads the IC port to acknowledge the request, which returns
+//! the number of its line (or `0xFF` if there is nothing to acknowledge) and puts it
+//! under service until an end of interrupt command.
+//!
+//! Priority rotates around the lines starting from a configurable highest line. The
+//! controller takes no part in the CPU's acknowledge cycle, so mode 2 vectors still
+//! come from the devices themselves.
+
+#[cfg(test)]
+mod tests;
+
+struct Command;
+impl Command {
+    /// Ends the highest priority interrupt under service
+    const EOI: u8 = 0x20;
+
+    /// Ends the interrupt of the line in the low bits
+    const SPECIFIC_EOI: u8 = 0x60;
+
+    const LINE: u8 = 0x07;
+}
+
+/// Read when nothing is waiting to be acknowledged
+const NO_INTERRUPT: u8 = 0xFF;
+
+#[derive(Default)]
+pub struct Pic {
+    requests: u8,
+    mask: u8,
+    in_service: u8,
+    priority: u8,
+}
+
+impl Pic {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Latches the state of the request lines
+    #[inline]
+    pub fn set_requests(&mut self, requests: u8) {
+        self.requests = requests;
+    }
+
+    #[inline]
+    pub fn requests(&self) -> u8 {
+        self.requests
+    }
+
+    /// Set bits mask their lines
+    #[inline]
+    pub fn set_mask(&mut self, mask: u8) {
+        self.mask = mask;
+    }
+
+    #[inline]
+    pub fn mask(&self) -> u8 {
+        self.mask
+    }
+
+    #[inline]
+    pub fn in_service(&self) -> u8 {
+        self.in_service
+    }
+
+    /// Makes `line` the highest priority, followed by the lines after it
+    #[inline]
+    pub fn set_priority(&mut self, line: u8) {
+        self.priority = line & Command::LINE;
+    }
+
+    #[inline]
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// The line with the highest priority out of a set, if any
+    fn highest(&self, lines: u8) -> Option<u8> {
+        (0..8)
+            .map(|rank| (self.priority + rank) & Command::LINE)
+            .find(|line| (lines & (1 << line)) != 0)
+    }
+
+    /// The unmasked request that outranks everything under service
+    pub fn active(&self) -> Option<u8> {
+        let line = self.highest(self.requests & !self.mask)?;
+        match self.highest(self.in_service | (1 << line)) {
+            Some(highest) if highest == line && (self.in_service & (1 << line)) == 0 => Some(line),
+            _ => None,
+        }
+    }
+
+    /// INT to the CPU
+    #[inline]
+    pub fn interrupting(&self) -> bool {
+        self.active().is_some()
+    }
+
+    /// Puts the active request under service and returns its line
+    pub fn acknowledge(&mut self) -> u8 {
+        match self.active() {
+            Some(line) => {
+                self.in_service |= 1 << line;
+                line
+            }
+            None => NO_INTERRUPT,
+        }
+    }
+
+    /// Unknown commands are ignored
+    pub fn command(&mut self, data: u8) {
+        if data == Command::EOI {
+            if let Some(line) = self.highest(self.in_service) {
+                self.in_service &= !(1 << line);
+            }
+        } else if (data & !Command::LINE) == Command::SPECIFIC_EOI {
+            self.in_service &= !(1 << (data & Command::LINE));
+        }
+    }
+}
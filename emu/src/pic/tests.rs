@@ -0,0 +1,91 @@
+use super::*;
+
+#[test]
+fn idle() {
+    let mut pic = Pic::new();
+    assert!(!pic.interrupting());
+    assert_eq!(pic.acknowledge(), NO_INTERRUPT);
+    assert_eq!(pic.in_service(), 0);
+}
+
+#[test]
+fn acknowledge() {
+    let mut pic = Pic::new();
+    pic.set_requests(0b0001_1000);
+    assert!(pic.interrupting());
+    assert_eq!(pic.acknowledge(), 3);
+    assert_eq!(pic.in_service(), 0b0000_1000);
+
+    // Lower priority requests wait for the end of interrupt
+    assert!(!pic.interrupting());
+    assert_eq!(pic.acknowledge(), NO_INTERRUPT);
+
+    // Even while the device is still requesting
+    pic.command(Command::EOI);
+    assert_eq!(pic.in_service(), 0);
+    assert_eq!(pic.acknowledge(), 3);
+    pic.command(Command::EOI);
+
+    pic.set_requests(0b0001_0000);
+    assert_eq!(pic.acknowledge(), 4);
+}
+
+#[test]
+fn nesting() {
+    let mut pic = Pic::new();
+    pic.set_requests(0b0001_0000);
+    assert_eq!(pic.acknowledge(), 4);
+
+    pic.set_requests(0b0001_0001);
+    assert!(pic.interrupting());
+    assert_eq!(pic.acknowledge(), 0);
+    assert_eq!(pic.in_service(), 0b0001_0001);
+
+    // The end of interrupt goes to the highest priority
+    pic.command(Command::EOI);
+    assert_eq!(pic.in_service(), 0b0001_0000);
+    pic.command(Command::SPECIFIC_EOI | 4);
+    assert_eq!(pic.in_service(), 0);
+}
+
+#[test]
+fn mask() {
+    let mut pic = Pic::new();
+    pic.set_mask(0b0000_0001);
+    pic.set_requests(0b0000_0001);
+    assert!(!pic.interrupting());
+    assert_eq!(pic.requests(), 0b0000_0001);
+
+    pic.set_requests(0b0000_0101);
+    assert_eq!(pic.acknowledge(), 2);
+
+    pic.set_mask(0);
+    assert_eq!(pic.acknowledge(), 0);
+}
+
+#[test]
+fn priority() {
+    let mut pic = Pic::new();
+    pic.set_priority(3);
+    pic.set_requests(0b0010_0010);
+    assert_eq!(pic.acknowledge(), 5);
+
+    // Line 1 is now below line 5
+    assert!(!pic.interrupting());
+    pic.command(Command::EOI);
+    pic.set_requests(0b0000_0010);
+    assert_eq!(pic.acknowledge(), 1);
+
+    pic.set_priority(0x0A);
+    assert_eq!(pic.priority(), 2);
+}
+
+#[test]
+fn unknown_command() {
+    let mut pic = Pic::new();
+    pic.set_requests(0b0000_0001);
+    pic.acknowledge();
+    pic.command(0x00);
+    pic.command(0x21);
+    assert_eq!(pic.in_service(), 0b0000_0001);
+}
@@ -1,4 +1,16 @@
 //! 16550A UART Emulation
+//!
+//! Characters take as long to send and receive as they would on a real line. The time
+//! is derived from the UART's input clock, the divisor latch and the frame format.
+//!
+//! The modem control outputs are wired to the host through [`SerialHost`]. With flow
+//! control enabled, the host is held off and nothing more is received from it while RTS
+//! is deasserted. In loopback mode the host is disconnected entirely: transmitted
+//! characters are received again and the modem control outputs drive the modem status
+//! inputs.
+
+#[cfg(test)]
+mod tests;
 
 use std::{
     collections::VecDeque,
@@ -33,63 +45,489 @@ impl InterruptSource {
     const RX_READY: u8 = 0x04;
 
     const RX_STATUS: u8 = 0x06;
+
+    /// Data has been sitting in the rx FIFO for a while
+    const RX_TIMEOUT: u8 = 0x0C;
+
+    /// Always set in the IIR while the FIFOs are enabled
+    const FIFOS_ENABLED: u8 = 0xC0;
+}
+
+struct FifoControl;
+impl FifoControl {
+    const ENABLE: u8 = 0x01;
+
+    /// Self-clearing
+    const CLEAR_RX: u8 = 0x02;
+
+    /// Self-clearing
+    const CLEAR_TX: u8 = 0x04;
+
+    const DMA_MODE: u8 = 0x08;
+
+    const RX_TRIGGER: u8 = 0xC0;
+}
+
+struct LineControl;
+impl LineControl {
+    /// Number of data bits, minus 5
+    const WORD_LENGTH: u8 = 0x03;
+
+    /// 1 stop bit when clear. 2 stop bits when set (or 1.5 with 5 data bits)
+    const STOP_BITS: u8 = 0x04;
+
+    const PARITY_ENABLE: u8 = 0x08;
+
+    /// Divisor latch access bit
+    const DLAB: u8 = 0x80;
+}
+
+struct ModemControl;
+impl ModemControl {
+    const DTR: u8 = 0x01;
+
+    const RTS: u8 = 0x02;
+
+    const OUT1: u8 = 0x04;
+
+    const OUT2: u8 = 0x08;
+
+    const LOOPBACK: u8 = 0x10;
+}
+
+struct LineStatus;
+impl LineStatus {
+    const DATA_READY: u8 = 0x01;
+
+    const OVERRUN_ERROR: u8 = 0x02;
+
+    const PARITY_ERROR: u8 = 0x04;
+
+    const FRAMING_ERROR: u8 = 0x08;
+
+    const BREAK_INTERRUPT: u8 = 0x10;
+
+    /// The tx holding register (or FIFO) is empty
+    const THR_EMPTY: u8 = 0x20;
+
+    /// Both the tx holding and shift registers are empty
+    const TX_EMPTY: u8 = 0x40;
+
+    /// The errors that are cleared by reading the LSR
+    const ERRORS: u8 =
+        Self::OVERRUN_ERROR | Self::PARITY_ERROR | Self::FRAMING_ERROR | Self::BREAK_INTERRUPT;
+}
+
+struct ModemStatus;
+impl ModemStatus {
+    const DELTA_CTS: u8 = 0x01;
+
+    const DELTA_DSR: u8 = 0x02;
+
+    /// RI went from asserted to deasserted
+    const TRAILING_RI: u8 = 0x04;
+
+    const DELTA_DCD: u8 = 0x08;
+
+    /// The deltas are cleared by reading the MSR
+    const DELTAS: u8 = 0x0F;
+
+    const CTS: u8 = 0x10;
+
+    const DSR: u8 = 0x20;
+
+    const RI: u8 = 0x40;
+
+    const DCD: u8 = 0x80;
+
+    const INPUTS: u8 = 0xF0;
+}
+
+/// The host end of a serial line. Reads and writes must never block.
+/// A read that returns nothing means nothing has arrived yet.
+pub trait SerialHost: Read + Write {
+    /// The host is ready to take data. The guest sees this as CTS.
+    fn clear_to_send(&mut self) -> bool {
+        true
+    }
+
+    /// Something is attached to the host end. The guest sees this as DSR and DCD.
+    fn connected(&mut self) -> bool {
+        true
+    }
 }
 
+impl<T> SerialHost for Box<T>
+where
+    T: SerialHost + ?Sized,
+{
+    fn clear_to_send(&mut self) -> bool {
+        (**self).clear_to_send()
+    }
+
+    fn connected(&mut self) -> bool {
+        (**self).connected()
+    }
+}
+
+/// The crystal driving the UART. Standard baud rates divide evenly into it.
+const UART_CLOCK_HZ: usize = 1_843_200;
+
+/// Each bit on the line lasts this many ticks of the baud rate generator
+const CLOCKS_PER_BIT: usize = 16;
+
+const FIFO_SIZE: usize = 16;
+
+/// Character times the rx FIFO can sit without being read (or receiving) before timing out
+const RX_TIMEOUT_CHARS: usize = 4;
+
 pub struct Uart<T> {
     handle: T,
+    /// The rate that the UART is ticked at
+    system_clock_hz: usize,
+    /// Fractional UART clocks carried between ticks
+    clock_accumulator: usize,
+    /// Bytes read from the host that haven't made it to the receiver yet
+    host_rx: VecDeque<u8>,
     tx_fifo: VecDeque<u8>,
+    tx_shift: Option<u8>,
+    tx_clocks: usize,
     rx_fifo: VecDeque<u8>,
+    rx_shift: Option<u8>,
+    rx_clocks: usize,
+    rx_idle_chars: usize,
+    tx_empty_interrupt: bool,
     interrupt_enable: u8,
-    interrupt_status: u8,
     fifo_control: u8,
     line_control: u8,
     modem_control: u8,
     line_status: u8,
     modem_status: u8,
     divisor_latch: u16,
+    scratch: u8,
+    /// RTS holds off the host
+    flow_control: bool,
 }
 
 impl<T> Uart<T> {
+    /// The UART expects to be ticked at `system_clock_hz`
     #[inline]
-    pub fn new(handle: T) -> Self {
+    pub fn new(handle: T, system_clock_hz: usize) -> Self {
         Self {
             handle,
-            tx_fifo: VecDeque::with_capacity(16),
-            rx_fifo: VecDeque::with_capacity(16),
+            system_clock_hz,
+            clock_accumulator: 0,
+            host_rx: VecDeque::new(),
+            tx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            tx_shift: None,
+            tx_clocks: 0,
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            rx_shift: None,
+            rx_clocks: 0,
+            rx_idle_chars: 0,
+            tx_empty_interrupt: false,
             interrupt_enable: 0,
-            interrupt_status: 0,
             fifo_control: 0,
             line_control: 0,
             modem_control: 0,
             line_status: 0,
-            modem_status: 0,
+            // The host is always "connected"
+            modem_status: ModemStatus::CTS | ModemStatus::DSR | ModemStatus::DCD,
             divisor_latch: 0,
+            scratch: 0,
+            flow_control: false,
+        }
+    }
+
+    /// Only hold off the host while RTS is deasserted if the guest is known to raise it
+    #[inline]
+    pub fn set_flow_control(&mut self, enabled: bool) {
+        self.flow_control = enabled;
+    }
+
+    #[inline]
+    fn fifos_enabled(&self) -> bool {
+        (self.fifo_control & FifoControl::ENABLE) != 0
+    }
+
+    /// Without FIFOs, there is only the single holding register
+    #[inline]
+    fn fifo_size(&self) -> usize {
+        if self.fifos_enabled() {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    /// How many UART clocks it takes to send or receive a whole character
+    fn char_clocks(&self) -> usize {
+        // A divisor of 0 wraps the baud rate counter around
+        let divisor = match self.divisor_latch {
+            0 => 0x10000,
+            divisor => divisor as usize,
+        };
+        let data_bits = ((self.line_control & LineControl::WORD_LENGTH) as usize) + 5;
+        let parity_bits = ((self.line_control & LineControl::PARITY_ENABLE) != 0) as usize;
+
+        // Counted in half bits since there can be 1.5 stop bits
+        let stop_half_bits = match (self.line_control & LineControl::STOP_BITS, data_bits) {
+            (0, _) => 2,
+            (_, 5) => 3,
+            _ => 4,
+        };
+        let frame_half_bits = (2 * (1 + data_bits + parity_bits)) + stop_half_bits;
+
+        (divisor * CLOCKS_PER_BIT * frame_half_bits) / 2
+    }
+
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifos_enabled() {
+            return 1;
+        }
+        match (self.fifo_control & FifoControl::RX_TRIGGER) >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            3 => 14,
+            _ => unreachable!(),
+        }
+    }
+
+    #[inline]
+    fn loopback(&self) -> bool {
+        (self.modem_control & ModemControl::LOOPBACK) != 0
+    }
+
+    /// Latches new modem status inputs, noting which of them changed
+    fn set_modem_inputs(&mut self, inputs: u8) {
+        let changed = (self.modem_status ^ inputs) & ModemStatus::INPUTS;
+        let mut deltas = 0;
+        if (changed & ModemStatus::CTS) != 0 {
+            deltas |= ModemStatus::DELTA_CTS;
+        }
+        if (changed & ModemStatus::DSR) != 0 {
+            deltas |= ModemStatus::DELTA_DSR;
+        }
+        if (changed & self.modem_status & ModemStatus::RI) != 0 {
+            deltas |= ModemStatus::TRAILING_RI;
+        }
+        if (changed & ModemStatus::DCD) != 0 {
+            deltas |= ModemStatus::DELTA_DCD;
+        }
+        self.modem_status = (self.modem_status & ModemStatus::DELTAS) | deltas | inputs;
+    }
+
+    /// In loopback mode the outputs are wired straight to the inputs
+    fn loopback_modem_inputs(&self) -> u8 {
+        let mut inputs = 0;
+        if (self.modem_control & ModemControl::RTS) != 0 {
+            inputs |= ModemStatus::CTS;
+        }
+        if (self.modem_control & ModemControl::DTR) != 0 {
+            inputs |= ModemStatus::DSR;
+        }
+        if (self.modem_control & ModemControl::OUT1) != 0 {
+            inputs |= ModemStatus::RI;
+        }
+        if (self.modem_control & ModemControl::OUT2) != 0 {
+            inputs |= ModemStatus::DCD;
+        }
+        inputs
+    }
+
+    /// A whole character made it into the receiver
+    fn receive(&mut self, data: u8) {
+        if self.rx_fifo.len() < self.fifo_size() {
+            self.rx_fifo.push_back(data);
+        } else {
+            self.line_status |= LineStatus::OVERRUN_ERROR;
+            // Without FIFOs the new character overwrites the held one. With them,
+            // the character in the shift register is lost instead.
+            if !self.fifos_enabled() {
+                self.rx_fifo.clear();
+                self.rx_fifo.push_back(data);
+            }
+        }
+        self.rx_idle_chars = 0;
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut status = self.line_status;
+        if !self.rx_fifo.is_empty() {
+            status |= LineStatus::DATA_READY;
+        }
+        if self.tx_fifo.is_empty() {
+            status |= LineStatus::THR_EMPTY;
+            if self.tx_shift.is_none() {
+                status |= LineStatus::TX_EMPTY;
+            }
         }
+        status
+    }
+
+    /// Only the highest priority pending interrupt is ever identified
+    fn interrupt_source(&self) -> u8 {
+        let enabled = |mask| (self.interrupt_enable & mask) != 0;
+
+        if enabled(InterruptEnable::RX_STATUS) && (self.line_status & LineStatus::ERRORS) != 0 {
+            return InterruptSource::RX_STATUS;
+        }
+        if enabled(InterruptEnable::RX_READY) {
+            if self.rx_fifo.len() >= self.rx_trigger_level() {
+                return InterruptSource::RX_READY;
+            }
+            if self.fifos_enabled()
+                && !self.rx_fifo.is_empty()
+                && self.rx_idle_chars >= RX_TIMEOUT_CHARS
+            {
+                return InterruptSource::RX_TIMEOUT;
+            }
+        }
+        if enabled(InterruptEnable::TX_EMPTY) && self.tx_empty_interrupt {
+            return InterruptSource::TX_EMPTY;
+        }
+        if enabled(InterruptEnable::MODEM_STATUS) && (self.modem_status & ModemStatus::DELTAS) != 0
+        {
+            return InterruptSource::MODEM_STATUS;
+        }
+        InterruptSource::NONE
+    }
+
+    fn write_fifo_control(&mut self, data: u8) {
+        // Toggling the FIFOs resets them
+        if ((data ^ self.fifo_control) & FifoControl::ENABLE) != 0 {
+            self.rx_fifo.clear();
+            self.tx_fifo.clear();
+            self.rx_idle_chars = 0;
+        }
+        if (data & FifoControl::CLEAR_RX) != 0 {
+            self.rx_fifo.clear();
+            self.rx_idle_chars = 0;
+        }
+        if (data & FifoControl::CLEAR_TX) != 0 {
+            self.tx_fifo.clear();
+            self.tx_empty_interrupt = true;
+        }
+
+        // The other bits can only be programmed while the FIFOs are enabled
+        self.fifo_control = if (data & FifoControl::ENABLE) != 0 {
+            data & (FifoControl::ENABLE | FifoControl::DMA_MODE | FifoControl::RX_TRIGGER)
+        } else {
+            0
+        };
     }
 }
 
-impl<T> Device for Uart<T>
+impl<T> Uart<T>
 where
-    T: Read + Write,
+    T: SerialHost,
 {
-    fn tick(&mut self, _: &mut dyn DeviceBus) {
-        if self.rx_fifo.is_empty() {
-            let mut buf = [0; 16];
-            let read = self.handle.read(&mut buf).unwrap_or_default();
-            for i in 0..read {
-                self.rx_fifo.push_back(buf[i]);
+    fn update_modem_inputs(&mut self) {
+        let inputs = if self.loopback() {
+            self.loopback_modem_inputs()
+        } else {
+            let mut inputs = 0;
+            if self.handle.clear_to_send() {
+                inputs |= ModemStatus::CTS;
+            }
+            if self.handle.connected() {
+                inputs |= ModemStatus::DSR | ModemStatus::DCD;
+            }
+            inputs
+        };
+        self.set_modem_inputs(inputs);
+    }
+
+    fn tick_transmitter(&mut self, mut clocks: usize) {
+        while clocks > 0 {
+            if self.tx_shift.is_none() {
+                match self.tx_fifo.pop_front() {
+                    Some(data) => {
+                        self.tx_shift = Some(data);
+                        self.tx_clocks = self.char_clocks();
+                        if self.tx_fifo.is_empty() {
+                            self.tx_empty_interrupt = true;
+                        }
+                    }
+                    None => return,
+                }
+            }
+
+            let elapsed = clocks.min(self.tx_clocks);
+            self.tx_clocks -= elapsed;
+            clocks -= elapsed;
+
+            // The character in the shift register finished sending
+            if self.tx_clocks == 0 && let Some(data) = self.tx_shift.take() {
+                if self.loopback() {
+                    self.receive(data);
+                } else {
+                    self.handle.write_all(&[data]).unwrap_or_default();
+                }
             }
         }
+    }
+
+    /// The receiver works in character-long slots. The host is only asked
+    /// for data at the start of each slot, and with flow control only while RTS is
+    /// asserted.
+    fn tick_receiver(&mut self, mut clocks: usize) {
+        while clocks > 0 {
+            if self.rx_clocks == 0 {
+                let ready = !self.flow_control || (self.modem_control & ModemControl::RTS) != 0;
+                if ready && !self.loopback() {
+                    if self.host_rx.is_empty() {
+                        let mut buf = [0; 16];
+                        let read = self.handle.read(&mut buf).unwrap_or_default();
+                        self.host_rx.extend(&buf[..read]);
+                    }
+                    self.rx_shift = self.host_rx.pop_front();
+                }
+                self.rx_clocks = self.char_clocks();
+            }
+
+            let elapsed = clocks.min(self.rx_clocks);
+            self.rx_clocks -= elapsed;
+            clocks -= elapsed;
+
+            if self.rx_clocks == 0 {
+                match self.rx_shift.take() {
+                    Some(data) => self.receive(data),
+
+                    None => {
+                        if !self.rx_fifo.is_empty() {
+                            self.rx_idle_chars = self.rx_idle_chars.saturating_add(1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
 
-        if let Some(data) = self.tx_fifo.pop_front() {
-            self.handle.write_all(&[data]).unwrap_or_default();
+impl<T> Device for Uart<T>
+where
+    T: SerialHost,
+{
+    fn tick(&mut self, _: &mut dyn DeviceBus) {
+        self.clock_accumulator += UART_CLOCK_HZ;
+        if self.clock_accumulator < self.system_clock_hz {
+            return;
         }
+        let clocks = self.clock_accumulator / self.system_clock_hz;
+        self.clock_accumulator %= self.system_clock_hz;
+
+        self.update_modem_inputs();
+        self.tick_transmitter(clocks);
+        self.tick_receiver(clocks);
     }
 
     fn read(&mut self, port: u16) -> u8 {
         match port & 0x07 {
             0 => {
-                if (self.line_control & 0x80) == 0 {
+                if (self.line_control & LineControl::DLAB) == 0 {
+                    self.rx_idle_chars = 0;
                     self.rx_fifo.pop_front().unwrap_or_default()
                 } else {
                     self.divisor_latch as u8
@@ -97,60 +535,114 @@ where
             }
 
             1 => {
-                if (self.line_control & 0x80) == 0 {
+                if (self.line_control & LineControl::DLAB) == 0 {
                     self.interrupt_enable
                 } else {
                     (self.divisor_latch >> 8) as u8
                 }
             }
 
-            2 => self.interrupt_status,
+            2 => {
+                let source = self.interrupt_source();
+                // Reading the IIR is one way to acknowledge the tx empty interrupt
+                if source == InterruptSource::TX_EMPTY {
+                    self.tx_empty_interrupt = false;
+                }
+                if self.fifos_enabled() {
+                    source | InterruptSource::FIFOS_ENABLED
+                } else {
+                    source
+                }
+            }
 
             3 => self.line_control,
 
             4 => self.modem_control,
 
-            5 => self.line_status,
+            5 => {
+                let status = self.line_status();
+                self.line_status &= !LineStatus::ERRORS;
+                status
+            }
+
+            6 => {
+                let status = self.modem_status;
+                self.modem_status &= !ModemStatus::DELTAS;
+                status
+            }
 
-            6 => self.modem_status,
+            7 => self.scratch,
 
-            _ => 0,
+            _ => unreachable!(),
         }
     }
 
     fn write(&mut self, port: u16, data: u8) {
         match port & 0x07 {
             0 => {
-                if (self.line_control & 0x80) == 0 {
-                    self.tx_fifo.push_back(data);
+                if (self.line_control & LineControl::DLAB) == 0 {
+                    if self.tx_fifo.len() < self.fifo_size() {
+                        self.tx_fifo.push_back(data);
+                    } else if !self.fifos_enabled() {
+                        // The holding register is just overwritten
+                        self.tx_fifo[0] = data;
+                    }
+                    self.tx_empty_interrupt = false;
                 } else {
                     self.divisor_latch = (self.divisor_latch & 0xFF00) | data as u16;
                 }
             }
 
             1 => {
-                if (self.line_control * 0x80) == 0 {
-                    self.interrupt_enable = data;
+                if (self.line_control & LineControl::DLAB) == 0 {
+                    // Enabling the tx empty interrupt while empty interrupts right away
+                    if (data & !self.interrupt_enable & InterruptEnable::TX_EMPTY) != 0
+                        && self.tx_fifo.is_empty()
+                    {
+                        self.tx_empty_interrupt = true;
+                    }
+                    self.interrupt_enable = data & 0x0F;
                 } else {
                     self.divisor_latch = (self.divisor_latch & 0x00FF) | ((data as u16) << 8);
                 }
             }
 
-            2 => self.fifo_control = data,
+            2 => self.write_fifo_control(data),
 
             3 => self.line_control = data,
 
-            4 => self.modem_control = data,
+            4 => {
+                self.modem_control = data & 0x1F;
+                self.update_modem_inputs();
+            }
 
+            // The status registers are read-only
             5 => {}
 
             6 => {}
 
-            _ => {}
+            7 => self.scratch = data,
+
+            _ => unreachable!(),
         }
     }
 
     fn interrupting(&self) -> bool {
-        todo!()
+        self.interrupt_source() != InterruptSource::NONE
+    }
+
+    /// TXRDY and RXRDY. In DMA mode 1 they follow the FIFOs: RXRDY waits for the trigger
+    /// level (or a timeout) and TXRDY stays active while there is room.
+    fn ready(&self, _: u16, output: bool) -> bool {
+        let mode_1 = (self.fifo_control & FifoControl::DMA_MODE) != 0;
+        match (output, mode_1) {
+            (true, false) => self.tx_fifo.is_empty(),
+            (true, true) => self.tx_fifo.len() < FIFO_SIZE,
+            (false, false) => !self.rx_fifo.is_empty(),
+            (false, true) => {
+                self.rx_fifo.len() >= self.rx_trigger_level()
+                    || (!self.rx_fifo.is_empty() && self.rx_idle_chars >= RX_TIMEOUT_CHARS)
+            }
+        }
     }
 }
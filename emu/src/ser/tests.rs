@@ -0,0 +1,434 @@
+use std::io;
+
+use super::*;
+use crate::bus::NullBus;
+
+struct TestHandle {
+    rx: VecDeque<u8>,
+    tx: Vec<u8>,
+    cts: bool,
+    connected: bool,
+}
+
+impl Default for TestHandle {
+    fn default() -> Self {
+        Self {
+            rx: VecDeque::new(),
+            tx: Vec::new(),
+            cts: true,
+            connected: true,
+        }
+    }
+}
+
+impl Read for TestHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.rx.read(buf)
+    }
+}
+
+impl Write for TestHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialHost for TestHandle {
+    fn clear_to_send(&mut self) -> bool {
+        self.cts
+    }
+
+    fn connected(&mut self) -> bool {
+        self.connected
+    }
+}
+
+/// Ticks per character at the fastest rate with 8N1 framing
+const CHAR_TICKS: usize = 160;
+
+/// Runs 1:1 with the UART clock, at the fastest rate with 8N1 framing,
+/// ready to receive from the host
+fn uart() -> Uart<TestHandle> {
+    let mut uart = Uart::new(TestHandle::default(), UART_CLOCK_HZ);
+    uart.write(3, LineControl::DLAB);
+    uart.write(0, 0x01);
+    uart.write(3, 0x03);
+    uart.write(4, ModemControl::DTR | ModemControl::RTS);
+    uart
+}
+
+fn tick_for(uart: &mut Uart<TestHandle>, ticks: usize) {
+    for _ in 0..ticks {
+        uart.tick(&mut NullBus);
+    }
+}
+
+fn tick_chars(uart: &mut Uart<TestHandle>, chars: usize) {
+    tick_for(uart, chars * CHAR_TICKS);
+}
+
+#[test]
+fn divisor_latch() {
+    let mut uart = uart();
+    uart.write(1, 0x05); // ier
+
+    uart.write(3, LineControl::DLAB | 0x03);
+    uart.write(0, 0x0C);
+    uart.write(1, 0x34);
+    assert_eq!(uart.read(0), 0x0C);
+    assert_eq!(uart.read(1), 0x34);
+    assert_eq!(uart.divisor_latch, 0x340C);
+
+    uart.write(3, 0x03);
+    assert_eq!(uart.read(1), 0x05);
+    assert_eq!(uart.read(3), 0x03);
+}
+
+#[test]
+fn interrupt_enable_mask() {
+    let mut uart = uart();
+    uart.write(1, 0xFF);
+    assert_eq!(uart.read(1), 0x0F);
+}
+
+#[test]
+fn scratch() {
+    let mut uart = uart();
+    uart.write(7, 0xA5);
+    assert_eq!(uart.read(7), 0xA5);
+}
+
+#[test]
+fn modem_control() {
+    let mut uart = uart();
+    uart.write(4, 0xFF);
+    assert_eq!(uart.read(4), 0x1F);
+}
+
+#[test]
+fn transmit_line_status() {
+    let mut uart = uart();
+    let empty = LineStatus::THR_EMPTY | LineStatus::TX_EMPTY;
+    assert_eq!(uart.read(5), empty);
+
+    uart.write(0, b'A');
+    assert_eq!(uart.read(5) & empty, 0);
+
+    // Moves into the shift register
+    uart.tick(&mut NullBus);
+    assert_eq!(uart.read(5) & empty, LineStatus::THR_EMPTY);
+    tick_for(&mut uart, CHAR_TICKS - 2);
+    assert_eq!(uart.read(5) & empty, LineStatus::THR_EMPTY);
+    assert!(uart.handle.tx.is_empty());
+
+    // And out to the host once the whole frame is sent
+    uart.tick(&mut NullBus);
+    assert_eq!(uart.read(5) & empty, empty);
+    assert_eq!(uart.handle.tx, b"A");
+}
+
+#[test]
+fn receive_line_status() {
+    let mut uart = uart();
+    uart.handle.rx.extend(b"AB");
+
+    tick_for(&mut uart, CHAR_TICKS - 1);
+    assert_eq!(uart.read(5) & LineStatus::DATA_READY, 0);
+    uart.tick(&mut NullBus);
+    assert_ne!(uart.read(5) & LineStatus::DATA_READY, 0);
+
+    // Without FIFOs the second character overruns and replaces the first
+    tick_chars(&mut uart, 1);
+    let status = uart.read(5);
+    assert_ne!(status & LineStatus::OVERRUN_ERROR, 0);
+    assert_eq!(uart.read(5) & LineStatus::OVERRUN_ERROR, 0);
+
+    assert_eq!(uart.read(0), b'B');
+    assert_eq!(uart.read(5) & LineStatus::DATA_READY, 0);
+}
+
+#[test]
+fn interrupt_identification() {
+    let mut uart = uart();
+    assert_eq!(uart.read(2), InterruptSource::NONE);
+    assert!(!uart.interrupting());
+
+    // Enabling the tx empty interrupt while empty fires immediately
+    uart.write(1, InterruptEnable::TX_EMPTY);
+    assert!(uart.interrupting());
+    assert_eq!(uart.read(2), InterruptSource::TX_EMPTY);
+    // and reading the IIR acknowledges it
+    assert_eq!(uart.read(2), InterruptSource::NONE);
+
+    // Line status errors take priority over received data
+    uart.write(
+        1,
+        InterruptEnable::RX_READY | InterruptEnable::RX_STATUS | InterruptEnable::TX_EMPTY,
+    );
+    uart.handle.rx.extend(b"AB");
+    tick_chars(&mut uart, 1);
+    assert_eq!(uart.read(2), InterruptSource::RX_READY);
+    tick_chars(&mut uart, 1);
+    assert_eq!(uart.read(2), InterruptSource::RX_STATUS);
+    uart.read(5);
+    assert_eq!(uart.read(2), InterruptSource::RX_READY);
+    uart.read(0);
+
+    // Then tx empty after writing
+    uart.write(0, b'C');
+    assert_eq!(uart.read(2), InterruptSource::NONE);
+    uart.tick(&mut NullBus);
+    assert_eq!(uart.read(2), InterruptSource::TX_EMPTY);
+    assert_eq!(uart.read(2), InterruptSource::NONE);
+}
+
+#[test]
+fn fifo_trigger_levels() {
+    for (bits, level) in [(0x00, 1), (0x40, 4), (0x80, 8), (0xC0, 14)] {
+        let mut uart = uart();
+        uart.write(2, FifoControl::ENABLE | bits);
+        uart.write(1, InterruptEnable::RX_READY);
+        uart.handle.rx.extend(vec![0x55; level]);
+
+        tick_chars(&mut uart, level - 1);
+        assert_eq!(
+            uart.read(2),
+            InterruptSource::NONE | InterruptSource::FIFOS_ENABLED
+        );
+
+        tick_chars(&mut uart, 1);
+        assert_eq!(
+            uart.read(2),
+            InterruptSource::RX_READY | InterruptSource::FIFOS_ENABLED
+        );
+    }
+}
+
+#[test]
+fn fifo_overrun() {
+    let mut uart = uart();
+    uart.write(2, FifoControl::ENABLE);
+    uart.handle.rx.extend(0..(FIFO_SIZE as u8 + 1));
+
+    tick_chars(&mut uart, FIFO_SIZE);
+    assert_eq!(uart.read(5) & LineStatus::OVERRUN_ERROR, 0);
+    tick_chars(&mut uart, 1);
+    assert_ne!(uart.read(5) & LineStatus::OVERRUN_ERROR, 0);
+
+    // The FIFO itself is intact
+    for i in 0..(FIFO_SIZE as u8) {
+        assert_eq!(uart.read(0), i);
+    }
+}
+
+#[test]
+fn fifo_reset() {
+    let mut uart = uart();
+    uart.write(2, FifoControl::ENABLE);
+    uart.handle.rx.extend(b"ABC");
+    tick_chars(&mut uart, 3);
+    uart.write(0, b'D');
+    uart.write(0, b'E');
+
+    uart.write(2, FifoControl::ENABLE | FifoControl::CLEAR_RX);
+    assert_eq!(uart.read(5) & LineStatus::DATA_READY, 0);
+    assert_eq!(uart.read(5) & LineStatus::THR_EMPTY, 0);
+
+    uart.write(2, FifoControl::ENABLE | FifoControl::CLEAR_TX);
+    assert_ne!(uart.read(5) & LineStatus::THR_EMPTY, 0);
+
+    // The self-clearing bits don't stick
+    assert_eq!(uart.fifo_control, FifoControl::ENABLE);
+}
+
+#[test]
+fn rx_timeout() {
+    let mut uart = uart();
+    uart.write(2, FifoControl::ENABLE | 0xC0);
+    uart.write(1, InterruptEnable::RX_READY);
+    uart.handle.rx.push_back(b'A');
+
+    tick_chars(&mut uart, 1);
+    for _ in 0..(RX_TIMEOUT_CHARS - 1) {
+        tick_chars(&mut uart, 1);
+        assert!(!uart.interrupting());
+    }
+    tick_chars(&mut uart, 1);
+    assert_eq!(
+        uart.read(2),
+        InterruptSource::RX_TIMEOUT | InterruptSource::FIFOS_ENABLED
+    );
+
+    assert_eq!(uart.read(0), b'A');
+    assert!(!uart.interrupting());
+}
+
+#[test]
+fn modem_status() {
+    let mut uart = uart();
+    assert_eq!(
+        uart.read(6),
+        ModemStatus::CTS | ModemStatus::DSR | ModemStatus::DCD
+    );
+}
+
+#[test]
+fn frame_length() {
+    let mut uart = uart();
+    for (line_control, bits_x2) in [
+        (0x03, 20), // 8N1
+        (0x00, 14), // 5N1
+        (0x04, 15), // 5N1.5
+        (0x07, 22), // 8N2
+        (0x0E, 22), // 7E2
+        (0x0F, 24), // 8E2
+    ] {
+        uart.write(3, line_control);
+        assert_eq!(uart.char_clocks(), bits_x2 * CLOCKS_PER_BIT / 2);
+    }
+}
+
+#[test]
+fn baud_rate() {
+    // 9600 baud 8N1 with the UART clocked at half the system rate
+    let mut uart = Uart::new(TestHandle::default(), UART_CLOCK_HZ * 2);
+    uart.write(3, LineControl::DLAB | 0x03);
+    uart.write(0, 12);
+    uart.write(3, 0x03);
+    uart.write(4, ModemControl::RTS);
+    let char_ticks = 2 * 12 * CLOCKS_PER_BIT * 10;
+    assert_eq!(UART_CLOCK_HZ / (12 * CLOCKS_PER_BIT), 9600);
+
+    uart.write(0, b'A');
+    tick_for(&mut uart, char_ticks - 1);
+    assert!(uart.handle.tx.is_empty());
+    uart.tick(&mut NullBus);
+    assert_eq!(uart.handle.tx, b"A");
+
+    uart.handle.rx.extend(b"B");
+    tick_for(&mut uart, char_ticks - 1);
+    assert_eq!(uart.read(5) & LineStatus::DATA_READY, 0);
+    uart.tick(&mut NullBus);
+    assert_eq!(uart.read(0), b'B');
+}
+
+#[test]
+fn loopback() {
+    let mut uart = uart();
+    uart.handle.rx.extend(b"X");
+    uart.write(4, ModemControl::LOOPBACK);
+    assert_eq!(uart.read(6) & ModemStatus::INPUTS, 0);
+
+    // The outputs drive the inputs
+    uart.write(
+        4,
+        ModemControl::LOOPBACK | ModemControl::RTS | ModemControl::OUT2,
+    );
+    assert_eq!(
+        uart.read(6),
+        ModemStatus::CTS | ModemStatus::DCD | ModemStatus::DELTA_CTS | ModemStatus::DELTA_DCD
+    );
+    uart.write(
+        4,
+        ModemControl::LOOPBACK | ModemControl::DTR | ModemControl::OUT1,
+    );
+    assert_eq!(
+        uart.read(6),
+        ModemStatus::DSR
+            | ModemStatus::RI
+            | ModemStatus::DELTA_CTS
+            | ModemStatus::DELTA_DSR
+            | ModemStatus::DELTA_DCD
+    );
+    uart.write(4, ModemControl::LOOPBACK | ModemControl::DTR);
+    assert_eq!(uart.read(6), ModemStatus::DSR | ModemStatus::TRAILING_RI);
+
+    // Transmitted characters come right back, and the host sees nothing
+    uart.write(0, b'A');
+    tick_chars(&mut uart, 2);
+    assert_eq!(uart.read(0), b'A');
+    assert!(uart.handle.tx.is_empty());
+    assert_eq!(uart.handle.rx, b"X");
+}
+
+#[test]
+fn modem_status_interrupt() {
+    let mut uart = uart();
+    uart.write(1, InterruptEnable::MODEM_STATUS);
+    uart.tick(&mut NullBus);
+    assert!(!uart.interrupting());
+
+    uart.handle.connected = false;
+    uart.tick(&mut NullBus);
+    assert_eq!(uart.read(2), InterruptSource::MODEM_STATUS);
+    assert_eq!(
+        uart.read(6),
+        ModemStatus::CTS | ModemStatus::DELTA_DSR | ModemStatus::DELTA_DCD
+    );
+    assert!(!uart.interrupting());
+
+    uart.handle.cts = false;
+    uart.tick(&mut NullBus);
+    assert_eq!(uart.read(6), ModemStatus::DELTA_CTS);
+}
+
+#[test]
+fn rts_flow_control() {
+    let mut uart = uart();
+    uart.set_flow_control(true);
+    uart.write(4, ModemControl::DTR);
+    uart.handle.rx.extend(b"AB");
+
+    // The host is held off
+    tick_chars(&mut uart, 4);
+    assert_eq!(uart.read(5) & LineStatus::DATA_READY, 0);
+    assert_eq!(uart.handle.rx, b"AB");
+
+    uart.write(4, ModemControl::DTR | ModemControl::RTS);
+    tick_chars(&mut uart, 1);
+    assert_eq!(uart.read(0), b'A');
+}
+
+#[test]
+fn no_flow_control() {
+    let mut uart = uart();
+    uart.write(4, ModemControl::DTR);
+    uart.handle.rx.extend(b"AB");
+
+    // RTS is ignored
+    tick_chars(&mut uart, 1);
+    assert_eq!(uart.read(0), b'A');
+    tick_chars(&mut uart, 1);
+    assert_eq!(uart.read(0), b'B');
+}
+
+#[test]
+fn dma_ready_mode_0() {
+    // Follows the holding registers
+    let mut uart = uart();
+    assert!(uart.ready(0, true));
+    assert!(!uart.ready(0, false));
+    uart.write(0, 0x55);
+    assert!(!uart.ready(0, true));
+    uart.handle.rx.push_back(0x55);
+    tick_chars(&mut uart, 1);
+    assert!(uart.ready(0, false));
+}
+
+#[test]
+fn dma_ready_mode_1() {
+    // Follows the FIFOs
+    let mut uart = uart();
+    uart.write(2, FifoControl::ENABLE | FifoControl::DMA_MODE | 0x40);
+    uart.write(0, 0x55);
+    assert!(uart.ready(0, true));
+    uart.handle.rx.extend([0x55; 4]);
+    tick_chars(&mut uart, 1);
+    assert!(!uart.ready(0, false));
+    tick_chars(&mut uart, 3);
+    assert!(uart.ready(0, false));
+}
@@ -0,0 +1,629 @@
+//! Z80 SIO/2 Emulation
+//!
+//! Only the asynchronous modes are emulated. Both channels are clocked by the same baud
+//! rate oscillator, divided down by each channel's clock mode, and characters take as
+//! long to send and receive as they would on a real line.
+//!
+//! The registers are at these offsets in the serial port block:
+//!
+//! | Offset | Register          |
+//! |--------|-------------------|
+//! | 0      | Channel A data    |
+//! | 1      | Channel A control |
+//! | 2      | Channel B data    |
+//! | 3      | Channel B control |
+//!
+//! Interrupts are vectored for mode 2. An acknowledged interrupt stays under service,
+//! holding off itself and everything of lower priority, until a RETI or the "return from
+//! interrupt" command on channel A.
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::VecDeque;
+
+use crate::{ser::SerialHost, Device, DeviceBus};
+
+/// WR0
+struct Command;
+impl Command {
+    /// Selects the register for the next control access
+    const POINTER: u8 = 0x07;
+
+    const MASK: u8 = 0x38;
+
+    const RESET_EXT_STATUS: u8 = 0x10;
+
+    const CHANNEL_RESET: u8 = 0x18;
+
+    const ENABLE_NEXT_RX: u8 = 0x20;
+
+    const RESET_TX_PENDING: u8 = 0x28;
+
+    const ERROR_RESET: u8 = 0x30;
+
+    /// Channel A only
+    const RETURN_FROM_INT: u8 = 0x38;
+}
+
+/// WR1
+struct InterruptControl;
+impl InterruptControl {
+    const EXT_ENABLE: u8 = 0x01;
+
+    const TX_ENABLE: u8 = 0x02;
+
+    /// Channel B only
+    const STATUS_AFFECTS_VECTOR: u8 = 0x04;
+
+    /// The other two modes interrupt on every character. They only differ in whether
+    /// parity errors are special conditions, and there are no parity errors here.
+    const RX_MODE: u8 = 0x18;
+
+    const RX_FIRST_CHAR: u8 = 0x08;
+}
+
+/// WR3
+struct RxControl;
+impl RxControl {
+    const ENABLE: u8 = 0x01;
+
+    /// DCD enables the receiver and CTS enables the transmitter
+    const AUTO_ENABLES: u8 = 0x20;
+
+    const BITS: u8 = 0xC0;
+}
+
+/// WR4
+struct Format;
+impl Format {
+    const PARITY_ENABLE: u8 = 0x01;
+
+    const STOP_BITS: u8 = 0x0C;
+
+    const CLOCK_MODE: u8 = 0xC0;
+}
+
+/// WR5
+struct TxControl;
+impl TxControl {
+    const RTS: u8 = 0x02;
+
+    const ENABLE: u8 = 0x08;
+
+    const BITS: u8 = 0x60;
+}
+
+/// RR0
+struct Status;
+impl Status {
+    const RX_AVAILABLE: u8 = 0x01;
+
+    /// Channel A only
+    const INT_PENDING: u8 = 0x02;
+
+    const TX_EMPTY: u8 = 0x04;
+
+    const DCD: u8 = 0x08;
+
+    const CTS: u8 = 0x20;
+}
+
+/// RR1
+struct SpecialStatus;
+impl SpecialStatus {
+    const ALL_SENT: u8 = 0x01;
+
+    const RX_OVERRUN: u8 = 0x20;
+}
+
+/// The oscillator on TxC and RxC. In x16 clock mode it is 115200 baud.
+const SIO_CLOCK_HZ: usize = 1_843_200;
+
+/// Received characters wait here, the last one being in the shift register
+const RX_FIFO_SIZE: usize = 3;
+
+/// Interrupt sources within a channel, highest priority first
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Source {
+    SpecialRx,
+    Rx,
+    Tx,
+    Ext,
+}
+
+impl Source {
+    /// Receive conditions share a priority level
+    #[inline]
+    fn level(self) -> usize {
+        match self {
+            Self::SpecialRx | Self::Rx => 0,
+            Self::Tx => 1,
+            Self::Ext => 2,
+        }
+    }
+
+    /// The bits written into V3-V1 of the vector when status affects vector
+    #[inline]
+    fn vector_code(self) -> u8 {
+        match self {
+            Self::Tx => 0,
+            Self::Ext => 1,
+            Self::Rx => 2,
+            Self::SpecialRx => 3,
+        }
+    }
+}
+
+/// Bits per character from the 2-bit code in WR3 or WR5
+#[inline]
+fn data_bits(code: u8) -> usize {
+    match code & 0x03 {
+        0 => 5,
+        1 => 7,
+        2 => 6,
+        3 => 8,
+        _ => unreachable!(),
+    }
+}
+
+struct Channel<T> {
+    handle: T,
+    pointer: u8,
+    interrupt_control: u8,
+    rx_control: u8,
+    format: u8,
+    tx_control: u8,
+    /// Bytes read from the host that haven't made it to the receiver yet
+    host_rx: VecDeque<u8>,
+    tx_buffer: Option<u8>,
+    tx_shift: Option<u8>,
+    tx_clocks: usize,
+    rx_fifo: VecDeque<u8>,
+    rx_shift: Option<u8>,
+    rx_clocks: usize,
+    /// The next character received interrupts in first character mode
+    rx_first_armed: bool,
+    rx_first_pending: bool,
+    tx_pending: bool,
+    ext_pending: bool,
+    /// The DCD and CTS inputs
+    inputs: u8,
+    errors: u8,
+    /// RTS holds off the host
+    flow_control: bool,
+}
+
+impl<T> Channel<T> {
+    fn new(handle: T) -> Self {
+        Self {
+            handle,
+            pointer: 0,
+            interrupt_control: 0,
+            rx_control: 0,
+            format: 0,
+            tx_control: 0,
+            host_rx: VecDeque::new(),
+            tx_buffer: None,
+            tx_shift: None,
+            tx_clocks: 0,
+            rx_fifo: VecDeque::with_capacity(RX_FIFO_SIZE),
+            rx_shift: None,
+            rx_clocks: 0,
+            rx_first_armed: true,
+            rx_first_pending: false,
+            tx_pending: false,
+            ext_pending: false,
+            // The host is always "connected"
+            inputs: Status::DCD | Status::CTS,
+            errors: 0,
+            flow_control: false,
+        }
+    }
+
+    /// Everything but the host and the state of the line
+    fn reset(&mut self) {
+        self.pointer = 0;
+        self.interrupt_control = 0;
+        self.rx_control = 0;
+        self.format = 0;
+        self.tx_control = 0;
+        self.tx_buffer = None;
+        self.tx_shift = None;
+        self.rx_fifo.clear();
+        self.rx_first_armed = true;
+        self.rx_first_pending = false;
+        self.tx_pending = false;
+        self.ext_pending = false;
+        self.errors = 0;
+    }
+
+    /// How many SIO clocks it takes to send or receive a whole character
+    fn char_clocks(&self, data_bits: usize) -> usize {
+        let clock_mode = match (self.format & Format::CLOCK_MODE) >> 6 {
+            0 => 1,
+            1 => 16,
+            2 => 32,
+            3 => 64,
+            _ => unreachable!(),
+        };
+        let parity_bits = ((self.format & Format::PARITY_ENABLE) != 0) as usize;
+
+        // Counted in half bits since there can be 1.5 stop bits. The sync modes
+        // aren't emulated, so they get 1 stop bit.
+        let stop_half_bits = match (self.format & Format::STOP_BITS) >> 2 {
+            0 | 1 => 2,
+            2 => 3,
+            3 => 4,
+            _ => unreachable!(),
+        };
+        let frame_half_bits = (2 * (1 + data_bits + parity_bits)) + stop_half_bits;
+
+        (clock_mode * frame_half_bits) / 2
+    }
+
+    #[inline]
+    fn auto_enables(&self) -> bool {
+        (self.rx_control & RxControl::AUTO_ENABLES) != 0
+    }
+
+    #[inline]
+    fn tx_enabled(&self) -> bool {
+        (self.tx_control & TxControl::ENABLE) != 0
+            && (!self.auto_enables() || (self.inputs & Status::CTS) != 0)
+    }
+
+    #[inline]
+    fn rx_enabled(&self) -> bool {
+        (self.rx_control & RxControl::ENABLE) != 0
+            && (!self.auto_enables() || (self.inputs & Status::DCD) != 0)
+    }
+
+    /// A whole character made it into the receiver
+    fn receive(&mut self, data: u8) {
+        // With nowhere to put it, the newest character is overwritten
+        if self.rx_fifo.len() < RX_FIFO_SIZE {
+            self.rx_fifo.push_back(data);
+        } else {
+            *self.rx_fifo.back_mut().unwrap() = data;
+            self.errors |= SpecialStatus::RX_OVERRUN;
+        }
+        if self.rx_first_armed {
+            self.rx_first_armed = false;
+            self.rx_first_pending = true;
+        }
+    }
+
+    /// The highest priority interrupt this channel has pending
+    fn pending(&self) -> Option<Source> {
+        let enabled = |mask| (self.interrupt_control & mask) != 0;
+        let rx_mode = self.interrupt_control & InterruptControl::RX_MODE;
+
+        if rx_mode != 0 && self.errors != 0 {
+            return Some(Source::SpecialRx);
+        }
+        if rx_mode == InterruptControl::RX_FIRST_CHAR {
+            if self.rx_first_pending {
+                return Some(Source::Rx);
+            }
+        } else if rx_mode != 0 && !self.rx_fifo.is_empty() {
+            return Some(Source::Rx);
+        }
+        if enabled(InterruptControl::TX_ENABLE) && self.tx_pending {
+            return Some(Source::Tx);
+        }
+        if enabled(InterruptControl::EXT_ENABLE) && self.ext_pending {
+            return Some(Source::Ext);
+        }
+        None
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = self.inputs;
+        if !self.rx_fifo.is_empty() {
+            status |= Status::RX_AVAILABLE;
+        }
+        if self.tx_buffer.is_none() {
+            status |= Status::TX_EMPTY;
+        }
+        status
+    }
+
+    fn special_status(&self) -> u8 {
+        let mut status = self.errors;
+        if self.tx_buffer.is_none() && self.tx_shift.is_none() {
+            status |= SpecialStatus::ALL_SENT;
+        }
+        status
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.rx_first_pending = false;
+        self.rx_fifo.pop_front().unwrap_or_default()
+    }
+
+    fn write_data(&mut self, data: u8) {
+        // A full buffer is just overwritten
+        self.tx_buffer = Some(data);
+        self.tx_pending = false;
+    }
+
+    fn command(&mut self, data: u8) {
+        self.pointer = data & Command::POINTER;
+        match data & Command::MASK {
+            Command::RESET_EXT_STATUS => self.ext_pending = false,
+            Command::CHANNEL_RESET => self.reset(),
+            Command::ENABLE_NEXT_RX => self.rx_first_armed = true,
+            Command::RESET_TX_PENDING => self.tx_pending = false,
+            Command::ERROR_RESET => self.errors = 0,
+            _ => {}
+        }
+    }
+
+    /// The write registers other than WR0 and WR2
+    fn write_register(&mut self, pointer: u8, data: u8) {
+        match pointer {
+            1 => self.interrupt_control = data,
+            3 => self.rx_control = data,
+            4 => self.format = data,
+            5 => self.tx_control = data,
+            // The sync characters only matter in the sync modes
+            6 | 7 => {}
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<T> Channel<T>
+where
+    T: SerialHost,
+{
+    fn update_inputs(&mut self) {
+        let mut inputs = 0;
+        if self.handle.clear_to_send() {
+            inputs |= Status::CTS;
+        }
+        if self.handle.connected() {
+            inputs |= Status::DCD;
+        }
+        if inputs != self.inputs && (self.interrupt_control & InterruptControl::EXT_ENABLE) != 0 {
+            self.ext_pending = true;
+        }
+        self.inputs = inputs;
+    }
+
+    fn tick_transmitter(&mut self, mut clocks: usize) {
+        let data_bits = data_bits((self.tx_control & TxControl::BITS) >> 5);
+        while clocks > 0 {
+            if self.tx_shift.is_none() {
+                if !self.tx_enabled() {
+                    return;
+                }
+                match self.tx_buffer.take() {
+                    Some(data) => {
+                        self.tx_shift = Some(data);
+                        self.tx_clocks = self.char_clocks(data_bits);
+                        if (self.interrupt_control & InterruptControl::TX_ENABLE) != 0 {
+                            self.tx_pending = true;
+                        }
+                    }
+                    None => return,
+                }
+            }
+
+            let elapsed = clocks.min(self.tx_clocks);
+            self.tx_clocks -= elapsed;
+            clocks -= elapsed;
+
+            // The character in the shift register finished sending
+            if self.tx_clocks == 0 && let Some(data) = self.tx_shift.take() {
+                self.handle.write_all(&[data]).unwrap_or_default();
+            }
+        }
+    }
+
+    /// The receiver works in character-long slots. The host is only asked
+    /// for data at the start of each slot, and with flow control only while RTS is
+    /// asserted.
+    fn tick_receiver(&mut self, mut clocks: usize) {
+        let data_bits = data_bits((self.rx_control & RxControl::BITS) >> 6);
+        while clocks > 0 {
+            if self.rx_clocks == 0 {
+                let ready = !self.flow_control || (self.tx_control & TxControl::RTS) != 0;
+                if ready && self.rx_enabled() {
+                    if self.host_rx.is_empty() {
+                        let mut buf = [0; 16];
+                        let read = self.handle.read(&mut buf).unwrap_or_default();
+                        self.host_rx.extend(&buf[..read]);
+                    }
+                    self.rx_shift = self.host_rx.pop_front();
+                }
+                self.rx_clocks = self.char_clocks(data_bits);
+            }
+
+            let elapsed = clocks.min(self.rx_clocks);
+            self.rx_clocks -= elapsed;
+            clocks -= elapsed;
+
+            if self.rx_clocks == 0 && let Some(data) = self.rx_shift.take() {
+                self.receive(data);
+            }
+        }
+    }
+}
+
+pub struct Sio<T> {
+    channels: [Channel<T>; 2],
+    /// The rate that the SIO is ticked at
+    system_clock_hz: usize,
+    /// Fractional SIO clocks carried between ticks
+    clock_accumulator: usize,
+    /// WR2 of channel B
+    vector: u8,
+    /// One bit per priority level, with the highest priority in bit 0
+    in_service: u8,
+}
+
+impl<T> Sio<T> {
+    /// The SIO expects to be ticked at `system_clock_hz`
+    #[inline]
+    pub fn new(a: T, b: T, system_clock_hz: usize) -> Self {
+        Self {
+            channels: [Channel::new(a), Channel::new(b)],
+            system_clock_hz,
+            clock_accumulator: 0,
+            vector: 0,
+            in_service: 0,
+        }
+    }
+
+    /// Only hold off the host of a channel (A is 0) while RTS is deasserted if the
+    /// guest is known to raise it
+    #[inline]
+    pub fn set_flow_control(&mut self, channel: usize, enabled: bool) {
+        self.channels[channel].flow_control = enabled;
+    }
+
+    /// The highest priority interrupt pending, with its priority level
+    fn pending(&self) -> Option<(usize, Source, usize)> {
+        self.channels
+            .iter()
+            .enumerate()
+            .find_map(|(index, channel)| {
+                channel
+                    .pending()
+                    .map(|source| (index, source, (index * 3) + source.level()))
+            })
+    }
+
+    /// The vector, with the pending interrupt in V3-V1 if status affects vector
+    fn vector(&self, pending: Option<(usize, Source, usize)>) -> u8 {
+        let control = self.channels[1].interrupt_control;
+        if (control & InterruptControl::STATUS_AFFECTS_VECTOR) == 0 {
+            return self.vector;
+        }
+        let code = match pending {
+            Some((0, source, _)) => 4 + source.vector_code(),
+            Some((_, source, _)) => source.vector_code(),
+            // Nothing pending reads back like a channel B special condition
+            None => 3,
+        };
+        (self.vector & !0x0E) | (code << 1)
+    }
+
+    /// Ends the service of the highest priority level
+    #[inline]
+    fn end_service(&mut self) {
+        self.in_service &= self.in_service.wrapping_sub(1);
+    }
+
+    fn read_control(&mut self, index: usize) -> u8 {
+        let pointer = self.channels[index].pointer;
+        self.channels[index].pointer = 0;
+        match (pointer, index) {
+            (0, 0) => {
+                let mut status = self.channels[0].status();
+                if self.pending().is_some() {
+                    status |= Status::INT_PENDING;
+                }
+                status
+            }
+            (0, _) => self.channels[index].status(),
+            (1, _) => self.channels[index].special_status(),
+            (2, 1) => self.vector(self.pending()),
+            _ => 0,
+        }
+    }
+
+    fn write_control(&mut self, index: usize, data: u8) {
+        let pointer = self.channels[index].pointer;
+        self.channels[index].pointer = 0;
+        match pointer {
+            0 => {
+                if index == 0 && (data & Command::MASK) == Command::RETURN_FROM_INT {
+                    self.end_service();
+                }
+                self.channels[index].command(data);
+            }
+            2 => {
+                if index == 1 {
+                    self.vector = data;
+                }
+            }
+            _ => self.channels[index].write_register(pointer, data),
+        }
+    }
+}
+
+impl<T> Device for Sio<T>
+where
+    T: SerialHost,
+{
+    fn tick(&mut self, _: &mut dyn DeviceBus) {
+        self.clock_accumulator += SIO_CLOCK_HZ;
+        if self.clock_accumulator < self.system_clock_hz {
+            return;
+        }
+        let clocks = self.clock_accumulator / self.system_clock_hz;
+        self.clock_accumulator %= self.system_clock_hz;
+
+        for channel in &mut self.channels {
+            channel.update_inputs();
+            channel.tick_transmitter(clocks);
+            channel.tick_receiver(clocks);
+        }
+    }
+
+    fn read(&mut self, port: u16) -> u8 {
+        let index = ((port >> 1) & 0x01) as usize;
+        if (port & 0x01) == 0 {
+            self.channels[index].read_data()
+        } else {
+            self.read_control(index)
+        }
+    }
+
+    fn write(&mut self, port: u16, data: u8) {
+        let index = ((port >> 1) & 0x01) as usize;
+        if (port & 0x01) == 0 {
+            self.channels[index].write_data(data);
+        } else {
+            self.write_control(index, data);
+        }
+    }
+
+    /// Interrupts under service hold off their own level and everything below
+    fn interrupting(&self) -> bool {
+        match self.pending() {
+            Some((_, _, level)) => (self.in_service & ((2 << level) - 1)) == 0,
+            None => false,
+        }
+    }
+
+    /// W/RDY of the addressed channel in ready mode. It follows the receiver for reads
+    /// and the transmit buffer for writes.
+    fn ready(&self, port: u16, output: bool) -> bool {
+        let status = self.channels[((port >> 1) & 0x01) as usize].status();
+        if output {
+            (status & Status::TX_EMPTY) != 0
+        } else {
+            (status & Status::RX_AVAILABLE) != 0
+        }
+    }
+
+    fn acknowledge(&mut self) -> Option<u8> {
+        let pending = self.pending();
+        if let Some((_, _, level)) = pending {
+            self.in_service |= 1 << level;
+        }
+        Some(self.vector(pending))
+    }
+
+    fn in_service(&self) -> bool {
+        self.in_service != 0
+    }
+
+    fn reti(&mut self) {
+        self.end_service();
+    }
+}
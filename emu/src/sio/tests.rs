@@ -0,0 +1,431 @@
+use std::io::{self, Read, Write};
+
+use super::*;
+use crate::bus::NullBus;
+
+struct TestHandle {
+    rx: VecDeque<u8>,
+    tx: Vec<u8>,
+    cts: bool,
+}
+
+impl Default for TestHandle {
+    fn default() -> Self {
+        Self {
+            rx: VecDeque::new(),
+            tx: Vec::new(),
+            cts: true,
+        }
+    }
+}
+
+impl Read for TestHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.rx.read(buf)
+    }
+}
+
+impl Write for TestHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialHost for TestHandle {
+    fn clear_to_send(&mut self) -> bool {
+        self.cts
+    }
+}
+
+const A_DATA: u16 = 0;
+const A_CONTROL: u16 = 1;
+const B_DATA: u16 = 2;
+const B_CONTROL: u16 = 3;
+
+/// Ticks per character in x16 clock mode with 8N1 framing
+const CHAR_TICKS: usize = 160;
+
+fn write_register(sio: &mut Sio<TestHandle>, control: u16, register: u8, data: u8) {
+    sio.write(control, register);
+    sio.write(control, data);
+}
+
+fn read_register(sio: &mut Sio<TestHandle>, control: u16, register: u8) -> u8 {
+    sio.write(control, register);
+    sio.read(control)
+}
+
+/// Runs 1:1 with the SIO clock. Both channels are set up for x16 clock mode and 8N1
+/// framing, ready to send and receive.
+fn sio() -> Sio<TestHandle> {
+    let mut sio = Sio::new(TestHandle::default(), TestHandle::default(), SIO_CLOCK_HZ);
+    for control in [A_CONTROL, B_CONTROL] {
+        sio.write(control, Command::CHANNEL_RESET);
+        write_register(&mut sio, control, 4, 0x44);
+        write_register(&mut sio, control, 3, RxControl::BITS | RxControl::ENABLE);
+        write_register(
+            &mut sio,
+            control,
+            5,
+            TxControl::BITS | TxControl::ENABLE | TxControl::RTS,
+        );
+    }
+    sio
+}
+
+fn tick_for(sio: &mut Sio<TestHandle>, ticks: usize) {
+    for _ in 0..ticks {
+        sio.tick(&mut NullBus);
+    }
+}
+
+fn tick_chars(sio: &mut Sio<TestHandle>, chars: usize) {
+    tick_for(sio, chars * CHAR_TICKS);
+}
+
+#[test]
+fn register_pointer() {
+    let mut sio = sio();
+    sio.write(A_CONTROL, 0x01);
+    sio.write(A_CONTROL, InterruptControl::TX_ENABLE);
+    assert_eq!(
+        sio.channels[0].interrupt_control,
+        InterruptControl::TX_ENABLE
+    );
+
+    // The pointer goes back to 0 after each access
+    sio.write(A_CONTROL, Command::RESET_TX_PENDING);
+    assert_eq!(
+        sio.channels[0].interrupt_control,
+        InterruptControl::TX_ENABLE
+    );
+    assert_eq!(sio.read(A_CONTROL) & Status::TX_EMPTY, Status::TX_EMPTY);
+}
+
+#[test]
+fn transmit() {
+    let mut sio = sio();
+    sio.write(A_DATA, b'h');
+    sio.write(B_DATA, b'i');
+    assert_eq!(sio.read(A_CONTROL) & Status::TX_EMPTY, 0);
+    assert_eq!(
+        read_register(&mut sio, A_CONTROL, 1) & SpecialStatus::ALL_SENT,
+        0
+    );
+
+    // The buffer empties right away into the shift register
+    tick_for(&mut sio, 1);
+    assert_eq!(sio.read(A_CONTROL) & Status::TX_EMPTY, Status::TX_EMPTY);
+    assert_eq!(
+        read_register(&mut sio, A_CONTROL, 1) & SpecialStatus::ALL_SENT,
+        0
+    );
+
+    tick_chars(&mut sio, 1);
+    assert_eq!(sio.channels[0].handle.tx, b"h");
+    assert_eq!(sio.channels[1].handle.tx, b"i");
+    assert_eq!(
+        read_register(&mut sio, A_CONTROL, 1) & SpecialStatus::ALL_SENT,
+        SpecialStatus::ALL_SENT
+    );
+}
+
+#[test]
+fn transmit_disabled() {
+    let mut sio = sio();
+    write_register(&mut sio, A_CONTROL, 5, TxControl::BITS | TxControl::RTS);
+    sio.write(A_DATA, b'h');
+    tick_chars(&mut sio, 2);
+    assert!(sio.channels[0].handle.tx.is_empty());
+    assert_eq!(sio.read(A_CONTROL) & Status::TX_EMPTY, 0);
+}
+
+#[test]
+fn receive() {
+    let mut sio = sio();
+    sio.channels[1].handle.rx.extend(b"hi");
+    assert_eq!(sio.read(B_CONTROL) & Status::RX_AVAILABLE, 0);
+
+    tick_chars(&mut sio, 2);
+    assert_eq!(
+        sio.read(B_CONTROL) & Status::RX_AVAILABLE,
+        Status::RX_AVAILABLE
+    );
+    assert_eq!(sio.read(B_DATA), b'h');
+    assert_eq!(sio.read(B_DATA), b'i');
+    assert_eq!(sio.read(B_CONTROL) & Status::RX_AVAILABLE, 0);
+    assert_eq!(sio.read(A_CONTROL) & Status::RX_AVAILABLE, 0);
+}
+
+#[test]
+fn dma_ready() {
+    // Each channel has a line of its own
+    let mut sio = sio();
+    assert!(sio.ready(A_DATA, true));
+    assert!(!sio.ready(B_DATA, false));
+    sio.write(A_DATA, b'h');
+    assert!(!sio.ready(A_DATA, true));
+    assert!(sio.ready(B_DATA, true));
+
+    sio.channels[1].handle.rx.push_back(b'i');
+    tick_chars(&mut sio, 1);
+    assert!(sio.ready(B_CONTROL, false));
+    assert!(!sio.ready(A_CONTROL, false));
+    sio.read(B_DATA);
+    assert!(!sio.ready(B_DATA, false));
+}
+
+#[test]
+fn receive_held_off() {
+    let mut sio = sio();
+    sio.set_flow_control(0, true);
+    sio.channels[0].handle.rx.extend(b"hi");
+
+    // With flow control, the host is held off without RTS
+    write_register(&mut sio, A_CONTROL, 5, TxControl::BITS | TxControl::ENABLE);
+    tick_chars(&mut sio, 2);
+    assert_eq!(sio.read(A_CONTROL) & Status::RX_AVAILABLE, 0);
+
+    // But not without it
+    sio.set_flow_control(0, false);
+    tick_chars(&mut sio, 1);
+    assert_eq!(sio.read(A_DATA), b'h');
+    sio.set_flow_control(0, true);
+
+    // And the same for a disabled receiver
+    write_register(&mut sio, A_CONTROL, 5, TxControl::BITS | TxControl::RTS);
+    write_register(&mut sio, A_CONTROL, 3, RxControl::BITS);
+    tick_chars(&mut sio, 2);
+    assert_eq!(sio.read(A_CONTROL) & Status::RX_AVAILABLE, 0);
+
+    write_register(&mut sio, A_CONTROL, 3, RxControl::BITS | RxControl::ENABLE);
+    tick_chars(&mut sio, 2);
+    assert_eq!(sio.read(A_DATA), b'i');
+}
+
+#[test]
+fn rx_overrun() {
+    let mut sio = sio();
+    sio.channels[0].handle.rx.extend(b"abcde");
+    tick_chars(&mut sio, 5);
+    assert_eq!(
+        read_register(&mut sio, A_CONTROL, 1) & SpecialStatus::RX_OVERRUN,
+        SpecialStatus::RX_OVERRUN
+    );
+    assert_eq!(sio.read(A_DATA), b'a');
+    assert_eq!(sio.read(A_DATA), b'b');
+    assert_eq!(sio.read(A_DATA), b'e');
+
+    // Errors stay latched until reset
+    assert_eq!(
+        read_register(&mut sio, A_CONTROL, 1) & SpecialStatus::RX_OVERRUN,
+        SpecialStatus::RX_OVERRUN
+    );
+    sio.write(A_CONTROL, Command::ERROR_RESET);
+    assert_eq!(
+        read_register(&mut sio, A_CONTROL, 1) & SpecialStatus::RX_OVERRUN,
+        0
+    );
+}
+
+#[test]
+fn baud_rate() {
+    let mut sio = sio();
+    // x64 clock mode with 7 data bits, even parity and 2 stop bits is 11 bits
+    write_register(&mut sio, A_CONTROL, 4, 0xCF);
+    write_register(&mut sio, A_CONTROL, 3, 0x40 | RxControl::ENABLE);
+    sio.channels[0].handle.rx.push_back(b'x');
+
+    tick_for(&mut sio, (64 * 11) - 1);
+    assert_eq!(sio.read(A_CONTROL) & Status::RX_AVAILABLE, 0);
+    tick_for(&mut sio, 1);
+    assert_eq!(sio.read(A_DATA), b'x');
+}
+
+#[test]
+fn rx_interrupt_first_char() {
+    let mut sio = sio();
+    write_register(&mut sio, A_CONTROL, 1, InterruptControl::RX_FIRST_CHAR);
+    sio.channels[0].handle.rx.extend(b"ab");
+
+    tick_chars(&mut sio, 1);
+    assert!(sio.interrupting());
+    sio.read(A_DATA);
+    assert!(!sio.interrupting());
+
+    tick_chars(&mut sio, 1);
+    assert!(!sio.interrupting());
+    sio.read(A_DATA);
+
+    sio.write(A_CONTROL, Command::ENABLE_NEXT_RX);
+    sio.channels[0].handle.rx.push_back(b'c');
+    tick_chars(&mut sio, 1);
+    assert!(sio.interrupting());
+}
+
+#[test]
+fn rx_interrupt_all_chars() {
+    let mut sio = sio();
+    write_register(&mut sio, A_CONTROL, 1, InterruptControl::RX_MODE);
+    sio.channels[0].handle.rx.extend(b"ab");
+
+    tick_chars(&mut sio, 2);
+    assert!(sio.interrupting());
+    sio.read(A_DATA);
+    assert!(sio.interrupting());
+    sio.read(A_DATA);
+    assert!(!sio.interrupting());
+}
+
+#[test]
+fn tx_interrupt() {
+    let mut sio = sio();
+    write_register(&mut sio, B_CONTROL, 1, InterruptControl::TX_ENABLE);
+    assert!(!sio.interrupting());
+
+    // The buffer emptying interrupts, not the buffer being empty
+    sio.write(B_DATA, b'x');
+    tick_for(&mut sio, 1);
+    assert!(sio.interrupting());
+    sio.write(B_CONTROL, Command::RESET_TX_PENDING);
+    assert!(!sio.interrupting());
+
+    sio.write(B_DATA, b'y');
+    tick_chars(&mut sio, 1);
+    assert!(sio.interrupting());
+    sio.write(B_DATA, b'z');
+    assert!(!sio.interrupting());
+}
+
+#[test]
+fn ext_status_interrupt() {
+    let mut sio = sio();
+    write_register(&mut sio, A_CONTROL, 1, InterruptControl::EXT_ENABLE);
+    tick_for(&mut sio, 1);
+    assert!(!sio.interrupting());
+    assert_eq!(sio.read(A_CONTROL) & Status::CTS, Status::CTS);
+
+    sio.channels[0].handle.cts = false;
+    tick_for(&mut sio, 1);
+    assert!(sio.interrupting());
+    assert_eq!(sio.read(A_CONTROL) & Status::CTS, 0);
+    sio.write(A_CONTROL, Command::RESET_EXT_STATUS);
+    assert!(!sio.interrupting());
+}
+
+#[test]
+fn auto_enables() {
+    let mut sio = sio();
+    write_register(
+        &mut sio,
+        A_CONTROL,
+        3,
+        RxControl::BITS | RxControl::AUTO_ENABLES | RxControl::ENABLE,
+    );
+    sio.channels[0].handle.cts = false;
+    sio.write(A_DATA, b'x');
+    tick_chars(&mut sio, 2);
+    assert!(sio.channels[0].handle.tx.is_empty());
+
+    sio.channels[0].handle.cts = true;
+    tick_chars(&mut sio, 2);
+    assert_eq!(sio.channels[0].handle.tx, b"x");
+}
+
+#[test]
+fn status_affects_vector() {
+    let mut sio = sio();
+    write_register(&mut sio, B_CONTROL, 2, 0x40);
+    assert_eq!(read_register(&mut sio, B_CONTROL, 2), 0x40);
+
+    write_register(
+        &mut sio,
+        B_CONTROL,
+        1,
+        InterruptControl::STATUS_AFFECTS_VECTOR | InterruptControl::TX_ENABLE,
+    );
+    // Nothing pending
+    assert_eq!(read_register(&mut sio, B_CONTROL, 2), 0x46);
+    assert_eq!(sio.read(A_CONTROL) & Status::INT_PENDING, 0);
+
+    sio.write(B_DATA, b'x');
+    tick_for(&mut sio, 1);
+    assert_eq!(read_register(&mut sio, B_CONTROL, 2), 0x40);
+    assert_eq!(
+        sio.read(A_CONTROL) & Status::INT_PENDING,
+        Status::INT_PENDING
+    );
+
+    // Channel A has priority
+    write_register(&mut sio, A_CONTROL, 1, InterruptControl::RX_MODE);
+    sio.channels[0].handle.rx.push_back(b'y');
+    tick_chars(&mut sio, 2);
+    assert_eq!(read_register(&mut sio, B_CONTROL, 2), 0x4C);
+
+    sio.channels[0].handle.rx.extend(b"abc");
+    tick_chars(&mut sio, 4);
+    assert_eq!(read_register(&mut sio, B_CONTROL, 2), 0x4E);
+}
+
+#[test]
+fn interrupt_service() {
+    let mut sio = sio();
+    write_register(&mut sio, B_CONTROL, 2, 0x20);
+    write_register(
+        &mut sio,
+        B_CONTROL,
+        1,
+        InterruptControl::STATUS_AFFECTS_VECTOR | InterruptControl::TX_ENABLE,
+    );
+    write_register(&mut sio, A_CONTROL, 1, InterruptControl::RX_MODE);
+
+    sio.write(B_DATA, b'x');
+    tick_for(&mut sio, 1);
+    assert!(sio.interrupting());
+    assert_eq!(sio.acknowledge(), Some(0x20));
+    assert!(sio.in_service());
+    assert!(!sio.interrupting());
+
+    // A higher priority interrupt can nest
+    sio.channels[0].handle.rx.push_back(b'y');
+    tick_chars(&mut sio, 2);
+    assert!(sio.interrupting());
+    assert_eq!(sio.acknowledge(), Some(0x2C));
+    assert!(!sio.interrupting());
+    sio.read(A_DATA);
+    sio.reti();
+
+    // Back to servicing channel B, which is still pending
+    assert!(sio.in_service());
+    assert!(!sio.interrupting());
+    sio.reti();
+    assert!(!sio.in_service());
+    assert!(sio.interrupting());
+
+    // Return from interrupt on channel A works the same as RETI
+    sio.acknowledge();
+    sio.write(A_CONTROL, Command::RETURN_FROM_INT);
+    assert!(!sio.in_service());
+}
+
+#[test]
+fn channel_reset() {
+    let mut sio = sio();
+    write_register(&mut sio, A_CONTROL, 1, InterruptControl::RX_MODE);
+    sio.channels[0].handle.rx.extend(b"ab");
+    tick_chars(&mut sio, 2);
+    assert!(sio.interrupting());
+
+    sio.write(A_CONTROL, Command::CHANNEL_RESET);
+    assert!(!sio.interrupting());
+    assert_eq!(sio.read(A_CONTROL) & Status::RX_AVAILABLE, 0);
+    assert_eq!(sio.channels[0].interrupt_control, 0);
+    assert_eq!(sio.channels[0].tx_control, 0);
+
+    // The other channel is untouched
+    assert_ne!(sio.channels[1].tx_control, 0);
+}
@@ -1,101 +1,378 @@
 //! The whole system tied together. Implements the shared bus.
 
+#[cfg(test)]
+mod tests;
+
+use std::io;
+
 use crate::{
-    bus::{Bus, Device, InterruptBus, NullBus},
+    bus::{Bus, Device, DeviceBus, InterruptBus, NullBus},
     cpu::Cpu,
+    ctc::Ctc,
+    dma::Dma,
+    mmu::Mmu,
+    pic::Pic,
     vdc::{Framebuffer, Vdc},
 };
 
+/// What the DMA bank register selects
 const BANK_SIZE: usize = 0x10000;
-const BANK_MAX: usize = 0x1F;
-const BANK_SHADOW_SIZE: u16 = 0x0400;
+
+/// Windows have bank registers at consecutive ports
+const MAX_WINDOWS: usize = 4;
 
 struct IOAddr;
 impl IOAddr {
     const IC: u16 = 0x00;
     const BANK: u16 = 0x01;
     const KB: u16 = 0x02;
+    const KB_STATUS: u16 = 0x03;
+    const KB_MODIFIERS: u16 = 0x04;
+    const ROM: u16 = 0x05;
+    const IC_MASK: u16 = 0x08;
+    const IC_PENDING: u16 = 0x09;
+    const IC_IN_SERVICE: u16 = 0x0A;
+    const IC_PRIORITY: u16 = 0x0B;
 
     const SER1: u16 = 0x10;
     const SER2: u16 = 0x18;
     const HD: u16 = 0x20;
+    const CTC: u16 = 0x30;
     const VDC: u16 = 0x40;
+    const DMA: u16 = 0x50;
+    const DMA_BANK: u16 = 0x58;
+    const WINDOW_BANKS: u16 = 0x60;
+    const MMU: u16 = 0x70;
 }
 
-struct InterruptPriority;
-impl InterruptPriority {
-    const SER1: u8 = 0x00;
-    const SER2: u8 = 0x01;
-    const HD: u8 = 0x02;
-    const VDC: u8 = 0x03;
+/// The request lines of the interrupt controller. By default, the lower the line the
+/// higher the priority. Z80 family devices are also daisy chained in this order.
+struct InterruptLine;
+impl InterruptLine {
+    const SER1: usize = 0x00;
+    const SER2: usize = 0x01;
+    const HD: usize = 0x02;
+    const VDC: usize = 0x03;
+    const KB: usize = 0x04;
+    const CTC: usize = 0x05;
+    const DMA: usize = 0x06;
 }
 
 pub struct System {
     cpu: Cpu,
+    rom: BootRom,
     bank: BankSelect,
     ram: Vec<u8>,
+    pic: Pic,
     hd: Option<Box<dyn Device>>,
+    ctc: Ctc,
+    dma: Dma,
     vdc: Vdc,
     kb: Box<dyn Device>,
+    ser1: Box<dyn Device>,
+    ser2: Option<Box<dyn Device>>,
 }
 
-#[inline]
-fn read(ram: &[u8], offset: usize, addr: u16) -> u8 {
-    if addr < BANK_SHADOW_SIZE {
-        ram[addr as usize]
-    } else {
-        ram[addr as usize + offset]
-    }
+/// How the address space is split up between banks of RAM
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryLayout {
+    /// RAM in multiples of 64K
+    pub ram_banks: usize,
+
+    /// The address space is split into this many windows (1, 2 or 4) of equal size,
+    /// each switched by a bank register of its own. A bank is a window sized piece of
+    /// RAM.
+    pub windows: usize,
+
+    /// The common area is never switched. It always sees the same addresses in the first
+    /// 64K of RAM.
+    pub common_base: u16,
+    pub common_size: usize,
+
+    /// Adds the MMU, which software can enable to page the address space in place of
+    /// the windows
+    pub mmu: bool,
 }
 
-#[inline]
-fn write(ram: &mut [u8], offset: usize, addr: u16, data: u8) {
-    if addr < BANK_SHADOW_SIZE {
-        ram[addr as usize] = data
-    } else {
-        ram[addr as usize + offset] = data
+impl Default for MemoryLayout {
+    /// 2MiB switched 64K at a time, around a 1K common area at the bottom
+    fn default() -> Self {
+        Self {
+            ram_banks: 0x20,
+            windows: 1,
+            common_base: 0x0000,
+            common_size: 0x0400,
+            mmu: false,
+        }
     }
 }
 
+/// Written to the ROM port to take the boot ROM out of the address space
+const ROM_UNMAP: u8 = 0x01;
+
+/// Read-only memory laid over the bottom of the address space from reset, ahead of any
+/// banking. Writes fall through to the RAM underneath, so the boot code can copy itself
+/// there before unmapping the ROM.
 #[derive(Default)]
+pub struct BootRom {
+    data: Vec<u8>,
+    unmapped: bool,
+}
+
+impl BootRom {
+    #[inline]
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        if self.unmapped {
+            None
+        } else {
+            self.data.get(addr as usize).copied()
+        }
+    }
+
+    /// Bit 0 unmaps the ROM. Clearing it maps the ROM back in.
+    #[inline]
+    pub fn control(&mut self, data: u8) {
+        self.unmapped = (data & ROM_UNMAP) != 0;
+    }
+
+    #[inline]
+    pub fn status(&self) -> u8 {
+        if self.unmapped {
+            ROM_UNMAP
+        } else {
+            0
+        }
+    }
+}
+
+/// Set in the DMA bank register to make the DMA follow the CPU's banks
+const DMA_FOLLOWS_CPU: u8 = 0x80;
+
+/// The DMA sees the same common area as the CPU. Outside of it, it either sees the CPU's
+/// banks or a 64K bank of its own.
 pub struct BankSelect {
-    bank: usize,
-    offset: usize,
+    layout: MemoryLayout,
+    window_size: usize,
+    banks: [usize; MAX_WINDOWS],
+    dma_bank: Option<usize>,
+    mmu: Option<Mmu>,
 }
 
 impl BankSelect {
+    /// To begin with, the windows map the first 64K of RAM in order
+    pub fn new(layout: MemoryLayout) -> Self {
+        assert!(
+            matches!(layout.windows, 1 | 2 | 4),
+            "The address space can only be split into 1, 2 or 4 windows"
+        );
+        let window_size = BANK_SIZE / layout.windows;
+        assert!(
+            (1..=(0x100 / layout.windows)).contains(&layout.ram_banks),
+            "Bank registers can only select 256 banks"
+        );
+        assert!(
+            (layout.common_base as usize) + layout.common_size <= BANK_SIZE,
+            "The common area must fit in the address space"
+        );
+        Self {
+            layout,
+            window_size,
+            banks: [0, 1, 2, 3],
+            dma_bank: None,
+            mmu: layout.mmu.then(|| Mmu::new(layout.ram_banks * BANK_SIZE)),
+        }
+    }
+
     #[inline]
-    pub fn select(&mut self, bank: u8) {
-        self.bank = (bank as usize) & BANK_MAX;
-        self.offset = self.bank * BANK_SIZE;
+    pub fn windows(&self) -> usize {
+        self.layout.windows
     }
 
     #[inline]
-    pub fn bank(&self) -> u8 {
-        self.bank as u8
+    fn bank_count(&self) -> usize {
+        self.layout.ram_banks * self.layout.windows
     }
 
     #[inline]
-    pub fn ram_offset(&self) -> usize {
-        self.offset
+    pub fn select(&mut self, window: usize, bank: u8) {
+        self.banks[window] = (bank as usize) % self.bank_count();
+    }
+
+    #[inline]
+    pub fn bank(&self, window: usize) -> u8 {
+        self.banks[window] as u8
+    }
+
+    #[inline]
+    fn in_common(&self, addr: u16) -> bool {
+        let offset = addr.wrapping_sub(self.layout.common_base) as usize;
+        offset < self.layout.common_size
+    }
+
+    #[inline]
+    fn mmu_enabled(&self) -> Option<&Mmu> {
+        self.mmu.as_ref().filter(|mmu| mmu.enabled())
+    }
+
+    /// Where in RAM the CPU sees an address
+    pub fn ram_addr(&self, addr: u16) -> usize {
+        if let Some(mmu) = self.mmu_enabled() {
+            return mmu.ram_addr(addr);
+        }
+        if self.in_common(addr) {
+            return addr as usize;
+        }
+        let window = (addr as usize) / self.window_size;
+        (self.banks[window] * self.window_size) + ((addr as usize) % self.window_size)
+    }
+
+    /// Where in RAM a CPU access goes, or `None` if the MMU faulted it
+    pub fn access(&mut self, addr: u16, write: bool) -> Option<usize> {
+        match self.mmu.as_mut() {
+            Some(mmu) if mmu.enabled() => mmu.access(addr, write),
+            _ => Some(self.ram_addr(addr)),
+        }
+    }
+
+    #[inline]
+    pub fn take_nmi(&mut self) -> bool {
+        self.mmu.as_mut().map(Mmu::take_nmi).unwrap_or(false)
+    }
+
+    #[inline]
+    pub fn mmu(&self) -> Option<&Mmu> {
+        self.mmu.as_ref()
+    }
+
+    #[inline]
+    pub fn mmu_mut(&mut self) -> Option<&mut Mmu> {
+        self.mmu.as_mut()
+    }
+
+    #[inline]
+    pub fn select_dma(&mut self, data: u8) {
+        self.dma_bank = if (data & DMA_FOLLOWS_CPU) != 0 {
+            None
+        } else {
+            Some((data as usize) % self.layout.ram_banks)
+        };
+    }
+
+    #[inline]
+    pub fn dma_bank(&self) -> u8 {
+        match self.dma_bank {
+            Some(bank) => bank as u8,
+            None => DMA_FOLLOWS_CPU,
+        }
+    }
+
+    /// Where in RAM the DMA sees an address
+    pub fn dma_ram_addr(&self, addr: u16) -> usize {
+        match self.dma_bank {
+            // The MMU takes the common area away
+            Some(bank) if self.mmu_enabled().is_some() || !self.in_common(addr) => {
+                (bank * BANK_SIZE) + (addr as usize)
+            }
+            _ => self.ram_addr(addr),
+        }
     }
 }
 
 struct CpuView<'a> {
+    rom: &'a mut BootRom,
     bank: &'a mut BankSelect,
     ram: &'a mut Vec<u8>,
+    pic: &'a mut Pic,
     hd: &'a mut Option<&'a mut Box<dyn Device>>,
+    ctc: &'a mut Ctc,
+    /// Empty while the DMA is the one using the bus
+    dma: Option<&'a mut Dma>,
     vdc: &'a mut Vdc,
     kb: &'a mut dyn Device,
+    ser1: &'a mut dyn Device,
+    ser2: &'a mut Option<&'a mut Box<dyn Device>>,
+}
+
+impl<'a> CpuView<'a> {
+    /// Where in RAM whoever has the bus accesses an address. Only the CPU's accesses
+    /// can fault.
+    #[inline]
+    fn access(&mut self, addr: u16, write: bool) -> Option<usize> {
+        if self.dma.is_none() {
+            Some(self.bank.dma_ram_addr(addr))
+        } else {
+            self.bank.access(addr, write)
+        }
+    }
+
+    /// The device on each interrupt line
+    fn devices(&mut self) -> [Option<&mut (dyn Device + 'a)>; 8] {
+        let mut devices: [Option<&mut (dyn Device + 'a)>; 8] = Default::default();
+        devices[InterruptLine::SER1] = Some(&mut *self.ser1);
+        devices[InterruptLine::SER2] = self.ser2.as_mut().map(|ser2| &mut ***ser2 as _);
+        devices[InterruptLine::HD] = self.hd.as_mut().map(|hd| &mut ***hd as _);
+        devices[InterruptLine::VDC] = Some(&mut *self.vdc);
+        devices[InterruptLine::KB] = Some(&mut *self.kb);
+        devices[InterruptLine::CTC] = Some(&mut *self.ctc);
+        devices[InterruptLine::DMA] = self.dma.as_mut().map(|dma| &mut **dma as _);
+        devices
+    }
+
+    /// The DMA request line of the device at an I/O port
+    fn ready(&self, port: u16, output: bool) -> bool {
+        let port = port & 0xFF;
+        match port & 0xF0 {
+            IOAddr::SER1 => match (port & 0xF8, &self.ser2) {
+                // The SIO's second channel is in the first block too
+                (IOAddr::SER1, _) => self.ser1.ready(port, output),
+                (IOAddr::SER2, Some(ser2)) => ser2.ready(port, output),
+                _ => false,
+            },
+
+            IOAddr::HD => match self.hd.as_ref() {
+                Some(hd) => hd.ready(port, output),
+                _ => false,
+            },
+
+            _ => false,
+        }
+    }
+
+    /// Latches the request lines into the interrupt controller. A device with an
+    /// interrupt under service holds off the ones after it in the daisy chain.
+    fn update_requests(&mut self) {
+        let mut requests = 0;
+        for (line, device) in self.devices().into_iter().enumerate() {
+            let Some(device) = device else {
+                continue;
+            };
+            if device.interrupting() {
+                requests |= 1 << line;
+            }
+            if device.in_service() {
+                break;
+            }
+        }
+        self.pic.set_requests(requests);
+    }
 }
 
 impl<'a> Bus for CpuView<'a> {
     fn read(&mut self, addr: u16) -> u8 {
-        read(&self.ram, self.bank.ram_offset(), addr)
+        match self.rom.read(addr) {
+            Some(data) => data,
+            None => match self.access(addr, false) {
+                Some(addr) => self.ram[addr],
+                // A faulting fetch runs as a NOP, so the NMI returns just past it
+                None => 0x00,
+            },
+        }
     }
 
     fn write(&mut self, addr: u16, data: u8) {
-        write(&mut self.ram, self.bank.ram_offset(), addr, data);
+        if let Some(addr) = self.access(addr, true) {
+            self.ram[addr] = data;
+        }
     }
 
     fn input(&mut self, port: u16) -> u8 {
@@ -106,29 +383,69 @@ impl<'a> Bus for CpuView<'a> {
             // as the IC
             IOAddr::IC => match port {
                 IOAddr::IC => {
-                    if let Some(hd) = self.hd && hd.interrupting() {
-                        return InterruptPriority::HD;
-                    }
-                    if self.vdc.interrupting() {
-                        return InterruptPriority::VDC;
+                    self.update_requests();
+                    let line = self.pic.acknowledge();
+                    if let Some(Some(device)) = self.devices().get_mut(line as usize) {
+                        device.polled();
                     }
-                    todo!("Read PIC when not in interrupt. Undefined state");
+                    line
                 }
 
-                IOAddr::KB => self.kb.read(port),
+                IOAddr::IC_MASK => self.pic.mask(),
+
+                IOAddr::IC_PENDING => {
+                    self.update_requests();
+                    self.pic.requests()
+                }
+
+                IOAddr::IC_IN_SERVICE => self.pic.in_service(),
+
+                IOAddr::IC_PRIORITY => self.pic.priority(),
+
+                IOAddr::KB | IOAddr::KB_STATUS | IOAddr::KB_MODIFIERS => {
+                    self.kb.read(port - IOAddr::KB)
+                }
 
-                IOAddr::BANK => self.bank.bank(),
+                IOAddr::BANK => self.bank.bank(0),
+
+                IOAddr::ROM => self.rom.status(),
 
                 _ => 0,
             },
 
+            // Both serial ports share a block
+            IOAddr::SER1 => match (port & 0xF8, &mut self.ser2) {
+                (IOAddr::SER1, _) => self.ser1.read(port),
+                (IOAddr::SER2, Some(ser2)) => ser2.read(port),
+                (IOAddr::SER2, None) => 0,
+                _ => unreachable!(),
+            },
+
             IOAddr::HD => match self.hd {
                 Some(hd) => hd.read(port),
                 _ => 0,
             },
 
+            IOAddr::CTC => self.ctc.read(port),
+
             IOAddr::VDC => self.vdc.read(port),
 
+            IOAddr::DMA => match (port, &mut self.dma) {
+                (IOAddr::DMA_BANK, _) => self.bank.dma_bank(),
+                (_, Some(dma)) => dma.read(port),
+                _ => 0,
+            },
+
+            IOAddr::WINDOW_BANKS => match (port - IOAddr::WINDOW_BANKS) as usize {
+                window if window < self.bank.windows() => self.bank.bank(window),
+                _ => 0,
+            },
+
+            IOAddr::MMU => match self.bank.mmu() {
+                Some(mmu) => mmu.read(port),
+                None => 0,
+            },
+
             _ => 0,
         }
     }
@@ -140,82 +457,187 @@ impl<'a> Bus for CpuView<'a> {
             // The lowest ports all mask to the same space
             // as the IC
             IOAddr::IC => match port {
-                IOAddr::KB => self.kb.write(port, data),
+                IOAddr::IC => self.pic.command(data),
+
+                IOAddr::IC_MASK => self.pic.set_mask(data),
+
+                IOAddr::IC_PRIORITY => self.pic.set_priority(data),
+
+                IOAddr::KB | IOAddr::KB_STATUS | IOAddr::KB_MODIFIERS => {
+                    self.kb.write(port - IOAddr::KB, data)
+                }
+
+                IOAddr::BANK => self.bank.select(0, data),
 
-                IOAddr::BANK => self.bank.select(data),
+                IOAddr::ROM => self.rom.control(data),
 
                 _ => {}
             },
 
+            // Both serial ports share a block
+            IOAddr::SER1 => match (port & 0xF8, &mut self.ser2) {
+                (IOAddr::SER1, _) => self.ser1.write(port, data),
+                (IOAddr::SER2, Some(ser2)) => ser2.write(port, data),
+                (IOAddr::SER2, None) => {}
+                _ => unreachable!(),
+            },
+
             IOAddr::HD => {
                 if let Some(hd) = self.hd {
                     hd.write(port, data);
                 }
             }
 
+            IOAddr::CTC => self.ctc.write(port, data),
+
             IOAddr::VDC => self.vdc.write(port, data),
 
+            IOAddr::DMA => match (port, &mut self.dma) {
+                (IOAddr::DMA_BANK, _) => self.bank.select_dma(data),
+                (_, Some(dma)) => dma.write(port, data),
+                _ => {}
+            },
+
+            IOAddr::WINDOW_BANKS => match (port - IOAddr::WINDOW_BANKS) as usize {
+                window if window < self.bank.windows() => self.bank.select(window, data),
+                _ => {}
+            },
+
+            IOAddr::MMU => {
+                if let Some(mmu) = self.bank.mmu_mut() {
+                    mmu.write(port, data);
+                }
+            }
+
             _ => {}
         }
     }
 }
 
+impl<'a> DeviceBus for CpuView<'a> {}
+
 impl<'a> InterruptBus for CpuView<'a> {
+    fn nmi(&mut self) -> bool {
+        self.bank.take_nmi()
+    }
+
     fn interrupted(&mut self) -> bool {
-        if let Some(hd) = self.hd && hd.interrupting() {
-            return true;
-        }
-        if self.vdc.interrupting() {
-            return true;
+        self.update_requests();
+        self.pic.interrupting()
+    }
+
+    /// The device on the active line supplies the vector
+    fn acknowledge(&mut self) -> u8 {
+        self.update_requests();
+        let device = self
+            .pic
+            .active()
+            .and_then(|line| self.devices()[line as usize].take());
+        device
+            .and_then(|device| device.acknowledge())
+            .unwrap_or(0xFF)
+    }
+
+    fn reti(&mut self) {
+        // Only the highest priority interrupt under service is ended
+        let mut devices = self.devices().into_iter().flatten();
+        if let Some(device) = devices.find(|device| device.in_service()) {
+            device.reti();
         }
-        false
     }
 }
 
 impl System {
-    pub fn new(kb: Box<dyn Device>, hd: Option<Box<dyn Device>>) -> Self {
+    /// The rate the CPU runs at. Devices that care about real time (like the UARTs)
+    /// count their time in these cycles.
+    pub const CLOCK_HZ: usize = 7_372_800;
+
+    /// A dual channel serial controller (like the SIO) at `ser1` serves both lines, so
+    /// `ser2` can be left empty.
+    pub fn new(
+        memory: MemoryLayout,
+        kb: Box<dyn Device>,
+        hd: Option<Box<dyn Device>>,
+        ser1: Box<dyn Device>,
+        ser2: Option<Box<dyn Device>>,
+    ) -> Self {
         Self {
             cpu: Cpu::default(),
-            bank: BankSelect::default(),
-            ram: vec![0; 0x10000 * 0x20],
+            rom: BootRom::default(),
+            bank: BankSelect::new(memory),
+            ram: vec![0; memory.ram_banks * BANK_SIZE],
+            pic: Pic::new(),
             hd,
+            ctc: Ctc::new(),
+            dma: Dma::default(),
             vdc: Vdc::new(),
             kb,
+            ser1,
+            ser2,
         }
     }
 
     pub fn step(&mut self) -> usize {
         let Self {
             cpu,
+            rom,
             bank,
             ram,
+            pic,
             hd,
+            ctc,
+            dma,
             vdc,
             kb,
+            ser1,
+            ser2,
             ..
         } = self;
 
-        let cycles = cpu.step(&mut CpuView {
+        let mut view = CpuView {
+            rom,
             bank,
             ram,
+            pic,
             hd: &mut hd.as_mut(),
+            ctc,
+            dma: None,
             vdc,
             kb: kb.as_mut(),
-        });
-
-        // Process devices that run in parallel with CPU
-        // TODO: For DMA devices, they compete with the CPU for the bus.
-        //   depending on the transfer mode they may hold the bus or only
-        //   take it for ~3/4 of CPU cycles.
-        //   Overall I will probably have:
-        //   * A DMA device for serial port 1
-        //   * A DMA device for serial port 2 (can I share 1?)
-        //   * A DMA device for the CF bus
-        //   * And maybe an extra for the VDC (though that requires emulating an 8564
-        //     for the VDC RDY signal)
+            ser1: ser1.as_mut(),
+            ser2: &mut ser2.as_mut(),
+        };
+
+        // RDY comes from the device the DMA is transferring to or from
+        let rdy = match dma.ready_port() {
+            Some((port, output)) => view.ready(port, output),
+            None => false,
+        };
+        dma.set_ready(rdy);
+
+        // The CPU grants BUSREQ at the end of each instruction and stalls while the DMA
+        // holds the bus.
+        let cycles = if dma.bus_requested() {
+            dma.transfer(&mut view)
+        } else {
+            dma.release_bus();
+            view.dma = Some(dma);
+            cpu.step(&mut view)
+        };
+
+        // Process devices that run in parallel with CPU (or DMA)
         for _ in 0..cycles {
             vdc.tick(&mut NullBus {});
+            ser1.tick(&mut NullBus {});
+            if let Some(ser2) = ser2 {
+                ser2.tick(&mut NullBus {});
+            }
         }
+        // The CTC runs off the CPU clock, so it can catch up all at once
+        ctc.advance(cycles);
+        // The keyboard has no sense of time. It only needs to catch up with the host once
+        // per instruction.
+        kb.tick(&mut NullBus {});
         cycles
     }
 
@@ -234,6 +656,21 @@ impl System {
         self.vdc.framebuffer()
     }
 
+    /// Lays the boot ROM over the bottom of the address space, as it is from reset
+    pub fn load_rom(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() > 0x10000 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The ROM must fit in the address space",
+            ));
+        }
+        self.rom = BootRom {
+            data: data.to_vec(),
+            unmapped: false,
+        };
+        Ok(())
+    }
+
     #[inline]
     pub fn write_ram(&mut self, data: &[u8], offset: usize) {
         for (i, b) in data.iter().enumerate() {
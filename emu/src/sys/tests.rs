@@ -0,0 +1,390 @@
+use super::*;
+use crate::{sio::Sio, term::Terminal};
+
+struct NullDevice;
+
+impl Device for NullDevice {
+    fn tick(&mut self, _: &mut dyn DeviceBus) {}
+
+    fn read(&mut self, _: u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, _: u16, _: u8) {}
+
+    fn interrupting(&self) -> bool {
+        false
+    }
+}
+
+fn system() -> System {
+    system_with(MemoryLayout::default())
+}
+
+fn system_with(memory: MemoryLayout) -> System {
+    System::new(
+        memory,
+        Box::new(NullDevice),
+        None,
+        Box::new(NullDevice),
+        None,
+    )
+}
+
+/// Runs until the CPU halts and the DMA is done
+fn run(system: &mut System) {
+    for _ in 0..1000 {
+        if system.halted() && !system.dma.bus_requested() {
+            return;
+        }
+        system.step();
+    }
+    panic!("Never finished");
+}
+
+/// Copies 4 bytes from 0x0100 to 0x8000
+const DMA_COPY: [u8; 12] = [
+    0x7D, // wr0: transfer a -> b, a address and length follow
+    0x00, 0x01, // a address: 0x0100
+    0x04, 0x00, // length: 4
+    0x14, // wr1: a is memory, increment
+    0x10, // wr2: b is memory, increment
+    0xAD, // wr4: continuous mode, b address follows
+    0x00, 0x80, // b address: 0x8000
+    0xCF, // Load
+    0x87, // Enable DMA
+];
+
+/// Selects the banks, programs the DMA from 0x0200, and halts
+fn dma_program(bank: u8, dma_bank: u8) -> Vec<u8> {
+    vec![
+        0x3E, bank, // ld a, bank
+        0xD3, 0x01, // out (0x01), a
+        0x3E, dma_bank, // ld a, dma_bank
+        0xD3, 0x58, // out (0x58), a
+        0x21, 0x00, 0x02, // ld hl, 0x0200
+        0x01, 0x50, 0x0C, // ld bc, 0x0C50
+        0xED, 0xB3, // otir
+        0x76, // halt
+    ]
+}
+
+fn load(system: &mut System, program: &[u8]) {
+    system.write_ram(program, 0x0000);
+    system.write_ram(&[1, 2, 3, 4], 0x0100);
+    system.write_ram(&DMA_COPY, 0x0200);
+}
+
+#[test]
+fn dma_follows_cpu_bank() {
+    let mut system = system();
+    load(&mut system, &dma_program(3, DMA_FOLLOWS_CPU));
+    run(&mut system);
+    assert_eq!(system.ram[(3 * BANK_SIZE) + 0x8000..][..4], [1, 2, 3, 4]);
+}
+
+#[test]
+fn dma_own_bank() {
+    let mut system = system();
+    load(&mut system, &dma_program(3, 5));
+    run(&mut system);
+    // The source is in the common area, which every bank shares
+    assert_eq!(system.ram[(5 * BANK_SIZE) + 0x8000..][..4], [1, 2, 3, 4]);
+    assert_eq!(system.ram[(3 * BANK_SIZE) + 0x8000..][..4], [0, 0, 0, 0]);
+    assert_eq!(system.bank.dma_bank(), 5);
+}
+
+#[test]
+fn dma_from_sio() {
+    let terminal = Terminal::default();
+    let mut system = System::new(
+        MemoryLayout::default(),
+        Box::new(NullDevice),
+        None,
+        Box::new(Sio::new(
+            Terminal::default(),
+            terminal.clone(),
+            System::CLOCK_HZ,
+        )),
+        None,
+    );
+    // Sets up channel B, then has the DMA copy from it, paced by its W/RDY line
+    system.write_ram(
+        &[
+            0x21, 0x00, 0x02, // ld hl, 0x0200
+            0x01, 0x13, 0x07, // ld bc, 0x0713
+            0xED, 0xB3, // otir
+            0x01, 0x50, 0x0D, // ld bc, 0x0D50
+            0xED, 0xB3, // otir
+            0x76, // halt
+        ],
+        0x0000,
+    );
+    system.write_ram(
+        &[
+            0x18, // Channel reset
+            0x04, 0x44, // wr4: x16 clock, 1 stop bit
+            0x03, 0xC1, // wr3: 8 bits, receiver enable
+            0x05, 0x68, // wr5: 8 bits, transmitter enable
+        ],
+        0x0200,
+    );
+    system.write_ram(
+        &[
+            0x7D, // wr0: transfer a -> b, a address and length follow
+            0x12, 0x00, // a address: 0x0012
+            0x02, 0x00, // length: 2
+            0x2C, // wr1: a is io, fixed
+            0x10, // wr2: b is memory, increment
+            0x8D, // wr4: byte mode, b address follows
+            0x00, 0x01, // b address: 0x0100
+            0x8A, // wr5: ready active high
+            0xCF, // Load
+            0x87, // Enable DMA
+        ],
+        0x0207,
+    );
+
+    // Nothing is copied until something is received
+    for _ in 0..10_000 {
+        system.step();
+    }
+    assert!(system.halted());
+    assert_eq!(system.ram[0x0100], 0x00);
+
+    terminal.text("hi");
+    for _ in 0..100_000 {
+        system.step();
+    }
+    assert_eq!(system.ram[0x0100..0x0103], *b"hi\0");
+}
+
+#[test]
+fn default_layout() {
+    let mut bank = BankSelect::new(MemoryLayout::default());
+    bank.select(0, 3);
+    assert_eq!(bank.ram_addr(0x03FF), 0x03FF);
+    assert_eq!(bank.ram_addr(0x0400), (3 * BANK_SIZE) + 0x0400);
+    assert_eq!(bank.ram_addr(0xFFFF), (3 * BANK_SIZE) + 0xFFFF);
+    // Banks past the end of RAM wrap around
+    bank.select(0, 0x21);
+    assert_eq!(bank.bank(0), 0x01);
+}
+
+#[test]
+fn windows() {
+    let mut bank = BankSelect::new(MemoryLayout {
+        ram_banks: 8,
+        windows: 4,
+        common_base: 0xC000,
+        common_size: 0x4000,
+        mmu: false,
+    });
+    // Out of reset the windows map the first 64K in order
+    assert_eq!(bank.ram_addr(0x4123), 0x4123);
+    assert_eq!(bank.ram_addr(0x8123), 0x8123);
+
+    bank.select(0, 0x10);
+    bank.select(1, 0x11);
+    bank.select(2, 0x02);
+    bank.select(3, 0x1F);
+    assert_eq!(bank.ram_addr(0x0123), 0x40123);
+    assert_eq!(bank.ram_addr(0x4123), 0x44123);
+    assert_eq!(bank.ram_addr(0xBFFF), 0x0BFFF);
+    // The top window is all common area
+    assert_eq!(bank.ram_addr(0xC000), 0x0C000);
+    assert_eq!(bank.ram_addr(0xFFFF), 0x0FFFF);
+}
+
+#[test]
+fn window_bank_ports() {
+    let mut system = system_with(MemoryLayout {
+        ram_banks: 4,
+        windows: 2,
+        common_base: 0xF000,
+        common_size: 0x1000,
+        mmu: false,
+    });
+    system.write_ram(
+        &[
+            0x3E, 0x05, // ld a, 0x05
+            0xD3, 0x61, // out (0x61), a
+            0x3E, 0xAA, // ld a, 0xAA
+            0x32, 0x00, 0x80, // ld (0x8000), a
+            0x32, 0x00, 0xF0, // ld (0xF000), a
+            0xDB, 0x61, // in a, (0x61)
+            0x76, // halt
+        ],
+        0x0000,
+    );
+    run(&mut system);
+    assert_eq!(system.ram[5 * 0x8000], 0xAA);
+    assert_eq!(system.ram[0x8000], 0x00);
+    assert_eq!(system.ram[0xF000], 0xAA);
+    assert_eq!(system.bank.bank(1), 0x05);
+}
+
+/// Copies itself into the RAM under the ROM, unmaps the ROM, and then reads back a
+/// write over the first instruction
+const SHADOW_BOOT: [u8; 25] = [
+    0x21, 0x00, 0x00, // ld hl, 0x0000
+    0x11, 0x00, 0x00, // ld de, 0x0000
+    0x01, 0x19, 0x00, // ld bc, 25
+    0xED, 0xB0, // ldir
+    0x3E, 0x01, // ld a, 0x01
+    0xD3, 0x05, // out (0x05), a
+    0x32, 0x01, 0x00, // ld (0x0001), a
+    0x3A, 0x01, 0x00, // ld a, (0x0001)
+    0x32, 0x00, 0x01, // ld (0x0100), a
+    0x76, // halt
+];
+
+#[test]
+fn rom_is_read_only() {
+    let mut system = system();
+    system
+        .load_rom(&[
+            0x3E, 0xAA, // ld a, 0xAA
+            0x32, 0x00, 0x00, // ld (0x0000), a
+            0x3A, 0x00, 0x00, // ld a, (0x0000)
+            0x32, 0x00, 0x01, // ld (0x0100), a
+            0x76, // halt
+        ])
+        .unwrap();
+    run(&mut system);
+    // The write went to the RAM underneath, but reads still see the ROM
+    assert_eq!(system.ram[0x0000], 0xAA);
+    assert_eq!(system.ram[0x0100], 0x3E);
+}
+
+#[test]
+fn rom_too_big() {
+    let mut system = system();
+    assert!(system.load_rom(&[0; 0x10000]).is_ok());
+    assert!(system.load_rom(&[0; 0x10001]).is_err());
+}
+
+#[test]
+fn shadow_boot() {
+    let mut system = system();
+    system.load_rom(&SHADOW_BOOT).unwrap();
+    run(&mut system);
+    assert_eq!(system.ram[0x02..0x19], SHADOW_BOOT[0x02..]);
+    assert_eq!(system.ram[0x0100], 0x01);
+    assert_eq!(system.rom.status(), ROM_UNMAP);
+}
+
+#[test]
+fn mmu_fault() {
+    let mut system = system_with(MemoryLayout {
+        mmu: true,
+        ..Default::default()
+    });
+    system.write_ram(
+        &[
+            0x3E, 0x08, // ld a, 0x08
+            0xD3, 0x71, // out (0x71), a
+            0x3E, 0x40, // ld a, READ_ONLY
+            0xD3, 0x73, // out (0x73), a
+            0x3E, 0x01, // ld a, ENABLE
+            0xD3, 0x70, // out (0x70), a
+            0x32, 0x34, 0x82, // ld (0x8234), a
+            0x76, // halt
+        ],
+        0x0000,
+    );
+    // The NMI handler reads back the fault
+    system.write_ram(
+        &[
+            0xDB, 0x74, // in a, (0x74)
+            0x32, 0x00, 0x01, // ld (0x0100), a
+            0xDB, 0x75, // in a, (0x75)
+            0x32, 0x01, 0x01, // ld (0x0101), a
+            0xDB, 0x76, // in a, (0x76)
+            0x32, 0x02, 0x01, // ld (0x0102), a
+            0x76, // halt
+        ],
+        0x0066,
+    );
+    run(&mut system);
+    assert_eq!(system.ram[0x8234], 0x00);
+    assert_eq!(system.ram[0x0100..0x0103], [0x81, 0x34, 0x82]);
+}
+
+#[test]
+fn mmu_fetch_fault() {
+    let mut system = system_with(MemoryLayout {
+        mmu: true,
+        ..Default::default()
+    });
+    system.write_ram(
+        &[
+            0x31, 0x00, 0x02, // ld sp, 0x0200
+            0x3E, 0x08, // ld a, 0x08
+            0xD3, 0x71, // out (0x71), a
+            0x3E, 0x80, // ld a, NO_ACCESS
+            0xD3, 0x73, // out (0x73), a
+            0x3E, 0x01, // ld a, ENABLE
+            0xD3, 0x70, // out (0x70), a
+            0xC3, 0x00, 0x80, // jp 0x8000
+        ],
+        0x0000,
+    );
+    // What would be fetched if the page could be read
+    system.write_ram(&[0xFF], 0x8000);
+    // The NMI handler saves its return address and reads back the fault
+    system.write_ram(
+        &[
+            0xE1, // pop hl
+            0x22, 0x00, 0x01, // ld (0x0100), hl
+            0xDB, 0x74, // in a, (0x74)
+            0x32, 0x02, 0x01, // ld (0x0102), a
+            0xDB, 0x75, // in a, (0x75)
+            0x32, 0x03, 0x01, // ld (0x0103), a
+            0xDB, 0x76, // in a, (0x76)
+            0x32, 0x04, 0x01, // ld (0x0104), a
+            0x76, // halt
+        ],
+        0x0066,
+    );
+    run(&mut system);
+    // The faulting opcode ran as a NOP rather than an RST
+    assert_eq!(system.ram[0x0100..0x0102], [0x01, 0x80]);
+    assert_eq!(system.ram[0x0102..0x0105], [0x80, 0x00, 0x80]);
+}
+
+#[test]
+fn polled_interrupt() {
+    let mut system = system();
+    system.write_ram(
+        &[
+            0x3E, 0x85, // ld a, 0x85
+            0xD3, 0x30, // out (0x30), a
+            0x3E, 0x00, // ld a, 0x00
+            0xD3, 0x30, // out (0x30), a
+            0xED, 0x56, // im 1
+            0xFB, // ei
+            0x18, 0xFE, // jr $
+        ],
+        0x0000,
+    );
+    // The handler polls the interrupt controller, ends the interrupt, and polls again
+    system.write_ram(
+        &[
+            0xDB, 0x00, // in a, (0x00)
+            0x32, 0x00, 0x01, // ld (0x0100), a
+            0x3E, 0x20, // ld a, EOI
+            0xD3, 0x00, // out (0x00), a
+            0xDB, 0x00, // in a, (0x00)
+            0x32, 0x01, 0x01, // ld (0x0101), a
+            0x76, // halt
+        ],
+        0x0038,
+    );
+    run(&mut system);
+    assert_eq!(system.ram[0x0100], InterruptLine::CTC as u8);
+    // Polling took the CTC's request without putting it under service
+    assert_eq!(system.ram[0x0101], 0xFF);
+    assert!(!system.ctc.in_service());
+    assert_eq!(system.pic.in_service(), 0);
+}
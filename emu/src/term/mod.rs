@@ -0,0 +1,589 @@
+//! VT100/ANSI Terminal Emulation
+//!
+//! A terminal that can be attached to a serial port in place of a host backend. What
+//! the guest sends is interpreted as VT100 (and common ANSI) control sequences and kept
+//! in a text buffer that can be read back at any time, so it works headless too.
+//!
+//! Frontends report keys through the same kind of calls as [`crate::KeyboardInput`].
+//! Keys are translated to the bytes a VT100 would send.
+//!
+//! Rendering uses a built-in font, so the terminal can be shown without any help
+//! from the guest.
+
+#[cfg(test)]
+mod tests;
+
+mod font;
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    mem,
+    rc::Rc,
+};
+
+use crate::{
+    kb::{self, Key, Modifier},
+    ser::SerialHost,
+    vdc::{self, Framebuffer},
+};
+
+pub const COLUMNS: usize = 80;
+pub const ROWS: usize = 24;
+
+const TAB_WIDTH: usize = 8;
+
+/// Colors are RGBI, just like the VDC's attributes
+struct Color;
+impl Color {
+    const INTENSITY: u8 = 0x01;
+    const BLUE: u8 = 0x02;
+    const GREEN: u8 = 0x04;
+    const RED: u8 = 0x08;
+
+    const DEFAULT_FG: u8 = Self::RED | Self::GREEN | Self::BLUE;
+    const DEFAULT_BG: u8 = 0x00;
+}
+
+/// ANSI colors are numbered with red, green and blue in the low bits
+fn ansi_color(n: u16) -> u8 {
+    let mut color = 0;
+    if (n & 0x01) != 0 {
+        color |= Color::RED;
+    }
+    if (n & 0x02) != 0 {
+        color |= Color::GREEN;
+    }
+    if (n & 0x04) != 0 {
+        color |= Color::BLUE;
+    }
+    color
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Style {
+    fg: u8,
+    bg: u8,
+    bold: bool,
+    underline: bool,
+    reverse: bool,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            fg: Color::DEFAULT_FG,
+            bg: Color::DEFAULT_BG,
+            bold: false,
+            underline: false,
+            reverse: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Cell {
+    code: u8,
+    style: Style,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    /// Selecting a character set. These are ignored.
+    Designate,
+    Csi,
+}
+
+struct Screen {
+    cells: Vec<Cell>,
+    x: usize,
+    y: usize,
+    /// The last column was written. The next character wraps to the next line.
+    wrap_pending: bool,
+    style: Style,
+    saved: (usize, usize, Style),
+    scroll_top: usize,
+    scroll_bottom: usize,
+    autowrap: bool,
+    cursor_visible: bool,
+    state: State,
+    params: Vec<u16>,
+    private: bool,
+}
+
+impl Screen {
+    fn new() -> Self {
+        let style = Style::default();
+        Self {
+            cells: vec![Cell { code: b' ', style }; COLUMNS * ROWS],
+            x: 0,
+            y: 0,
+            wrap_pending: false,
+            style,
+            saved: (0, 0, style),
+            scroll_top: 0,
+            scroll_bottom: ROWS - 1,
+            autowrap: true,
+            cursor_visible: true,
+            state: State::Ground,
+            params: Vec::new(),
+            private: false,
+        }
+    }
+
+    #[inline]
+    fn blank(&self) -> Cell {
+        Cell {
+            code: b' ',
+            style: self.style,
+        }
+    }
+
+    /// Erases the cells from `start` up to (not including) `end`
+    fn erase(&mut self, start: usize, end: usize) {
+        let blank = self.blank();
+        self.cells[start..end].fill(blank);
+    }
+
+    /// Moves rows `top..=bottom` up by `n`, blanking the rows left behind
+    fn scroll_up(&mut self, top: usize, bottom: usize, n: usize) {
+        let n = n.min(bottom + 1 - top);
+        self.cells.copy_within(
+            ((top + n) * COLUMNS)..((bottom + 1) * COLUMNS),
+            top * COLUMNS,
+        );
+        self.erase((bottom + 1 - n) * COLUMNS, (bottom + 1) * COLUMNS);
+    }
+
+    /// Moves rows `top..=bottom` down by `n`, blanking the rows left behind
+    fn scroll_down(&mut self, top: usize, bottom: usize, n: usize) {
+        let n = n.min(bottom + 1 - top);
+        self.cells.copy_within(
+            (top * COLUMNS)..((bottom + 1 - n) * COLUMNS),
+            (top + n) * COLUMNS,
+        );
+        self.erase(top * COLUMNS, (top + n) * COLUMNS);
+    }
+
+    fn line_feed(&mut self) {
+        if self.y == self.scroll_bottom {
+            self.scroll_up(self.scroll_top, self.scroll_bottom, 1);
+        } else if self.y < (ROWS - 1) {
+            self.y += 1;
+        }
+        self.wrap_pending = false;
+    }
+
+    fn reverse_index(&mut self) {
+        if self.y == self.scroll_top {
+            self.scroll_down(self.scroll_top, self.scroll_bottom, 1);
+        } else if self.y > 0 {
+            self.y -= 1;
+        }
+        self.wrap_pending = false;
+    }
+
+    fn put(&mut self, code: u8) {
+        if self.wrap_pending {
+            self.x = 0;
+            self.line_feed();
+        }
+        self.cells[(self.y * COLUMNS) + self.x] = Cell {
+            code,
+            style: self.style,
+        };
+        if self.x < (COLUMNS - 1) {
+            self.x += 1;
+        } else {
+            self.wrap_pending = self.autowrap;
+        }
+    }
+
+    fn move_to(&mut self, x: usize, y: usize) {
+        self.x = x.min(COLUMNS - 1);
+        self.y = y.min(ROWS - 1);
+        self.wrap_pending = false;
+    }
+
+    /// A parameter where 0 means the same as leaving it out
+    fn param(&self, index: usize, default: usize) -> usize {
+        match self.params.get(index) {
+            Some(0) | None => default,
+            Some(param) => *param as usize,
+        }
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        if self.params.is_empty() {
+            self.style = Style::default();
+        }
+        for param in mem::take(&mut self.params) {
+            match param {
+                0 => self.style = Style::default(),
+                1 => self.style.bold = true,
+                4 => self.style.underline = true,
+                7 => self.style.reverse = true,
+                22 => self.style.bold = false,
+                24 => self.style.underline = false,
+                27 => self.style.reverse = false,
+                n @ 30..=37 => self.style.fg = ansi_color(n - 30),
+                39 => self.style.fg = Color::DEFAULT_FG,
+                n @ 40..=47 => self.style.bg = ansi_color(n - 40),
+                49 => self.style.bg = Color::DEFAULT_BG,
+                n @ 90..=97 => self.style.fg = ansi_color(n - 90) | Color::INTENSITY,
+                n @ 100..=107 => self.style.bg = ansi_color(n - 100) | Color::INTENSITY,
+                _ => {}
+            }
+        }
+    }
+
+    /// Carries out a control sequence. Replies for the guest go into `reply`.
+    fn control_sequence(&mut self, command: u8, reply: &mut VecDeque<u8>) {
+        let row = self.y * COLUMNS;
+        match (self.private, command) {
+            (false, b'A') => self.move_to(self.x, self.y.saturating_sub(self.param(0, 1))),
+            (false, b'B') => self.move_to(self.x, self.y + self.param(0, 1)),
+            (false, b'C') => self.move_to(self.x + self.param(0, 1), self.y),
+            (false, b'D') => self.move_to(self.x.saturating_sub(self.param(0, 1)), self.y),
+            (false, b'G') => self.move_to(self.param(0, 1) - 1, self.y),
+            (false, b'd') => self.move_to(self.x, self.param(0, 1) - 1),
+            (false, b'H' | b'f') => self.move_to(self.param(1, 1) - 1, self.param(0, 1) - 1),
+
+            (false, b'J') => match self.params.first().copied().unwrap_or(0) {
+                0 => self.erase(row + self.x, COLUMNS * ROWS),
+                1 => self.erase(0, row + self.x + 1),
+                _ => self.erase(0, COLUMNS * ROWS),
+            },
+            (false, b'K') => match self.params.first().copied().unwrap_or(0) {
+                0 => self.erase(row + self.x, row + COLUMNS),
+                1 => self.erase(row, row + self.x + 1),
+                _ => self.erase(row, row + COLUMNS),
+            },
+            (false, b'X') => {
+                let end = (self.x + self.param(0, 1)).min(COLUMNS);
+                self.erase(row + self.x, row + end);
+            }
+
+            (false, b'@') => {
+                let n = self.param(0, 1).min(COLUMNS - self.x);
+                let start = row + self.x;
+                self.cells
+                    .copy_within(start..(row + COLUMNS - n), start + n);
+                self.erase(start, start + n);
+            }
+            (false, b'P') => {
+                let n = self.param(0, 1).min(COLUMNS - self.x);
+                let start = row + self.x;
+                self.cells.copy_within((start + n)..(row + COLUMNS), start);
+                self.erase(row + COLUMNS - n, row + COLUMNS);
+            }
+
+            // Lines are only inserted and deleted within the scrolling region
+            (false, b'L') => {
+                if (self.scroll_top..=self.scroll_bottom).contains(&self.y) {
+                    self.scroll_down(self.y, self.scroll_bottom, self.param(0, 1));
+                }
+            }
+            (false, b'M') => {
+                if (self.scroll_top..=self.scroll_bottom).contains(&self.y) {
+                    self.scroll_up(self.y, self.scroll_bottom, self.param(0, 1));
+                }
+            }
+
+            (false, b'm') => self.select_graphic_rendition(),
+
+            (false, b'r') => {
+                let top = self.param(0, 1) - 1;
+                let bottom = self.param(1, ROWS).min(ROWS) - 1;
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.move_to(0, 0);
+                }
+            }
+
+            (false, b's') => self.saved = (self.x, self.y, self.style),
+            (false, b'u') => {
+                let (x, y, style) = self.saved;
+                self.move_to(x, y);
+                self.style = style;
+            }
+
+            // Device status report
+            (false, b'n') => match self.params.first() {
+                Some(5) => reply.extend(b"\x1B[0n"),
+                Some(6) => {
+                    let report = format!("\x1B[{};{}R", self.y + 1, self.x + 1);
+                    reply.extend(report.bytes());
+                }
+                _ => {}
+            },
+
+            // Device attributes: a VT100 with no options
+            (false, b'c') => reply.extend(b"\x1B[?1;0c"),
+
+            (true, b'h' | b'l') => {
+                let set = command == b'h';
+                for param in &self.params {
+                    match param {
+                        7 => self.autowrap = set,
+                        25 => self.cursor_visible = set,
+                        _ => {}
+                    }
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    fn feed(&mut self, byte: u8, reply: &mut VecDeque<u8>) {
+        match self.state {
+            State::Ground => match byte {
+                0x1B => self.state = State::Escape,
+                b'\r' => self.move_to(0, self.y),
+                b'\n' | 0x0B | 0x0C => self.line_feed(),
+                0x08 => self.move_to(self.x.saturating_sub(1), self.y),
+                b'\t' => self.move_to(((self.x / TAB_WIDTH) + 1) * TAB_WIDTH, self.y),
+                // Other control characters (like BEL) do nothing
+                0x00..=0x1F | 0x7F => {}
+                // The high codes are kept, even though the font has no glyphs for them
+                _ => self.put(byte),
+            },
+
+            State::Escape => {
+                self.state = State::Ground;
+                match byte {
+                    b'[' => {
+                        self.params.clear();
+                        self.private = false;
+                        self.state = State::Csi;
+                    }
+                    b'(' | b')' => self.state = State::Designate,
+                    b'7' => self.saved = (self.x, self.y, self.style),
+                    b'8' => {
+                        let (x, y, style) = self.saved;
+                        self.move_to(x, y);
+                        self.style = style;
+                    }
+                    b'D' => self.line_feed(),
+                    b'E' => {
+                        self.move_to(0, self.y);
+                        self.line_feed();
+                    }
+                    b'M' => self.reverse_index(),
+                    b'c' => *self = Self::new(),
+                    _ => {}
+                }
+            }
+
+            State::Designate => self.state = State::Ground,
+
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    if self.params.is_empty() {
+                        self.params.push(0);
+                    }
+                    let param = self.params.last_mut().unwrap();
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u16);
+                }
+                b';' => {
+                    if self.params.is_empty() {
+                        self.params.push(0);
+                    }
+                    self.params.push(0);
+                }
+                b'?' => self.private = true,
+                0x40..=0x7E => {
+                    self.state = State::Ground;
+                    self.control_sequence(byte, reply);
+                }
+                // CAN and SUB abort the sequence
+                0x18 | 0x1A => self.state = State::Ground,
+                _ => {}
+            },
+        }
+    }
+}
+
+/// The bytes a VT100 sends for a non-printable key
+fn key_sequence(key: Key) -> Option<&'static [u8]> {
+    let sequence: &[u8] = match key {
+        Key::Enter => b"\r",
+        Key::Backspace => b"\x08",
+        Key::Tab => b"\t",
+        Key::Escape => b"\x1B",
+        Key::Delete => b"\x7F",
+
+        Key::Up => b"\x1B[A",
+        Key::Down => b"\x1B[B",
+        Key::Right => b"\x1B[C",
+        Key::Left => b"\x1B[D",
+        Key::Home => b"\x1B[H",
+        Key::End => b"\x1B[F",
+        Key::Insert => b"\x1B[2~",
+        Key::PageUp => b"\x1B[5~",
+        Key::PageDown => b"\x1B[6~",
+
+        Key::F(1) => b"\x1BOP",
+        Key::F(2) => b"\x1BOQ",
+        Key::F(3) => b"\x1BOR",
+        Key::F(4) => b"\x1BOS",
+        Key::F(5) => b"\x1B[15~",
+        Key::F(6) => b"\x1B[17~",
+        Key::F(7) => b"\x1B[18~",
+        Key::F(8) => b"\x1B[19~",
+        Key::F(9) => b"\x1B[20~",
+        Key::F(10) => b"\x1B[21~",
+        Key::F(11) => b"\x1B[23~",
+        Key::F(12) => b"\x1B[24~",
+
+        _ => return None,
+    };
+    Some(sequence)
+}
+
+struct Shared {
+    screen: Screen,
+    /// Bytes waiting for the guest to receive them
+    input: VecDeque<u8>,
+    modifiers: u8,
+}
+
+/// A shared handle to a terminal.
+///
+/// One clone is given to a UART as its host while the frontend keeps another to send
+/// keys and display (or inspect) the screen.
+#[derive(Clone)]
+pub struct Terminal(Rc<RefCell<Shared>>);
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Self(Rc::new(RefCell::new(Shared {
+            screen: Screen::new(),
+            input: VecDeque::new(),
+            modifiers: 0,
+        })))
+    }
+}
+
+impl Terminal {
+    pub fn key_down(&self, key: Key, modifiers: u8) {
+        let mut shared = self.0.borrow_mut();
+        shared.modifiers = modifiers;
+        match key {
+            // Printable keys arrive as text input, unless ctrl is held
+            Key::Char(c) if (modifiers & Modifier::CTRL) != 0 => {
+                if let Some(code) = kb::control_code(c) {
+                    shared.input.push_back(code);
+                }
+            }
+            _ => {
+                if let Some(sequence) = key_sequence(key) {
+                    shared.input.extend(sequence);
+                }
+            }
+        }
+    }
+
+    pub fn text(&self, text: &str) {
+        let mut shared = self.0.borrow_mut();
+        // Ctrl-combinations were already handled as key presses
+        if (shared.modifiers & Modifier::CTRL) == 0 {
+            shared.input.extend(text.bytes().filter(u8::is_ascii));
+        }
+    }
+
+    /// The text of a row, without trailing spaces. Rows past the bottom are empty.
+    pub fn row(&self, y: usize) -> String {
+        if y >= ROWS {
+            return String::new();
+        }
+        let shared = self.0.borrow();
+        let cells = &shared.screen.cells[(y * COLUMNS)..((y + 1) * COLUMNS)];
+        let text: String = cells.iter().map(|cell| cell.code as char).collect();
+        text.trim_end_matches(' ').to_string()
+    }
+
+    /// The text of the whole screen, one line per row
+    pub fn contents(&self) -> String {
+        (0..ROWS)
+            .map(|y| self.row(y))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The column and row of the cursor
+    pub fn cursor(&self) -> (usize, usize) {
+        let shared = self.0.borrow();
+        (shared.screen.x, shared.screen.y)
+    }
+
+    /// Draws the screen with 8x8 pixel cells
+    pub fn render(&self, framebuffer: &mut Framebuffer) {
+        let shared = self.0.borrow();
+        let screen = &shared.screen;
+        let height = font::HEIGHT;
+        let width = COLUMNS * 8;
+        framebuffer.resize(width, ROWS * height);
+        let pixels = framebuffer.data_mut();
+
+        for (i, cell) in screen.cells.iter().enumerate() {
+            let (cell_x, cell_y) = (i % COLUMNS, i / COLUMNS);
+            let style = cell.style;
+            let mut fg = if style.bold {
+                style.fg | Color::INTENSITY
+            } else {
+                style.fg
+            };
+            let mut bg = style.bg;
+            if style.reverse {
+                mem::swap(&mut fg, &mut bg);
+            }
+            if screen.cursor_visible && (cell_x, cell_y) == (screen.x, screen.y) {
+                mem::swap(&mut fg, &mut bg);
+            }
+            let (fg, bg) = (vdc::color_lookup(fg), vdc::color_lookup(bg));
+
+            for (row, pix) in font::glyph(cell.code).iter().enumerate() {
+                let pix = if style.underline && row == (height - 1) {
+                    0xFF
+                } else {
+                    *pix
+                };
+                let start = (((cell_y * height) + row) * width) + (cell_x * 8);
+                for (bit, pixel) in pixels[start..(start + 8)].iter_mut().enumerate() {
+                    *pixel = if ((pix << bit) & 0x80) != 0 { fg } else { bg };
+                }
+            }
+        }
+    }
+}
+
+impl Read for Terminal {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().input.read(buf)
+    }
+}
+
+impl Write for Terminal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = self.0.borrow_mut();
+        let Shared { screen, input, .. } = &mut *shared;
+        for byte in buf {
+            screen.feed(*byte, input);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialHost for Terminal {}
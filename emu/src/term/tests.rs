@@ -0,0 +1,152 @@
+use super::*;
+
+fn terminal() -> Terminal {
+    Terminal::default()
+}
+
+fn print(terminal: &mut Terminal, text: &str) {
+    terminal.write_all(text.as_bytes()).unwrap();
+}
+
+#[test]
+fn printing() {
+    let mut terminal = terminal();
+    print(&mut terminal, "hello\r\nworld\tX\x07");
+    assert_eq!(terminal.row(0), "hello");
+    assert_eq!(terminal.row(1), "world   X");
+    assert_eq!(terminal.cursor(), (9, 1));
+
+    print(&mut terminal, "\x08\x08Y");
+    assert_eq!(terminal.row(1), "world  YX");
+}
+
+#[test]
+fn wrapping() {
+    let mut terminal = terminal();
+    print(&mut terminal, &"a".repeat(COLUMNS));
+    // The cursor waits at the last column until something else is printed
+    assert_eq!(terminal.cursor(), (COLUMNS - 1, 0));
+    print(&mut terminal, "b");
+    assert_eq!(terminal.row(1), "b");
+
+    print(&mut terminal, "\x1B[?7l\r");
+    print(&mut terminal, &"c".repeat(COLUMNS + 1));
+    assert_eq!(terminal.cursor(), (COLUMNS - 1, 1));
+    assert_eq!(terminal.row(2), "");
+}
+
+#[test]
+fn scrolling() {
+    let mut terminal = terminal();
+    for i in 0..(ROWS + 2) {
+        print(&mut terminal, &format!("{i}\r\n"));
+    }
+    assert_eq!(terminal.row(0), "3");
+    assert_eq!(terminal.row(ROWS - 2), "25");
+    assert_eq!(terminal.row(ROWS - 1), "");
+
+    // Only the region scrolls
+    print(&mut terminal, "\x1B[2;4r\x1B[4;1Hx\nz");
+    assert_eq!(terminal.row(0), "3");
+    assert_eq!(terminal.row(1), "5");
+    assert_eq!(terminal.row(2), "x");
+    assert_eq!(terminal.row(3), " z");
+    assert_eq!(terminal.row(4), "7");
+
+    print(&mut terminal, "\x1B[2;1H\x1BMy");
+    assert_eq!(terminal.row(1), "y");
+    assert_eq!(terminal.row(2), "5");
+    assert_eq!(terminal.row(4), "7");
+}
+
+#[test]
+fn cursor_and_erase() {
+    let mut terminal = terminal();
+    print(&mut terminal, "0123456789\r\nabcdefghij");
+    print(&mut terminal, "\x1B[1;5H\x1B[K");
+    assert_eq!(terminal.row(0), "0123");
+    print(&mut terminal, "\x1B[2;3H\x1B[1K");
+    assert_eq!(terminal.row(1), "   defghij");
+    print(&mut terminal, "\x1B[2D\x1B[B\x1B[3C\x1B[AX");
+    assert_eq!(terminal.cursor(), (4, 1));
+    assert_eq!(terminal.row(1), "   Xefghij");
+
+    print(&mut terminal, "\x1B[2;1H\x1B[2P\x1B[3@");
+    assert_eq!(terminal.row(1), "    Xefghij");
+
+    print(&mut terminal, "\x1B7\x1B[10;10H\x1B8!");
+    assert_eq!(terminal.row(1), "!   Xefghij");
+
+    print(&mut terminal, "\x1B[2J");
+    assert_eq!(terminal.contents(), "\n".repeat(ROWS - 1));
+}
+
+#[test]
+fn attributes() {
+    let mut terminal = terminal();
+    print(&mut terminal, "\x1B[1;4;31;44mA\x1B[0mB");
+    let shared = terminal.0.borrow();
+    let cells = &shared.screen.cells;
+    assert_eq!(
+        cells[0].style,
+        Style {
+            fg: Color::RED,
+            bg: Color::BLUE,
+            bold: true,
+            underline: true,
+            reverse: false,
+        }
+    );
+    assert_eq!(cells[1].style, Style::default());
+}
+
+#[test]
+fn replies() {
+    let mut terminal = terminal();
+    print(&mut terminal, "\x1B[5;10H\x1B[6n\x1B[5n\x1B[c");
+    let mut reply = Vec::new();
+    terminal.read_to_end(&mut reply).unwrap();
+    assert_eq!(reply, b"\x1B[5;10R\x1B[0n\x1B[?1;0c");
+}
+
+#[test]
+fn keys() {
+    let mut terminal = terminal();
+    terminal.key_down(Key::Char('h'), 0);
+    terminal.text("h");
+    terminal.key_down(Key::Enter, 0);
+    terminal.key_down(Key::Up, 0);
+    terminal.key_down(Key::F(1), 0);
+    terminal.key_down(Key::Char('c'), Modifier::CTRL);
+    terminal.text("c");
+
+    let mut sent = Vec::new();
+    terminal.read_to_end(&mut sent).unwrap();
+    assert_eq!(sent, b"h\r\x1B[A\x1BOP\x03");
+}
+
+#[test]
+fn rows_past_the_bottom() {
+    let mut terminal = terminal();
+    print(&mut terminal, "hi");
+    assert_eq!(terminal.row(ROWS - 1), "");
+    assert_eq!(terminal.row(ROWS), "");
+    assert_eq!(terminal.row(usize::MAX), "");
+}
+
+#[test]
+fn rendering() {
+    let mut terminal = terminal();
+    print(&mut terminal, "\x1B[?25l!");
+    let mut framebuffer = Framebuffer::default();
+    terminal.render(&mut framebuffer);
+    assert_eq!(framebuffer.width(), COLUMNS * 8);
+    assert_eq!(framebuffer.height(), ROWS * font::HEIGHT);
+
+    // The built-in font is drawn without any help from the VDC
+    let (fg, bg) = (
+        vdc::color_lookup(Color::DEFAULT_FG),
+        vdc::color_lookup(Color::DEFAULT_BG),
+    );
+    assert_eq!(framebuffer.data()[..8], [bg, bg, bg, fg, fg, bg, bg, bg]);
+}
@@ -50,7 +50,7 @@ impl VScrollControl {
     const COPY: u8 = 0x80;
 }
 
-fn color_lookup(bits: u8) -> u32 {
+pub(crate) fn color_lookup(bits: u8) -> u32 {
     match bits & 0x0F {
         // black
         0b0000 => 0xFF000000,
@@ -111,7 +111,7 @@ pub struct Framebuffer {
 }
 
 impl Framebuffer {
-    fn resize(&mut self, width: usize, height: usize) -> bool {
+    pub(crate) fn resize(&mut self, width: usize, height: usize) -> bool {
         let changed = (width != self.width) || (height != self.height);
         self.pixels.resize(width * height, 0);
         self.width = width;
@@ -124,6 +124,11 @@ impl Framebuffer {
         &self.pixels
     }
 
+    #[inline]
+    pub(crate) fn data_mut(&mut self) -> &mut [u32] {
+        &mut self.pixels
+    }
+
     #[inline]
     pub fn width(&self) -> usize {
         self.width